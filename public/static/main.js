@@ -2,7 +2,52 @@
 
 var nowPlayingEl = document.getElementById("np");
 var queueEl = document.getElementById("queue");
+var queueHeadingEl = document.getElementById("queue-heading");
 var playedEl = document.getElementById("played");
+var downloadsEl = document.getElementById("downloads");
+var entryErrorsEl = document.getElementById("entry-errors");
+var enqueueUrlEl = document.getElementById("enqueue-url-input");
+var enqueuePreviewButtonEl = document.getElementById("enqueue-preview-button");
+var enqueuePreviewEl = document.getElementById("enqueue-preview");
+var uploadFormEl = document.getElementById("upload-form");
+var uploadInputEl = document.getElementById("upload-input");
+var uploadProgressEl = document.getElementById("upload-progress");
+var searchFormEl = document.getElementById("search-form");
+var searchInputEl = document.getElementById("search-input");
+var searchResultsEl = document.getElementById("search-results");
+var libraryEl = document.getElementById("library");
+var libraryPathEl = document.getElementById("library-path");
+var libraryEntriesEl = document.getElementById("library-entries");
+var libraryUpEl = document.getElementById("library-up");
+var libraryQueueFolderEl = document.getElementById("library-queue-folder");
+var libraryQueueShuffledEl = document.getElementById("library-queue-shuffled");
+var librarySearchFormEl = document.getElementById("library-search-form");
+var librarySearchInputEl = document.getElementById("library-search-input");
+var tracksEl = document.getElementById("tracks");
+var audioTrackSelectEl = document.getElementById("audio-track-select");
+var subTrackSelectEl = document.getElementById("sub-track-select");
+var secondarySubTrackSelectEl = document.getElementById("secondary-sub-track-select");
+var chapterLabelEl = document.getElementById("chapter-label");
+var chapterSelectEl = document.getElementById("chapter-select");
+var subDelayInputEl = document.getElementById("sub-delay-input");
+var audioDelayInputEl = document.getElementById("audio-delay-input");
+var subtitleInputEl = document.getElementById("subtitle-input");
+var importPlaylistFormEl = document.getElementById("import-playlist-form");
+var importPlaylistInputEl = document.getElementById("import-playlist-input");
+var savedQueueFormEl = document.getElementById("saved-queue-form");
+var savedQueueNameInputEl = document.getElementById("saved-queue-name-input");
+var savedQueueAppendCurrentEl = document.getElementById("saved-queue-append-current");
+var savedQueueListEl = document.getElementById("saved-queue-list");
+var sleepFormEl = document.getElementById("sleep-form");
+var sleepModeSelectEl = document.getElementById("sleep-mode-select");
+var sleepMinutesInputEl = document.getElementById("sleep-minutes-input");
+var sleepAtInputEl = document.getElementById("sleep-at-input");
+var sleepCancelEl = document.getElementById("sleep-cancel");
+var sleepStatusEl = document.getElementById("sleep-status");
+var chatMessagesEl = document.getElementById("chat-messages");
+var chatFormEl = document.getElementById("chat-form");
+var chatInputEl = document.getElementById("chat-input");
+var reactionsEl = document.getElementById("reactions");
 
 /**
  * @typedef {object} PlaylistEntry
@@ -11,6 +56,11 @@ var playedEl = document.getElementById("played");
  * @property {string=} title
  * @property {boolean} current
  * @property {boolean} playing
+ * @property {string=} uploader
+ * @property {number=} duration
+ * @property {string=} thumbnail
+ * @property {boolean} filler
+ * @property {number=} eta
  */
 
 /**
@@ -33,14 +83,90 @@ function renderPlaylistEntry(entry, type) {
 
   textContainer.textContent = entry.title || entry.filename;
 
+  var details = [];
+  if (entry.filler) {
+    details.push("autoplay");
+  }
+  if (entry.uploader) {
+    details.push(entry.uploader);
+  }
+  if (entry.duration) {
+    details.push(formatDuration(entry.duration));
+  }
+  if (entry.eta != null) {
+    details.push("in " + formatDuration(entry.eta));
+  }
+
+  if (details.length > 0) {
+    var small = document.createElement("small");
+    small.textContent = details.join(" · ");
+    container.appendChild(small);
+  }
+
   return container;
 }
 
+/** @type {number | null} */
+var subtitleEntryId = null;
+
+/**
+ * @param {PlaylistEntry} entry
+ */
+function renderAddSubtitlesButton(entry) {
+  var button = document.createElement("button");
+  button.type = "button";
+  button.className = "add-subtitles";
+  button.textContent = "add subs";
+  button.addEventListener("click", () => {
+    subtitleEntryId = entry.id;
+    subtitleInputEl.click();
+  });
+  return button;
+}
+
+subtitleInputEl.addEventListener("change", () => {
+  var file = subtitleInputEl.files && subtitleInputEl.files[0];
+  if (!file || subtitleEntryId == null) {
+    return;
+  }
+
+  var body = new FormData();
+  body.append("file", file);
+
+  fetch("/api/playlist/" + subtitleEntryId + "/subtitles", { method: "POST", body: body })
+    .then((resp) => (resp.ok ? null : resp.json()))
+    .then((err) => {
+      if (err) {
+        alert(err.message);
+      }
+      subtitleInputEl.value = "";
+      fetchTracks();
+    });
+});
+
 /**
  * @param {PlaylistEntry} entry
  * @returns {HTMLElement[]}
  */
 function renderNowPlaying(entry) {
+  var elements = renderNowPlayingText(entry);
+  elements.push(renderAddSubtitlesButton(entry));
+
+  if (entry.thumbnail) {
+    var img = document.createElement("img");
+    img.src = entry.thumbnail;
+    img.alt = "";
+    elements.unshift(img);
+  }
+
+  return elements;
+}
+
+/**
+ * @param {PlaylistEntry} entry
+ * @returns {HTMLElement[]}
+ */
+function renderNowPlayingText(entry) {
   if (entry.filename.startsWith("http")) {
     var a = document.createElement("a");
     a.href = entry.filename;
@@ -71,6 +197,7 @@ function render(entries) {
   if (currentIndex == -1) {
     playedEl.replaceChildren();
     queueEl.replaceChildren();
+    queueHeadingEl.textContent = "queue";
     return;
   }
 
@@ -81,21 +208,873 @@ function render(entries) {
   var playedLinks = played
     .reverse()
     .map((entry) => renderPlaylistEntry(entry, "li"));
-  var queueLinks = queue.map((entry) => renderPlaylistEntry(entry, "li"));
+  var queueLinks = queue.map((entry) => {
+    var li = renderPlaylistEntry(entry, "li");
+    li.append(renderAddSubtitlesButton(entry));
+    return li;
+  });
 
   nowPlayingEl.replaceChildren(...renderNowPlaying(nowPlaying));
 
   playedEl.replaceChildren(...playedLinks);
   queueEl.replaceChildren(...queueLinks);
+
+  var queueLength = queue.reduce((total, entry) => total + (entry.duration || 0), 0);
+  queueHeadingEl.textContent =
+    queueLength > 0 ? "queue (" + formatDuration(queueLength) + ")" : "queue";
 }
 
-function updatePlaylist() {
-  fetch("/api/playlist")
+/**
+ * @typedef {object} PreviewEntry
+ * @property {string} url
+ * @property {string=} title
+ * @property {number=} duration
+ */
+
+/**
+ * @typedef {object} Preview
+ * @property {string=} title
+ * @property {number=} total
+ * @property {PreviewEntry[]} entries
+ */
+
+/**
+ * @param {number} seconds
+ */
+function formatDuration(seconds) {
+  var hours = Math.floor(seconds / 3600);
+  var minutes = Math.floor((seconds % 3600) / 60);
+  var secs = Math.floor(seconds % 60);
+  var pad = (n) => (n < 10 ? "0" : "") + n;
+  return (hours > 0 ? hours + ":" + pad(minutes) : minutes) + ":" + pad(secs);
+}
+
+/**
+ * @param {Preview} preview
+ */
+function renderPreview(preview) {
+  var items = preview.entries.map((entry) => {
+    var li = document.createElement("li");
+    li.textContent = entry.title || entry.url;
+    if (entry.duration) {
+      li.textContent += " (" + formatDuration(entry.duration) + ")";
+    }
+    return li;
+  });
+
+  if (preview.total && preview.total > preview.entries.length) {
+    var more = document.createElement("li");
+    more.textContent =
+      "...only the first " + preview.entries.length + " of " + preview.total + " will be added";
+    items.push(more);
+  }
+
+  enqueuePreviewEl.replaceChildren(...items);
+}
+
+enqueuePreviewButtonEl.addEventListener("click", () => {
+  var li = document.createElement("li");
+  li.textContent = "now loading...";
+  enqueuePreviewEl.replaceChildren(li);
+
+  fetch("/api/enqueue/preview?url=" + encodeURIComponent(enqueueUrlEl.value))
     .then((resp) => resp.json())
-    .then((playlist) => {
-      render(playlist);
-      setTimeout(updatePlaylist, 10 * 1000);
+    .then((preview) => {
+      if (preview.message) {
+        li.textContent = preview.message;
+      } else {
+        renderPreview(preview);
+      }
     });
+});
+
+var UPLOAD_CHUNK_SIZE = 8 * 1024 * 1024;
+
+/**
+ * @typedef {object} UploadStatus
+ * @property {string} id
+ * @property {number} size
+ * @property {number} offset
+ * @property {boolean} complete
+ */
+
+/**
+ * @param {Response} resp
+ * @returns {Promise<any>}
+ */
+function jsonOrThrow(resp) {
+  return resp.json().then((body) => {
+    if (!resp.ok) {
+      throw new Error(body.message);
+    }
+    return body;
+  });
+}
+
+/**
+ * Resumes the upload of this file if it was interrupted, or starts a new one.
+ * @param {File} file
+ * @returns {Promise<UploadStatus>}
+ */
+function startUpload(file) {
+  var key = "upload:" + file.name + ":" + file.size + ":" + file.lastModified;
+  var id = localStorage.getItem(key);
+
+  var create = () =>
+    fetch("/api/uploads", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ filename: file.name, size: file.size }),
+    })
+      .then(jsonOrThrow)
+      .then((status) => {
+        localStorage.setItem(key, status.id);
+        return status;
+      });
+
+  if (!id) {
+    return create();
+  }
+
+  return fetch("/api/uploads/" + id).then((resp) => (resp.ok ? resp.json() : create()));
+}
+
+/**
+ * @param {File} file
+ * @param {UploadStatus} status
+ * @param {number} retries
+ * @returns {Promise<UploadStatus>}
+ */
+function uploadChunks(file, status, retries) {
+  uploadProgressEl.textContent =
+    "uploading " + file.name + ": " + Math.floor((status.offset / status.size) * 100) + "%";
+
+  if (status.complete) {
+    return Promise.resolve(status);
+  }
+
+  var chunk = file.slice(status.offset, status.offset + UPLOAD_CHUNK_SIZE);
+
+  return fetch("/api/uploads/" + status.id, {
+    method: "PATCH",
+    headers: { "Upload-Offset": String(status.offset) },
+    body: chunk,
+  })
+    .then(jsonOrThrow)
+    .then((status) => uploadChunks(file, status, 5))
+    .catch((err) => {
+      if (retries <= 0) {
+        throw err;
+      }
+
+      // Ask the server where to resume from after a little while.
+      return new Promise((resolve) => setTimeout(resolve, 2000))
+        .then(() => fetch("/api/uploads/" + status.id))
+        .then(jsonOrThrow)
+        .then((status) => uploadChunks(file, status, retries - 1));
+    });
+}
+
+/** Files larger than this are uploaded without checking if they're already on the server first. */
+var HASH_FIRST_MAX_SIZE = 512 * 1024 * 1024;
+
+/**
+ * Enqueues the copy of this file that's already on the server, if there is one.
+ * Hashing is only available on HTTPS or localhost, so otherwise the file is just uploaded.
+ * @param {File} file
+ * @returns {Promise<boolean>}
+ */
+function enqueueExisting(file) {
+  if (!window.crypto || !window.crypto.subtle || file.size > HASH_FIRST_MAX_SIZE) {
+    return Promise.resolve(false);
+  }
+
+  uploadProgressEl.textContent = "checking " + file.name;
+
+  return file
+    .arrayBuffer()
+    .then((buf) => window.crypto.subtle.digest("SHA-256", buf))
+    .then((hash) =>
+      Array.from(new Uint8Array(hash))
+        .map((b) => b.toString(16).padStart(2, "0"))
+        .join("")
+    )
+    .then((sha256) =>
+      fetch("/api/uploads/existing", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ sha256: sha256 }),
+      })
+    )
+    .then((resp) => resp.ok)
+    .catch(() => false);
+}
+
+uploadFormEl.addEventListener("submit", (event) => {
+  var file = uploadInputEl.files && uploadInputEl.files[0];
+  if (!file) {
+    return;
+  }
+
+  event.preventDefault();
+
+  enqueueExisting(file)
+    .then((enqueued) =>
+      enqueued ? null : startUpload(file).then((status) => uploadChunks(file, status, 5))
+    )
+    .then(() => {
+      localStorage.removeItem("upload:" + file.name + ":" + file.size + ":" + file.lastModified);
+      uploadProgressEl.textContent = "uploaded " + file.name;
+      uploadFormEl.reset();
+      fetchPlaylist();
+    })
+    .catch((err) => {
+      uploadProgressEl.textContent = "upload failed: " + err.message + " (submit again to resume)";
+    });
+});
+
+/**
+ * @typedef {object} SearchResult
+ * @property {string} id
+ * @property {string} url
+ * @property {string=} title
+ * @property {number=} duration
+ */
+
+/**
+ * @param {SearchResult} result
+ */
+function renderSearchResult(result) {
+  var li = document.createElement("li");
+
+  var button = document.createElement("button");
+  button.type = "button";
+  button.textContent = "add";
+  button.addEventListener("click", () => {
+    button.disabled = true;
+    fetch("/api/search/enqueue", {
+      method: "POST",
+      body: new URLSearchParams({ id: result.id }),
+    }).then(() => {
+      button.textContent = "added";
+      fetchPlaylist();
+    });
+  });
+
+  var a = document.createElement("a");
+  a.href = result.url;
+  a.textContent = result.title || result.url;
+
+  li.append(button, a);
+
+  if (result.duration) {
+    li.append(" (" + formatDuration(result.duration) + ")");
+  }
+
+  return li;
+}
+
+searchFormEl.addEventListener("submit", (event) => {
+  event.preventDefault();
+
+  var li = document.createElement("li");
+  li.textContent = "searching...";
+  searchResultsEl.replaceChildren(li);
+
+  fetch("/api/search?q=" + encodeURIComponent(searchInputEl.value))
+    .then((resp) => resp.json())
+    .then((results) => {
+      if (results.message) {
+        li.textContent = results.message;
+      } else {
+        searchResultsEl.replaceChildren(...results.map(renderSearchResult));
+      }
+    });
+});
+
+/**
+ * @typedef {object} LibraryEntry
+ * @property {string} name
+ * @property {string} path
+ * @property {boolean} is_dir
+ */
+
+var libraryPath = "";
+
+/**
+ * @param {string} url
+ * @param {Record<string, string>} params
+ */
+function post(url, params) {
+  return fetch(url, { method: "POST", body: new URLSearchParams(params) })
+    .then((resp) => (resp.ok ? null : resp.json()))
+    .then((err) => {
+      if (err) {
+        alert(err.message);
+      }
+      fetchPlaylist();
+    });
+}
+
+/**
+ * @param {LibraryEntry} entry
+ */
+function renderLibraryEntry(entry) {
+  var li = document.createElement("li");
+
+  if (entry.is_dir) {
+    var a = document.createElement("a");
+    a.href = "#";
+    a.textContent = entry.name + "/";
+    a.addEventListener("click", (event) => {
+      event.preventDefault();
+      browseLibrary(entry.path);
+    });
+    li.append(a);
+  } else {
+    var button = document.createElement("button");
+    button.type = "button";
+    button.textContent = "add";
+    button.addEventListener("click", () => post("/api/library/enqueue", { path: entry.path }));
+    li.append(button, entry.name);
+  }
+
+  return li;
+}
+
+/**
+ * @param {string} path
+ */
+function browseLibrary(path) {
+  fetch("/api/library?path=" + encodeURIComponent(path))
+    .then((resp) => resp.json())
+    .then((entries) => {
+      if (entries.message) {
+        alert(entries.message);
+        return;
+      }
+
+      if (path === "" && entries.length === 0) {
+        libraryEl.hidden = true;
+        return;
+      }
+
+      libraryEl.hidden = false;
+      libraryPath = path;
+      libraryPathEl.textContent = "/" + path;
+      libraryUpEl.disabled = path === "";
+      libraryQueueFolderEl.disabled = path === "";
+      libraryQueueShuffledEl.disabled = path === "";
+      libraryEntriesEl.replaceChildren(...entries.map(renderLibraryEntry));
+    });
+}
+
+libraryUpEl.addEventListener("click", () => {
+  browseLibrary(libraryPath.split("/").slice(0, -1).join("/"));
+});
+
+libraryQueueFolderEl.addEventListener("click", () => {
+  post("/api/library/enqueue-folder", { path: libraryPath });
+});
+
+libraryQueueShuffledEl.addEventListener("click", () => {
+  post("/api/library/enqueue-folder", { path: libraryPath, shuffle: "true" });
+});
+
+/**
+ * @typedef {object} LibrarySearchResult
+ * @property {string} path
+ * @property {string=} title
+ * @property {string=} artist
+ * @property {number=} duration
+ */
+
+/**
+ * @param {LibrarySearchResult} result
+ */
+function renderLibrarySearchResult(result) {
+  var li = document.createElement("li");
+
+  var button = document.createElement("button");
+  button.type = "button";
+  button.textContent = "add";
+  button.addEventListener("click", () => post("/api/library/enqueue", { path: result.path }));
+
+  var text = result.title || result.path.split("/").pop();
+  if (result.artist) {
+    text = result.artist + " - " + text;
+  }
+  if (result.duration) {
+    text += " (" + formatDuration(result.duration) + ")";
+  }
+
+  var small = document.createElement("small");
+  small.textContent = result.path;
+
+  li.append(button, text, small);
+
+  return li;
+}
+
+librarySearchFormEl.addEventListener("submit", (event) => {
+  event.preventDefault();
+
+  fetch("/api/library/search?q=" + encodeURIComponent(librarySearchInputEl.value))
+    .then((resp) => resp.json())
+    .then((results) => {
+      if (results.message) {
+        alert(results.message);
+        return;
+      }
+
+      libraryPathEl.textContent = "search: " + librarySearchInputEl.value;
+      libraryEntriesEl.replaceChildren(...results.map(renderLibrarySearchResult));
+    });
+});
+
+browseLibrary("");
+
+importPlaylistFormEl.addEventListener("submit", (event) => {
+  var file = importPlaylistInputEl.files && importPlaylistInputEl.files[0];
+  if (!file) {
+    return;
+  }
+
+  event.preventDefault();
+
+  var body = new FormData();
+  body.append("file", file);
+
+  fetch("/api/playlist/import", { method: "POST", body: body })
+    .then(jsonOrThrow)
+    .then((/** @type {{ enqueued: number, skipped: number }} */ result) => {
+      var message = "added " + result.enqueued + " entries";
+      if (result.skipped > 0) {
+        message += ", skipped " + result.skipped + " that aren't urls or library files";
+      }
+      alert(message);
+      importPlaylistFormEl.reset();
+      fetchPlaylist();
+    })
+    .catch((err) => alert(err.message));
+});
+
+/**
+ * @typedef {object} SavedQueue
+ * @property {string} name
+ * @property {number} entries
+ */
+
+/**
+ * @param {SavedQueue} queue
+ */
+function renderSavedQueue(queue) {
+  var li = document.createElement("li");
+  var url = "/api/queues/" + encodeURIComponent(queue.name);
+
+  var play = document.createElement("button");
+  play.type = "button";
+  play.textContent = "play";
+  play.addEventListener("click", () => {
+    if (confirm("replace the whole queue with " + queue.name + "?")) {
+      post(url + "/play", {});
+    }
+  });
+
+  var remove = document.createElement("button");
+  remove.type = "button";
+  remove.textContent = "delete";
+  remove.addEventListener("click", () => {
+    if (confirm("delete " + queue.name + "?")) {
+      fetch(url, { method: "DELETE" })
+        .then((resp) => (resp.ok ? null : jsonOrThrow(resp)))
+        .then(fetchSavedQueues)
+        .catch((err) => alert(err.message));
+    }
+  });
+
+  li.append(play, remove, queue.name + " (" + queue.entries + ")");
+
+  return li;
+}
+
+function fetchSavedQueues() {
+  return fetch("/api/queues")
+    .then((resp) => resp.json())
+    .then((/** @type {SavedQueue[]} */ queues) => {
+      savedQueueListEl.replaceChildren(...queues.map(renderSavedQueue));
+    });
+}
+
+savedQueueFormEl.addEventListener("submit", (event) => {
+  event.preventDefault();
+
+  fetch("/api/playlist/export/json")
+    .then((resp) => resp.text())
+    .then((items) =>
+      fetch("/api/queues/" + encodeURIComponent(savedQueueNameInputEl.value), {
+        method: "PUT",
+        headers: { "Content-Type": "application/json" },
+        body: items,
+      })
+    )
+    .then((resp) => (resp.ok ? null : jsonOrThrow(resp)))
+    .then(fetchSavedQueues)
+    .catch((err) => alert(err.message));
+});
+
+savedQueueAppendCurrentEl.addEventListener("click", () => {
+  fetch("/api/queues/" + encodeURIComponent(savedQueueNameInputEl.value) + "/append-current", {
+    method: "POST",
+  })
+    .then((resp) => (resp.ok ? null : jsonOrThrow(resp)))
+    .then(fetchSavedQueues)
+    .catch((err) => alert(err.message));
+});
+
+fetchSavedQueues();
+
+/**
+ * @typedef {object} Track
+ * @property {number} id
+ * @property {"video" | "audio" | "sub"} type
+ * @property {string=} title
+ * @property {string=} lang
+ * @property {boolean} selected
+ * @property {number=} main_selection
+ * @property {boolean} external
+ */
+
+/**
+ * @param {HTMLSelectElement} select
+ * @param {Track[]} tracks
+ * @param {number} mainSelection 0 for the main track, 1 for the secondary subtitles
+ */
+function renderTrackOptions(select, tracks, mainSelection) {
+  var off = document.createElement("option");
+  off.value = "";
+  off.textContent = "off";
+
+  var options = tracks.map((track) => {
+    var option = document.createElement("option");
+    option.value = String(track.id);
+    option.textContent =
+      track.id + ": " + ([track.title, track.lang].filter((s) => s).join(" ") || "untitled");
+    option.selected = track.selected && (track.main_selection || 0) === mainSelection;
+    return option;
+  });
+
+  select.replaceChildren(off, ...options);
+}
+
+function fetchTracks() {
+  return fetch("/api/tracks")
+    .then((resp) => (resp.ok ? resp.json() : []))
+    .then((/** @type {Track[]} */ tracks) => {
+      tracksEl.hidden = tracks.length === 0;
+      var audioTracks = tracks.filter((track) => track.type === "audio");
+      var subTracks = tracks.filter((track) => track.type === "sub");
+      renderTrackOptions(audioTrackSelectEl, audioTracks, 0);
+      renderTrackOptions(subTrackSelectEl, subTracks, 0);
+      renderTrackOptions(secondarySubTrackSelectEl, subTracks, 1);
+    });
+}
+
+/**
+ * @typedef {object} Chapter
+ * @property {string=} title
+ * @property {number} time
+ */
+
+function fetchChapters() {
+  return fetch("/api/chapters")
+    .then((resp) => (resp.ok ? resp.json() : { chapters: [] }))
+    .then((/** @type {{ current?: number, chapters: Chapter[] }} */ body) => {
+      chapterLabelEl.hidden = body.chapters.length === 0;
+      chapterSelectEl.replaceChildren(
+        ...body.chapters.map((chapter, index) => {
+          var option = document.createElement("option");
+          option.value = String(index);
+          option.textContent =
+            formatDuration(chapter.time) + " " + (chapter.title || "chapter " + (index + 1));
+          option.selected = index === body.current;
+          return option;
+        })
+      );
+    });
+}
+
+function fetchDelays() {
+  return fetch("/api/delays")
+    .then((resp) => (resp.ok ? resp.json() : null))
+    .then((/** @type {{ sub_delay: number, audio_delay: number } | null} */ delays) => {
+      // Don't overwrite what someone is typing.
+      if (delays && document.activeElement !== subDelayInputEl) {
+        subDelayInputEl.value = String(delays.sub_delay);
+      }
+      if (delays && document.activeElement !== audioDelayInputEl) {
+        audioDelayInputEl.value = String(delays.audio_delay);
+      }
+    });
+}
+
+function fetchPlayback() {
+  return Promise.all([fetchTracks(), fetchChapters(), fetchDelays()]);
+}
+
+/**
+ * @param {string} type
+ * @param {HTMLSelectElement} select
+ */
+function selectTrack(type, select) {
+  post("/api/tracks/" + type, select.value ? { id: select.value } : {}).then(fetchTracks);
+}
+
+audioTrackSelectEl.addEventListener("change", () => selectTrack("audio", audioTrackSelectEl));
+subTrackSelectEl.addEventListener("change", () => selectTrack("sub", subTrackSelectEl));
+secondarySubTrackSelectEl.addEventListener("change", () =>
+  selectTrack("secondary-sub", secondarySubTrackSelectEl)
+);
+
+chapterSelectEl.addEventListener("change", () => {
+  post("/api/chapters", { index: chapterSelectEl.value }).then(fetchChapters);
+});
+
+subDelayInputEl.addEventListener("change", () => {
+  post("/api/delays", { sub_delay: subDelayInputEl.value });
+});
+
+audioDelayInputEl.addEventListener("change", () => {
+  post("/api/delays", { audio_delay: audioDelayInputEl.value });
+});
+
+/**
+ * @typedef {object} Download
+ * @property {number} id
+ * @property {string} url
+ * @property {string=} name
+ * @property {number} total
+ * @property {number} completed
+ * @property {number} speed
+ * @property {"downloading" | "done" | "failed"} state
+ * @property {string=} error
+ */
+
+/**
+ * @param {Download} download
+ */
+function renderDownload(download) {
+  var li = document.createElement("li");
+  li.textContent = download.name || download.url;
+
+  var status = document.createElement("small");
+  if (download.state === "failed") {
+    status.textContent = "failed: " + download.error;
+  } else if (download.state === "done") {
+    status.textContent = "downloaded";
+  } else if (download.total > 0) {
+    var percent = Math.floor((download.completed / download.total) * 100);
+    status.textContent =
+      "downloading, " + percent + "% at " + (download.speed / 1024 / 1024).toFixed(1) + " MB/s";
+  } else {
+    status.textContent = "fetching torrent metadata...";
+  }
+
+  li.append(status);
+  return li;
+}
+
+function fetchDownloads() {
+  return fetch("/api/torrents")
+    .then(jsonOrThrow)
+    .then((/** @type {Download[]} */ downloads) => {
+      downloadsEl.replaceChildren(...downloads.map(renderDownload));
+    })
+    .catch(() => {});
+}
+
+/**
+ * @typedef {object} EntryError
+ * @property {number} playlist_entry_id
+ * @property {string=} url
+ * @property {string=} title
+ * @property {string=} file_error
+ * @property {string[]} log
+ * @property {number} timestamp
+ */
+
+/**
+ * @param {EntryError} error
+ */
+function renderEntryError(error) {
+  var li = document.createElement("li");
+  li.textContent = "couldn't play " + (error.title || error.url || "an entry");
+
+  var reason = document.createElement("small");
+  reason.textContent = error.log[error.log.length - 1] || error.file_error || "unknown error";
+
+  li.append(reason);
+  return li;
+}
+
+function fetchEntryErrors() {
+  return fetch("/api/errors")
+    .then(jsonOrThrow)
+    .then((/** @type {EntryError[]} */ errors) => {
+      entryErrorsEl.replaceChildren(...errors.map(renderEntryError));
+    })
+    .catch(() => {});
+}
+
+/**
+ * @typedef {object} ChatMessage
+ * @property {string} text
+ * @property {boolean} reaction
+ * @property {number} timestamp
+ */
+
+/** Number of chat messages kept on the page. */
+var MAX_CHAT_MESSAGES = 20;
+
+/**
+ * @param {string} text
+ * @param {boolean} reaction
+ * @returns {Promise<boolean>} whether the message was sent
+ */
+function sendChat(text, reaction) {
+  return fetch("/api/chat", {
+    method: "POST",
+    body: new URLSearchParams({ text: text, reaction: String(reaction) }),
+  })
+    .then(jsonOrThrow)
+    .then(
+      () => true,
+      (err) => {
+        alert(err.message);
+        return false;
+      },
+    );
+}
+
+chatFormEl.addEventListener("submit", (event) => {
+  event.preventDefault();
+  sendChat(chatInputEl.value, false).then((sent) => {
+    if (sent) {
+      chatInputEl.value = "";
+    }
+  });
+});
+
+reactionsEl.querySelectorAll("button").forEach((button) => {
+  button.addEventListener("click", () => sendChat(button.textContent || "", true));
+});
+
+var events = new EventSource("/api/events");
+
+/**
+ * @param {string} text
+ * @param {string} className
+ */
+function appendChatMessage(text, className) {
+  var li = document.createElement("li");
+  li.textContent = text;
+  li.className = className;
+
+  chatMessagesEl.append(li);
+  while (chatMessagesEl.children.length > MAX_CHAT_MESSAGES) {
+    chatMessagesEl.removeChild(chatMessagesEl.children[0]);
+  }
+}
+
+events.addEventListener("chat", (event) => {
+  /** @type {ChatMessage} */
+  var message = JSON.parse(event.data);
+  appendChatMessage(message.text, message.reaction ? "reaction" : "");
+});
+
+events.addEventListener("announcement", (event) => {
+  /** @type {{ text: string }} */
+  var announcement = JSON.parse(event.data);
+  appendChatMessage(announcement.text, "announcement");
+});
+
+/**
+ * @typedef {object} SleepTimerStatus
+ * @property {"after-current" | "timer" | "fade"} mode
+ * @property {number=} stops_at
+ */
+
+/**
+ * @param {SleepTimerStatus | null} status
+ */
+function renderSleepTimer(status) {
+  if (!status) {
+    sleepStatusEl.textContent = "";
+  } else if (status.mode === "after-current") {
+    sleepStatusEl.textContent = "stopping after this file";
+  } else {
+    var at = new Date((status.stops_at || 0) * 1000).toLocaleTimeString([], {
+      hour: "2-digit",
+      minute: "2-digit",
+    });
+    sleepStatusEl.textContent = (status.mode === "fade" ? "fading out until " : "stopping at ") + at;
+  }
+}
+
+function fetchSleepTimer() {
+  return fetch("/api/sleep")
+    .then(jsonOrThrow)
+    .then(renderSleepTimer)
+    // Only the admin can see the sleep timer.
+    .catch(() => {});
+}
+
+sleepModeSelectEl.addEventListener("change", () => {
+  var mode = sleepModeSelectEl.value;
+  sleepMinutesInputEl.hidden = mode !== "minutes" && mode !== "fade";
+  sleepAtInputEl.hidden = mode !== "at";
+});
+
+sleepMinutesInputEl.hidden = true;
+
+sleepFormEl.addEventListener("submit", (event) => {
+  event.preventDefault();
+
+  fetch("/api/sleep", {
+    method: "POST",
+    body: new URLSearchParams({
+      mode: sleepModeSelectEl.value,
+      minutes: sleepMinutesInputEl.value,
+      at: sleepAtInputEl.value,
+      utc_offset: String(-new Date().getTimezoneOffset()),
+    }),
+  })
+    .then(jsonOrThrow)
+    .then(renderSleepTimer)
+    .catch((err) => alert(err.message));
+});
+
+sleepCancelEl.addEventListener("click", () => {
+  fetch("/api/sleep", { method: "DELETE" })
+    .then((resp) => (resp.ok ? null : jsonOrThrow(resp)))
+    .then(() => renderSleepTimer(null))
+    .catch((err) => alert(err.message));
+});
+
+function fetchPlaylist() {
+  return fetch("/api/playlist")
+    .then((resp) => resp.json())
+    .then(render);
+}
+
+function updatePlaylist() {
+  Promise.all([
+    fetchPlaylist(),
+    fetchPlayback(),
+    fetchDownloads(),
+    fetchEntryErrors(),
+    fetchSleepTimer(),
+  ]).then(() => setTimeout(updatePlaylist, 10 * 1000));
 }
 
 updatePlaylist();
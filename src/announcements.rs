@@ -0,0 +1,263 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    events::{Event, Events},
+    mpv::Client,
+    osd::{self, escape_ass},
+};
+
+/// Longest an announcement can stay on screen.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 60);
+
+const MAX_LENGTH: usize = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("announcement is empty")]
+    Empty,
+
+    #[error("announcement is longer than {MAX_LENGTH} characters")]
+    TooLong,
+
+    #[error("duration must be between 0 and {} seconds", MAX_DURATION.as_secs())]
+    InvalidDuration,
+
+    #[error("unknown announcement style: {0}")]
+    UnknownStyle(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    /// A strip at the top of the screen, over the video.
+    Banner,
+
+    /// The text in the middle of a dark screen.
+    Fullscreen,
+}
+
+impl FromStr for Style {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "banner" => Ok(Style::Banner),
+            "fullscreen" => Ok(Style::Fullscreen),
+            _ => Err(Error::UnknownStyle(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Announcement {
+    pub text: String,
+    pub style: Style,
+
+    /// Seconds.
+    pub duration: f64,
+
+    /// Pause playback while the announcement is shown.
+    pub pause: bool,
+}
+
+impl Announcement {
+    pub fn new(text: &str, style: Style, duration: f64, pause: bool) -> Result<Self, Error> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        if text.chars().count() > MAX_LENGTH {
+            return Err(Error::TooLong);
+        }
+
+        if !(duration > 0.0 && duration <= MAX_DURATION.as_secs_f64()) {
+            return Err(Error::InvalidDuration);
+        }
+
+        Ok(Announcement {
+            text: text.to_string(),
+            style,
+            duration,
+            pause,
+        })
+    }
+
+    /// The ASS events that draw the announcement.
+    fn render(&self) -> String {
+        // Keep the line breaks the admin typed, but nothing else that ASS would interpret.
+        let text = self
+            .text
+            .lines()
+            .map(escape_ass)
+            .collect::<Vec<_>>()
+            .join("\\N");
+
+        let (w, h) = (osd::RES_X, osd::RES_Y);
+
+        match self.style {
+            Style::Banner => format!(
+                "{{\\an7\\pos(0,0)\\bord0\\shad0\\1c&H000000&\\alpha&H40&\\p1}}m 0 0 l {w} 0 {w} 110 0 110{{\\p0}}\n\
+                 {{\\an8\\pos({},25)\\fs44\\bord2\\shad0}}{text}",
+                w / 2
+            ),
+            Style::Fullscreen => format!(
+                "{{\\an7\\pos(0,0)\\bord0\\shad0\\1c&H000000&\\alpha&H10&\\p1}}m 0 0 l {w} 0 {w} {h} 0 {h}{{\\p0}}\n\
+                 {{\\an5\\pos({},{})\\fs72\\bord0\\shad0}}{text}",
+                w / 2,
+                h / 2
+            ),
+        }
+    }
+}
+
+/// Messages from the admin shown on the TV one after the other.
+#[derive(Debug, Clone)]
+pub struct Announcements {
+    ipc: Client,
+    events: Events,
+    queue: Arc<Mutex<VecDeque<Announcement>>>,
+    added: Arc<Notify>,
+}
+
+impl Announcements {
+    pub fn new(ipc: Client, events: Events) -> Self {
+        Announcements {
+            ipc,
+            events,
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            added: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queues an announcement and returns how many are waiting to be shown, including this one.
+    pub fn announce(&self, announcement: Announcement) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(announcement);
+        self.added.notify_one();
+        queue.len()
+    }
+
+    /// The announcements waiting to be shown, not including the one on screen.
+    pub fn pending(&self) -> Vec<Announcement> {
+        self.queue.lock().unwrap().iter().cloned().collect()
+    }
+
+    async fn show(&self, announcement: Announcement) {
+        // Only resume afterwards if it was playing before.
+        let resume = announcement.pause && !self.ipc.get_paused().await.unwrap_or(false);
+        if resume {
+            if let Err(e) = self.ipc.set_paused(true).await {
+                log::warn!("failed to pause for the announcement: {e}");
+            }
+        }
+
+        self.events.send(Event::Announcement(announcement.clone()));
+
+        if let Err(e) = self
+            .ipc
+            .osd_overlay(
+                osd::ANNOUNCEMENT_OVERLAY_ID,
+                &announcement.render(),
+                osd::RES_X,
+                osd::RES_Y,
+            )
+            .await
+        {
+            log::warn!("failed to show the announcement: {e}");
+        }
+
+        tokio::time::sleep(Duration::from_secs_f64(announcement.duration)).await;
+
+        if let Err(e) = self
+            .ipc
+            .osd_overlay_remove(osd::ANNOUNCEMENT_OVERLAY_ID)
+            .await
+        {
+            log::warn!("failed to remove the announcement: {e}");
+        }
+
+        if resume {
+            if let Err(e) = self.ipc.set_paused(false).await {
+                log::warn!("failed to resume after the announcement: {e}");
+            }
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            let next = self.queue.lock().unwrap().pop_front();
+
+            match next {
+                Some(announcement) => self.show(announcement).await,
+                None => self.added.notified().await,
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_announcements() {
+    let (ipc, mut commands) = Client::fake();
+    let announcements = Announcements::new(ipc, Events::new());
+
+    assert!(matches!(
+        Announcement::new("  ", Style::Banner, 5.0, false),
+        Err(Error::Empty)
+    ));
+    assert!(matches!(
+        Announcement::new("pizza", Style::Banner, 0.0, false),
+        Err(Error::InvalidDuration)
+    ));
+
+    let pizza = Announcement::new("pizza is here\n{\\fs1}", Style::Fullscreen, 0.05, true).unwrap();
+    assert!(pizza
+        .render()
+        .ends_with("}pizza is here\\N\\{\\\u{feff}fs1\\}"));
+
+    assert_eq!(announcements.announce(pizza), 1);
+    assert_eq!(
+        announcements.announce(Announcement::new("last call", Style::Banner, 0.05, false).unwrap()),
+        2
+    );
+    tokio::spawn(announcements.clone().run());
+
+    // Pauses for the first one and resumes afterwards, but not for the second.
+    let mut sent = vec![];
+    while sent.len() < 7 {
+        let command = commands.recv().await.unwrap();
+        sent.push(match command.get("name") {
+            Some(_) => format!("osd-overlay {}", command["format"].as_str().unwrap()),
+            None => command
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|arg| arg.as_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(" "),
+        });
+    }
+
+    assert_eq!(
+        sent,
+        [
+            "get_property pause",
+            "set_property pause yes",
+            "osd-overlay ass-events",
+            "osd-overlay none",
+            "set_property pause no",
+            "osd-overlay ass-events",
+            "osd-overlay none",
+        ]
+    );
+    assert!(announcements.pending().is_empty());
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    autoplay::Autoplay,
+    history::History,
+    library::is_media_file,
+    mpv::{response::PlaylistEntry, Client, LoadFileOptions},
+    retention::{self, RetentionPolicy},
+    upload::{Reservation, UploadLimits},
+};
+
+/// How often the status of the downloads is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long finished and failed downloads are still shown.
+const KEEP_FINISHED: Duration = Duration::from_secs(10 * 60);
+
+/// A download is given up on after failing to get its status this many times in a row.
+const MAX_STATUS_ERRORS: u32 = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to start aria2c: {0}")]
+    Spawn(std::io::Error),
+
+    #[error("failed to reach aria2c: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("aria2c returned an error: {0}")]
+    Rpc(String),
+
+    #[error("invalid response from aria2c: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+}
+
+pub fn is_torrent_url(url: &str) -> bool {
+    url.starts_with("magnet:")
+        || url
+            .split(['?', '#'])
+            .next()
+            .is_some_and(|path| path.to_lowercase().ends_with(".torrent"))
+}
+
+fn number_from_string<'de, D: Deserializer<'de>>(de: D) -> Result<u64, D::Error> {
+    let s = String::deserialize(de)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+// Fields of the reply to aria2.tellStatus, which sends all numbers as strings.
+// https://aria2.github.io/manual/en/html/aria2c.html#aria2.tellStatus
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// One of active, waiting, paused, error, complete or removed.
+    pub status: String,
+    #[serde(deserialize_with = "number_from_string")]
+    pub total_length: u64,
+    #[serde(deserialize_with = "number_from_string")]
+    pub completed_length: u64,
+    #[serde(deserialize_with = "number_from_string")]
+    pub download_speed: u64,
+    #[serde(default)]
+    pub files: Vec<File>,
+
+    /// Downloads started after this one finished, like the files of a magnet link
+    /// after its metadata or the contents of a .torrent file.
+    #[serde(default)]
+    pub followed_by: Vec<String>,
+    pub error_message: Option<String>,
+    pub bittorrent: Option<BitTorrent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct File {
+    pub path: String,
+    #[serde(deserialize_with = "number_from_string")]
+    pub length: u64,
+    #[serde(deserialize_with = "number_from_string")]
+    pub completed_length: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BitTorrent {
+    pub info: Option<BitTorrentInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BitTorrentInfo {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+/// Client for the JSON-RPC interface of aria2c.
+#[derive(Debug, Clone)]
+pub struct Aria2 {
+    url: String,
+    secret: Option<String>,
+    http: hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl Aria2 {
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        Aria2 {
+            url,
+            secret,
+            http: hyper::Client::new(),
+        }
+    }
+
+    /// Starts an aria2c process that only listens on localhost and saves the downloads to `download_dir`.
+    pub fn spawn(path: &str, download_dir: &Path) -> Result<(Self, tokio::process::Child), Error> {
+        // Let the OS pick a free port for us.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(Error::Spawn)?
+            .port();
+        let secret = uuid::Uuid::new_v4().simple().to_string();
+
+        let child = tokio::process::Command::new(path)
+            .arg("--enable-rpc")
+            .arg("--rpc-listen-all=false")
+            .arg(format!("--rpc-listen-port={port}"))
+            .arg(format!("--rpc-secret={secret}"))
+            .arg(format!("--dir={}", download_dir.to_string_lossy()))
+            .arg("--seed-time=0")
+            .arg("--follow-torrent=true")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(Error::Spawn)?;
+
+        Ok((
+            Aria2::new(format!("http://127.0.0.1:{port}/jsonrpc"), Some(secret)),
+            child,
+        ))
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Result<T, Error> {
+        let mut params = params;
+        if let Some(secret) = &self.secret {
+            params.insert(0, serde_json::json!(format!("token:{secret}")));
+        }
+
+        let body = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "kameloso",
+            "method": method,
+            "params": params,
+        });
+
+        let request = hyper::Request::post(&self.url)
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(serde_json::to_vec(&body)?))
+            .expect("invalid aria2c request");
+
+        let response = self.http.request(request).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let response: RpcResponse<T> = serde_json::from_slice(&body)?;
+
+        match (response.result, response.error) {
+            (Some(result), _) => Ok(result),
+            (None, Some(error)) => Err(Error::Rpc(error.message)),
+            (None, None) => Err(Error::Rpc("empty response".to_string())),
+        }
+    }
+
+    /// Starts downloading a magnet link, .torrent file or anything else aria2c supports.
+    /// Returns the GID of the download.
+    pub async fn add_uri(&self, uri: &str) -> Result<String, Error> {
+        self.call("aria2.addUri", vec![serde_json::json!([uri])])
+            .await
+    }
+
+    pub async fn tell_status(&self, gid: &str) -> Result<Status, Error> {
+        self.call("aria2.tellStatus", vec![serde_json::json!(gid)])
+            .await
+    }
+
+    /// Stops a download right away.
+    pub async fn remove(&self, gid: &str) -> Result<String, Error> {
+        self.call("aria2.forceRemove", vec![serde_json::json!(gid)])
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    Downloading,
+    Done,
+    Failed,
+}
+
+/// A torrent shown as a pending entry in the queue until its files are enqueued.
+#[derive(Debug, Clone, Serialize)]
+pub struct Download {
+    pub id: u64,
+    pub url: String,
+    pub name: Option<String>,
+    pub total: u64,
+    pub completed: u64,
+
+    /// Bytes per second.
+    pub speed: u64,
+    pub state: DownloadState,
+    pub error: Option<String>,
+
+    #[serde(skip)]
+    gid: String,
+    #[serde(skip)]
+    queued_by: Option<IpAddr>,
+    #[serde(skip)]
+    enqueued: HashSet<String>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+
+    /// Failed attempts to get the status in a row.
+    #[serde(skip)]
+    status_errors: u32,
+}
+
+/// Downloads torrents with aria2c and enqueues their media files as soon as each one is complete.
+#[derive(Debug, Clone)]
+pub struct Torrents {
+    aria2: Aria2,
+    download_dir: PathBuf,
+
+    /// Downloads are limited and deleted like uploads.
+    limits: UploadLimits,
+    retention: RetentionPolicy,
+
+    ipc: Client,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    history: History,
+    autoplay: Autoplay,
+    downloads: Arc<Mutex<Vec<Download>>>,
+
+    /// Download ID to the space reserved for it once its size is known.
+    reservations: Arc<Mutex<HashMap<u64, Reservation>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Torrents {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        aria2: Aria2,
+        download_dir: PathBuf,
+        limits: UploadLimits,
+        retention: RetentionPolicy,
+        ipc: Client,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+        history: History,
+        autoplay: Autoplay,
+    ) -> Self {
+        Torrents {
+            aria2,
+            download_dir,
+            limits,
+            retention,
+            ipc,
+            playlist,
+            history,
+            autoplay,
+            downloads: Arc::new(Mutex::new(vec![])),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub async fn add(&self, url: &str, queued_by: Option<IpAddr>) -> Result<(), Error> {
+        let gid = self.aria2.add_uri(url).await?;
+
+        self.downloads.lock().unwrap().push(Download {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            url: url.to_string(),
+            name: None,
+            total: 0,
+            completed: 0,
+            speed: 0,
+            state: DownloadState::Downloading,
+            error: None,
+            gid,
+            queued_by,
+            enqueued: HashSet::new(),
+            finished_at: None,
+            status_errors: 0,
+        });
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Download> {
+        self.downloads.lock().unwrap().clone()
+    }
+
+    async fn enqueue(&self, path: &str, queued_by: Option<IpAddr>) {
+        match self.ipc.load_file(path, &LoadFileOptions::AppendPlay).await {
+            Ok(loaded) => {
+                self.history.queued(loaded.playlist_entry_id, queued_by);
+                self.autoplay.guest_queued().await;
+            }
+            Err(e) => log::warn!("failed to enqueue downloaded file {path}: {e}"),
+        }
+    }
+
+    fn fail(&self, mut download: Download, error: String) -> Download {
+        download.state = DownloadState::Failed;
+        download.error = Some(error);
+        download.finished_at = Some(Instant::now());
+        self.reservations.lock().unwrap().remove(&download.id);
+
+        download
+    }
+
+    async fn update(&self, mut download: Download) -> Download {
+        let status = match self.aria2.tell_status(&download.gid).await {
+            Ok(status) => status,
+            Err(e) => {
+                log::warn!("failed to get the status of {}: {e}", download.url);
+                download.status_errors += 1;
+                if download.status_errors >= MAX_STATUS_ERRORS {
+                    return self.fail(download, e.to_string());
+                }
+                return download;
+            }
+        };
+        download.status_errors = 0;
+
+        // The metadata of a magnet link or the .torrent file is done, follow the actual download.
+        if let Some(gid) = status.followed_by.first() {
+            download.gid = gid.clone();
+            return download;
+        }
+
+        // Check the limits as soon as we know how much there is to download.
+        if status.total_length > 0 && download.total == 0 {
+            match self
+                .limits
+                .check(&self.download_dir, download.queued_by, status.total_length)
+                .await
+            {
+                Ok(reservation) => {
+                    self.reservations
+                        .lock()
+                        .unwrap()
+                        .insert(download.id, reservation);
+                }
+                Err(e) => {
+                    if let Err(e) = self.aria2.remove(&download.gid).await {
+                        log::warn!("failed to stop the download of {}: {e}", download.url);
+                    }
+                    return self.fail(download, e.to_string());
+                }
+            }
+        }
+
+        download.total = status.total_length;
+        download.completed = status.completed_length;
+        download.speed = status.download_speed;
+        if let Some(info) = status.bittorrent.and_then(|bittorrent| bittorrent.info) {
+            download.name = Some(info.name);
+        }
+
+        for file in &status.files {
+            let is_complete = file.length > 0 && file.completed_length == file.length;
+
+            if is_complete
+                && is_media_file(&PathBuf::from(&file.path))
+                && download.enqueued.insert(file.path.clone())
+            {
+                self.enqueue(&file.path, download.queued_by).await;
+            }
+        }
+
+        match status.status.as_str() {
+            "complete" if download.enqueued.is_empty() => {
+                self.fail(download, "the torrent has no playable files".to_string())
+            }
+            "complete" => {
+                download.state = DownloadState::Done;
+                download.finished_at = Some(Instant::now());
+                if let Some(mut reservation) =
+                    self.reservations.lock().unwrap().remove(&download.id)
+                {
+                    reservation.record(download.total);
+                }
+                download
+            }
+            "error" | "removed" => {
+                let error = status
+                    .error_message
+                    .unwrap_or_else(|| "the download was removed".to_string());
+                self.fail(download, error)
+            }
+            _ => download,
+        }
+    }
+
+    /// Deletes old downloads according to the retention policy, keeping those that are still
+    /// downloading or have files in the queue.
+    async fn sweep(&self) {
+        if self
+            .list()
+            .iter()
+            .any(|download| download.state == DownloadState::Downloading)
+        {
+            return;
+        }
+
+        let keep: HashSet<PathBuf> = {
+            let playlist = self.playlist.read().await;
+            let current = playlist
+                .iter()
+                .position(|entry| entry.current || entry.playing)
+                .unwrap_or(0);
+
+            playlist[current..]
+                .iter()
+                .filter_map(|entry| {
+                    Path::new(&entry.filename)
+                        .strip_prefix(&self.download_dir)
+                        .ok()
+                })
+                .filter_map(|path| path.components().next())
+                .map(|download| self.download_dir.join(download))
+                .collect()
+        };
+
+        if let Err(e) = retention::sweep_downloads(self.retention, &self.download_dir, &keep).await
+        {
+            log::warn!("failed to clean up the downloads: {e}");
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        // Wait for a while before the first sweep, so that imported playlists are in the queue.
+        let mut sweep_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + retention::SWEEP_INTERVAL,
+            retention::SWEEP_INTERVAL,
+        );
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = sweep_interval.tick() => {
+                    self.sweep().await;
+                    continue;
+                }
+            }
+
+            let downloads = self.list();
+            let mut updated = Vec::with_capacity(downloads.len());
+
+            for download in downloads {
+                match download.finished_at {
+                    Some(finished_at) if finished_at.elapsed() > KEEP_FINISHED => {}
+                    Some(_) => updated.push(download),
+                    None => updated.push(self.update(download).await),
+                }
+            }
+
+            // Keep the downloads that were added while we were checking the others.
+            let mut downloads = self.downloads.lock().unwrap();
+            let added: Vec<_> = downloads
+                .iter()
+                .filter(|download| updated.iter().all(|updated| updated.id != download.id))
+                .filter(|download| download.finished_at.is_none())
+                .cloned()
+                .collect();
+            *downloads = updated;
+            downloads.extend(added);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_torrent_download() {
+    use warp::Filter;
+
+    let polls = Arc::new(AtomicU64::new(0));
+
+    let rpc = warp::post()
+        .and(warp::body::json())
+        .map({
+            let polls = polls.clone();
+            move |request: serde_json::Value| {
+                assert_eq!(request["params"][0], "token:secret");
+
+                let result = match request["method"].as_str().unwrap() {
+                    "aria2.addUri" => match request["params"][1][0].as_str().unwrap() {
+                        "magnet:?xt=urn:btih:abc" => serde_json::json!("meta"),
+                        "magnet:?xt=urn:btih:big" => serde_json::json!("big"),
+                        "magnet:?xt=urn:btih:gone" => serde_json::json!("gone"),
+                        uri => panic!("unexpected uri {uri}"),
+                    },
+                    "aria2.forceRemove" => {
+                        assert_eq!(request["params"][1], "big");
+                        serde_json::json!("big")
+                    }
+                    "aria2.tellStatus" if request["params"][1] == "big" => serde_json::json!({
+                        "status": "active",
+                        "totalLength": "1000000000",
+                        "completedLength": "0",
+                        "downloadSpeed": "0",
+                    }),
+                    "aria2.tellStatus" if request["params"][1] == "gone" => {
+                        return warp::reply::json(&serde_json::json!({
+                            "id": request["id"],
+                            "jsonrpc": "2.0",
+                            "error": { "code": 1, "message": "GID gone is not found" },
+                        }));
+                    }
+                    "aria2.tellStatus" if request["params"][1] == "meta" => serde_json::json!({
+                        "status": "complete",
+                        "totalLength": "100",
+                        "completedLength": "100",
+                        "downloadSpeed": "0",
+                        "followedBy": ["files"],
+                    }),
+                    "aria2.tellStatus" => {
+                        let done = polls.fetch_add(1, Ordering::SeqCst) > 0;
+                        let completed = if done { "1000" } else { "10" };
+                        serde_json::json!({
+                            "status": if done { "complete" } else { "active" },
+                            "totalLength": "1010",
+                            "completedLength": completed,
+                            "downloadSpeed": "500",
+                            "bittorrent": { "info": { "name": "Movie" } },
+                            "files": [
+                                { "path": "/dl/Movie/movie.mkv", "length": "1000", "completedLength": completed },
+                                { "path": "/dl/Movie/info.nfo", "length": "10", "completedLength": "10" },
+                            ],
+                        })
+                    }
+                    method => panic!("unexpected method {method}"),
+                };
+
+                warp::reply::json(&serde_json::json!({
+                    "id": request["id"],
+                    "jsonrpc": "2.0",
+                    "result": result,
+                }))
+            }
+        });
+
+    let (addr, server) = warp::serve(rpc).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let dir = crate::test_dir::TestDir::new();
+    let (ipc, mut commands) = Client::fake();
+    let ytdlp = crate::ytdlp::YtDlp::new("yt-dlp".to_string());
+    let history = History::new(
+        &dir.join("history.jsonl"),
+        Arc::new(tokio::sync::RwLock::new(vec![])),
+        crate::metadata::Resolver::start(
+            ytdlp.clone(),
+            crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+            1,
+        ),
+    );
+    let autoplay = Autoplay::new(
+        None,
+        ipc.clone(),
+        history.clone(),
+        crate::library::Library::default(),
+        ytdlp,
+    );
+    std::fs::create_dir_all(&dir).unwrap();
+    let torrents = Torrents::new(
+        Aria2::new(format!("http://{addr}/jsonrpc"), Some("secret".to_string())),
+        dir.to_path_buf(),
+        UploadLimits::new(1024 * 1024, None, None, 0),
+        RetentionPolicy::Forever,
+        ipc,
+        Arc::new(RwLock::new(vec![])),
+        history,
+        autoplay,
+    );
+
+    // Torrents larger than an upload can be are stopped as soon as their size is known.
+    torrents.add("magnet:?xt=urn:btih:big", None).await.unwrap();
+    let big = torrents.update(torrents.list().remove(0)).await;
+    assert_eq!(big.state, DownloadState::Failed);
+    assert!(big.error.unwrap().starts_with("file is too large"));
+
+    // Downloads that aria2c forgot about don't stay in the queue forever.
+    torrents
+        .add("magnet:?xt=urn:btih:gone", None)
+        .await
+        .unwrap();
+    let mut gone = torrents.list().remove(1);
+    for _ in 0..MAX_STATUS_ERRORS {
+        assert_eq!(gone.state, DownloadState::Downloading);
+        gone = torrents.update(gone).await;
+    }
+    assert_eq!(gone.state, DownloadState::Failed);
+
+    *torrents.downloads.lock().unwrap() = vec![];
+    torrents.add("magnet:?xt=urn:btih:abc", None).await.unwrap();
+    tokio::spawn(torrents.clone().run());
+
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "/dl/Movie/movie.mkv", "append-play"])
+    );
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let downloads = torrents.list();
+    assert_eq!(downloads[0].name.as_deref(), Some("Movie"));
+    assert_eq!(downloads[0].state, DownloadState::Done);
+    assert!(is_torrent_url("https://example.com/linux.iso.torrent?dl=1"));
+    assert!(!is_torrent_url("https://example.com/video.mp4"));
+}
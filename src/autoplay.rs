@@ -0,0 +1,437 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::seq::SliceRandom;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    history::History,
+    library::Library,
+    metadata::is_url,
+    mpv::{event::StartFile, response::PlaylistEntry, Client, LoadFileOptions},
+    ytdlp::YtDlp,
+};
+
+/// Number of filler entries that won't be picked again.
+const MAX_RECENT: usize = 50;
+
+/// Number of related videos looked up for each filler entry.
+const MAX_RELATED: usize = 25;
+
+/// How often to try again while mpv is idle, in case the last filler entry failed to load.
+const IDLE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where filler entries come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Entries that were played before.
+    History,
+
+    /// Files in a folder of the library, as a library path.
+    Library(String),
+
+    /// YouTube's mix of videos related to the last one, falling back to the history.
+    Related,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "history" => Ok(Source::History),
+            None if s == "related" => Ok(Source::Related),
+            Some(("library", path)) if !path.is_empty() => Ok(Source::Library(path.to_string())),
+            _ => Err("expected one of history, related or library=<library path>".to_string()),
+        }
+    }
+}
+
+/// The ID of a YouTube video from its URL.
+fn youtube_id(url: &str) -> Option<&str> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let rest = rest.strip_prefix("www.").unwrap_or(rest);
+    let rest = rest.strip_prefix("m.").unwrap_or(rest);
+
+    let id = if let Some(rest) = rest.strip_prefix("youtu.be/") {
+        rest
+    } else if let Some(rest) = rest.strip_prefix("youtube.com/shorts/") {
+        rest
+    } else {
+        let query = rest.strip_prefix("youtube.com/watch?")?;
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("v="))?
+    };
+
+    let id = id.split(['?', '&', '#', '/']).next()?;
+    (!id.is_empty()).then_some(id)
+}
+
+/// Indices of the upcoming filler entries that should make room for an entry queued by a guest,
+/// from last to first so they can be removed in order, and whether the playing entry is a filler.
+fn yield_plan(playlist: &[PlaylistEntry], fillers: &HashSet<u64>) -> (Vec<usize>, bool) {
+    let playing = playlist.iter().position(|entry| entry.playing);
+    let upcoming = playing.map_or(0, |playing| playing + 1);
+
+    let mut indices: Vec<_> = (upcoming..playlist.len())
+        .filter(|&i| fillers.contains(&playlist[i].id))
+        .collect();
+    indices.reverse();
+
+    let skip = playing.is_some_and(|playing| fillers.contains(&playlist[playing].id));
+
+    (indices, skip)
+}
+
+/// Keeps something playing when the queue runs out, and gets out of the way as soon as
+/// a guest queues something.
+#[derive(Debug, Clone)]
+pub struct Autoplay {
+    /// Autoplay is disabled when unset.
+    source: Option<Source>,
+    ipc: Client,
+    history: History,
+    library: Library,
+    ytdlp: YtDlp,
+    fillers: Arc<Mutex<HashSet<u64>>>,
+    recent: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Autoplay {
+    pub fn new(
+        source: Option<Source>,
+        ipc: Client,
+        history: History,
+        library: Library,
+        ytdlp: YtDlp,
+    ) -> Self {
+        Autoplay {
+            source,
+            ipc,
+            history,
+            library,
+            ytdlp,
+            fillers: Arc::new(Mutex::new(HashSet::new())),
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn is_filler(&self, playlist_entry_id: u64) -> bool {
+        self.fillers.lock().unwrap().contains(&playlist_entry_id)
+    }
+
+    /// Keeps a filler entry marked as one when it's replaced by a local copy.
+    pub fn replaced(&self, old_id: u64, new_id: u64) {
+        let mut fillers = self.fillers.lock().unwrap();
+        if fillers.remove(&old_id) {
+            fillers.insert(new_id);
+        }
+    }
+
+    async fn history_candidates(&self) -> Vec<String> {
+        match self.history.entries().await {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|entry| entry.url)
+                .filter(|url| is_url(url) || Path::new(url).exists())
+                .collect(),
+            Err(e) => {
+                log::warn!("failed to read the history: {e}");
+                vec![]
+            }
+        }
+    }
+
+    async fn related_candidates(&self, playlist: &[PlaylistEntry]) -> Vec<String> {
+        let Some(id) = playlist
+            .iter()
+            .rev()
+            .find_map(|entry| youtube_id(&entry.filename))
+        else {
+            return vec![];
+        };
+
+        let mix = format!("https://www.youtube.com/watch?v={id}&list=RD{id}");
+        match self.ytdlp.flat_playlist(&mix, MAX_RELATED).await {
+            Ok(related) => related
+                .entries
+                .into_iter()
+                .map(|entry| entry.url)
+                .filter(|url| youtube_id(url) != Some(id))
+                .collect(),
+            Err(e) => {
+                log::warn!("failed to look up videos related to {id}: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// Picks an entry that isn't in the playlist and wasn't picked recently.
+    async fn pick(&self, source: &Source, playlist: &[PlaylistEntry]) -> Option<String> {
+        let candidates = match source {
+            Source::History => self.history_candidates().await,
+            Source::Library(library_path) => match self.library.media_files(library_path).await {
+                Ok(files) => files
+                    .into_iter()
+                    .map(|path| path.to_string_lossy().to_string())
+                    .collect(),
+                Err(e) => {
+                    log::warn!("failed to list {library_path}: {e}");
+                    vec![]
+                }
+            },
+            Source::Related => match self.related_candidates(playlist).await {
+                related if related.is_empty() => self.history_candidates().await,
+                related => related,
+            },
+        };
+
+        let recent = self.recent.lock().unwrap();
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|url| {
+                !recent.contains(url) && !playlist.iter().any(|entry| &entry.filename == url)
+            })
+            .collect();
+
+        candidates.choose(&mut rand::thread_rng()).cloned()
+    }
+
+    async fn fill(&self, playlist: &[PlaylistEntry], options: &LoadFileOptions) {
+        let Some(source) = &self.source else {
+            return;
+        };
+
+        let Some(url) = self.pick(source, playlist).await else {
+            log::info!("the queue is empty and there's nothing to autoplay");
+            return;
+        };
+
+        match self.ipc.load_file(&url, options).await {
+            Ok(loaded) => {
+                log::info!("autoplaying {url}");
+
+                let mut fillers = self.fillers.lock().unwrap();
+                fillers.retain(|id| playlist.iter().any(|entry| entry.id == *id));
+                fillers.insert(loaded.playlist_entry_id as u64);
+
+                let mut recent = self.recent.lock().unwrap();
+                if recent.len() == MAX_RECENT {
+                    recent.pop_front();
+                }
+                recent.push_back(url);
+            }
+            Err(e) => log::warn!("failed to autoplay {url}: {e}"),
+        }
+    }
+
+    /// Removes the upcoming filler entries and skips the one that's playing,
+    /// so that what a guest just queued plays right away.
+    pub async fn guest_queued(&self) {
+        if self.fillers.lock().unwrap().is_empty() {
+            return;
+        }
+
+        let playlist = match self.ipc.get_playlist().await {
+            Ok(playlist) => playlist,
+            Err(e) => {
+                log::warn!("failed to get the playlist: {e}");
+                return;
+            }
+        };
+
+        let (indices, skip) = yield_plan(&playlist, &self.fillers.lock().unwrap());
+
+        for index in indices {
+            if let Err(e) = self.ipc.playlist_remove(index as u64).await {
+                log::warn!("failed to remove a filler entry: {e}");
+            }
+        }
+
+        if skip {
+            if let Err(e) = self.ipc.playlist_next().await {
+                log::warn!("failed to skip the filler entry: {e}");
+            }
+        }
+    }
+
+    pub async fn run(
+        self,
+        mut start_file_rx: UnboundedReceiver<StartFile>,
+        mut idle_rx: UnboundedReceiver<serde_json::Value>,
+    ) {
+        if self.source.is_none() {
+            return;
+        }
+
+        let mut idle = false;
+        let mut retry = tokio::time::interval(IDLE_RETRY_INTERVAL);
+
+        loop {
+            let options = tokio::select! {
+                start_file = start_file_rx.recv() => match start_file {
+                    // Queue the next one while the last entry plays, so the screen never goes blank.
+                    Some(StartFile { playlist_entry_id }) => {
+                        let playlist = self.ipc.get_playlist().await.unwrap_or_default();
+                        if playlist.last().map(|entry| entry.id as i64) != Some(playlist_entry_id) {
+                            continue;
+                        }
+                        LoadFileOptions::Append
+                    }
+                    None => break,
+                },
+
+                idle_active = idle_rx.recv() => {
+                    let Some(idle_active) = idle_active else {
+                        break;
+                    };
+
+                    idle = idle_active.as_bool().unwrap_or(false);
+                    if !idle {
+                        continue;
+                    }
+                    retry.reset();
+                    LoadFileOptions::AppendPlay
+                }
+
+                _ = retry.tick() => {
+                    if !idle {
+                        continue;
+                    }
+                    LoadFileOptions::AppendPlay
+                }
+            };
+
+            let playlist = self.ipc.get_playlist().await.unwrap_or_default();
+            self.fill(&playlist, &options).await;
+        }
+    }
+}
+
+#[test]
+fn test_autoplay_source() {
+    assert_eq!("history".parse(), Ok(Source::History));
+    assert_eq!("related".parse(), Ok(Source::Related));
+    assert_eq!(
+        "library=Music/party".parse(),
+        Ok(Source::Library("Music/party".to_string()))
+    );
+    assert!("library=".parse::<Source>().is_err());
+    assert!("radio".parse::<Source>().is_err());
+}
+
+#[test]
+fn test_youtube_id() {
+    assert_eq!(
+        youtube_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42"),
+        Some("dQw4w9WgXcQ")
+    );
+    assert_eq!(
+        youtube_id("https://m.youtube.com/watch?feature=share&v=dQw4w9WgXcQ"),
+        Some("dQw4w9WgXcQ")
+    );
+    assert_eq!(
+        youtube_id("https://youtu.be/dQw4w9WgXcQ?si=abc"),
+        Some("dQw4w9WgXcQ")
+    );
+    assert_eq!(
+        youtube_id("https://youtube.com/shorts/dQw4w9WgXcQ"),
+        Some("dQw4w9WgXcQ")
+    );
+    assert_eq!(youtube_id("https://www.youtube.com/watch?list=PL123"), None);
+    assert_eq!(youtube_id("https://example.com/watch?v=abc"), None);
+    assert_eq!(youtube_id("/music/song.mp3"), None);
+}
+
+#[test]
+fn test_yield_plan() {
+    let entry = |id, playing| PlaylistEntry {
+        id,
+        filename: format!("/music/{id}.mp3"),
+        title: None,
+        current: playing,
+        playing,
+    };
+    let fillers = HashSet::from([2, 4, 5]);
+
+    let playlist = [
+        entry(1, false),
+        entry(2, true),
+        entry(3, false),
+        entry(4, false),
+        entry(5, false),
+        entry(6, false),
+    ];
+    assert_eq!(yield_plan(&playlist, &fillers), (vec![4, 3], true));
+
+    let playlist = [entry(1, true), entry(4, false), entry(6, false)];
+    assert_eq!(yield_plan(&playlist, &fillers), (vec![1], false));
+}
+
+#[tokio::test]
+async fn test_autoplay_library() {
+    let dir = crate::test_dir::TestDir::new();
+    let music = dir.join("music");
+    std::fs::create_dir_all(&music).unwrap();
+    std::fs::write(music.join("a.mp3"), b"").unwrap();
+    std::fs::write(music.join("b.mp3"), b"").unwrap();
+    std::fs::write(music.join("cover.jpg"), b"").unwrap();
+
+    let library = Library::new(std::slice::from_ref(&music)).unwrap();
+    let ytdlp = YtDlp::new("yt-dlp".to_string());
+    let metadata = crate::metadata::Resolver::start(
+        ytdlp.clone(),
+        crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+        1,
+    );
+    let history = History::new(
+        &dir.join("history.jsonl"),
+        Arc::new(tokio::sync::RwLock::new(vec![])),
+        metadata,
+    );
+
+    let (ipc, mut commands) = Client::fake();
+    let autoplay = Autoplay::new(
+        Some(Source::Library("music".to_string())),
+        ipc,
+        history,
+        library,
+        ytdlp,
+    );
+
+    let a = music.canonicalize().unwrap().join("a.mp3");
+    let playing = PlaylistEntry {
+        id: 10,
+        filename: a.to_string_lossy().to_string(),
+        title: None,
+        current: true,
+        playing: true,
+    };
+
+    // The file that's playing is never picked again.
+    autoplay
+        .fill(std::slice::from_ref(&playing), &LoadFileOptions::Append)
+        .await;
+    let loadfile = commands.recv().await.unwrap();
+    assert!(loadfile[1].as_str().unwrap().ends_with("b.mp3"));
+    assert_eq!(loadfile[2], "append");
+    assert!(autoplay.is_filler(1));
+    assert!(!autoplay.is_filler(10));
+
+    // Nothing left that wasn't played recently.
+    autoplay.fill(&[], &LoadFileOptions::AppendPlay).await;
+    let loadfile = commands.recv().await.unwrap();
+    assert!(loadfile[1].as_str().unwrap().ends_with("a.mp3"));
+    autoplay.fill(&[], &LoadFileOptions::AppendPlay).await;
+    assert!(commands.try_recv().is_err());
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::Rng;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{
+    events::{Event, Events},
+    mpv::Client,
+    osd::{self, escape_ass},
+};
+
+/// How long a chat message stays on screen.
+const MESSAGE_DURATION: Duration = Duration::from_secs(15);
+
+/// Number of chat messages on screen at once, the oldest ones scroll off the top.
+const MAX_VISIBLE_MESSAGES: usize = 6;
+
+/// How long a reaction takes to float up the screen.
+const REACTION_DURATION: Duration = Duration::from_secs(3);
+
+/// Reactions are meant to be an emoji or two, not messages.
+const MAX_REACTION_LENGTH: usize = 8;
+
+/// Window of the per-user rate limit.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Time between frames while a reaction is moving.
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("message is empty")]
+    Empty,
+
+    #[error("message is longer than {0} characters")]
+    TooLong(usize),
+
+    #[error("too many messages, slow down")]
+    RateLimited,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub text: String,
+    pub reaction: bool,
+
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Limits the length of messages and masks blocked words.
+#[derive(Debug, Clone)]
+pub struct ChatFilter {
+    max_length: usize,
+
+    /// Lowercase words replaced with asterisks.
+    blocked_words: Vec<String>,
+}
+
+impl ChatFilter {
+    pub fn new(max_length: usize, blocked_words: Vec<String>) -> Self {
+        ChatFilter {
+            max_length,
+            blocked_words: blocked_words
+                .into_iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Reads the blocked words from a file with one word per line.
+    pub async fn load(max_length: usize, path: &Path) -> std::io::Result<Self> {
+        let words = tokio::fs::read_to_string(path).await?;
+        Ok(ChatFilter::new(
+            max_length,
+            words.lines().map(str::to_string).collect(),
+        ))
+    }
+
+    fn is_blocked(&self, word: &str) -> bool {
+        let word = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+        self.blocked_words.contains(&word)
+    }
+
+    pub fn apply(&self, text: &str, max_length: usize) -> Result<String, Error> {
+        let max_length = max_length.min(self.max_length);
+        let words: Vec<_> = text.split_whitespace().collect();
+
+        if words.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        let text = words
+            .into_iter()
+            .map(|word| {
+                if self.is_blocked(word) {
+                    "*".repeat(word.chars().count())
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if text.chars().count() > max_length {
+            return Err(Error::TooLong(max_length));
+        }
+
+        Ok(text)
+    }
+}
+
+#[derive(Debug)]
+struct Reaction {
+    text: String,
+    x: u32,
+    started: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Visible {
+    messages: VecDeque<(Instant, String)>,
+    reactions: Vec<Reaction>,
+}
+
+impl Visible {
+    fn expire(&mut self, now: Instant) {
+        self.messages
+            .retain(|(posted, _)| now.duration_since(*posted) < MESSAGE_DURATION);
+        self.reactions
+            .retain(|reaction| now.duration_since(reaction.started) < REACTION_DURATION);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.reactions.is_empty()
+    }
+
+    /// The ASS events for the current frame, one per line.
+    fn render(&self, now: Instant) -> String {
+        let mut events = vec![];
+
+        if !self.messages.is_empty() {
+            let lines: Vec<_> = self
+                .messages
+                .iter()
+                .map(|(_, text)| escape_ass(text))
+                .collect();
+
+            events.push(format!(
+                "{{\\an1\\pos(20,{})\\fs26\\bord2\\shad0}}{}",
+                osd::RES_Y - 20,
+                lines.join("\\N")
+            ));
+        }
+
+        for reaction in &self.reactions {
+            let progress = now.duration_since(reaction.started).as_secs_f64()
+                / REACTION_DURATION.as_secs_f64();
+            let y = osd::RES_Y as f64 - 30.0 - progress * 400.0;
+            let alpha = (progress * 255.0).clamp(0.0, 255.0) as u8;
+
+            events.push(format!(
+                "{{\\an2\\pos({},{y:.0})\\fs56\\bord1\\alpha&H{alpha:02X}&}}{}",
+                reaction.x,
+                escape_ass(&reaction.text)
+            ));
+        }
+
+        events.join("\n")
+    }
+}
+
+/// Messages and reactions from the guests, shown on top of the video and sent to the other clients.
+#[derive(Debug, Clone)]
+pub struct Chat {
+    ipc: Client,
+    events: Events,
+    filter: ChatFilter,
+    max_per_minute: usize,
+    sent: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+    visible: Arc<Mutex<Visible>>,
+    changed: Arc<Notify>,
+}
+
+impl Chat {
+    pub fn new(ipc: Client, events: Events, filter: ChatFilter, max_per_minute: usize) -> Self {
+        Chat {
+            ipc,
+            events,
+            filter,
+            max_per_minute,
+            sent: Arc::new(Mutex::new(HashMap::new())),
+            visible: Arc::new(Mutex::new(Visible::default())),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// The most characters a message can have.
+    pub fn max_length(&self) -> usize {
+        self.filter.max_length
+    }
+
+    fn check_rate_limit(&self, from: Option<IpAddr>) -> Result<(), Error> {
+        let from = from.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let now = Instant::now();
+
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, times| {
+            times.retain(|time| now.duration_since(*time) < RATE_LIMIT_WINDOW);
+            !times.is_empty()
+        });
+
+        let times = sent.entry(from).or_default();
+        if times.len() >= self.max_per_minute {
+            return Err(Error::RateLimited);
+        }
+
+        times.push_back(now);
+        Ok(())
+    }
+
+    pub fn post(
+        &self,
+        text: &str,
+        reaction: bool,
+        from: Option<IpAddr>,
+    ) -> Result<ChatMessage, Error> {
+        let max_length = if reaction {
+            MAX_REACTION_LENGTH
+        } else {
+            usize::MAX
+        };
+        let text = self.filter.apply(text, max_length)?;
+        self.check_rate_limit(from)?;
+
+        {
+            let mut visible = self.visible.lock().unwrap();
+
+            if reaction {
+                let x = rand::thread_rng().gen_range(osd::RES_X / 10..osd::RES_X * 9 / 10);
+                visible.reactions.push(Reaction {
+                    text: text.clone(),
+                    x,
+                    started: Instant::now(),
+                });
+            } else {
+                if visible.messages.len() == MAX_VISIBLE_MESSAGES {
+                    visible.messages.pop_front();
+                }
+                visible.messages.push_back((Instant::now(), text.clone()));
+            }
+        }
+        self.changed.notify_one();
+
+        let message = ChatMessage {
+            text,
+            reaction,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        self.events.send(Event::Chat(message.clone()));
+
+        Ok(message)
+    }
+
+    /// Keeps the overlay up to date with the visible messages and reactions.
+    pub async fn run(self) {
+        let mut shown = false;
+
+        loop {
+            let (frame, animating) = {
+                let mut visible = self.visible.lock().unwrap();
+                let now = Instant::now();
+                visible.expire(now);
+
+                if visible.is_empty() {
+                    (None, false)
+                } else {
+                    (Some(visible.render(now)), !visible.reactions.is_empty())
+                }
+            };
+
+            let result = match &frame {
+                Some(frame) => {
+                    self.ipc
+                        .osd_overlay(osd::CHAT_OVERLAY_ID, frame, osd::RES_X, osd::RES_Y)
+                        .await
+                }
+                None if shown => self.ipc.osd_overlay_remove(osd::CHAT_OVERLAY_ID).await,
+                None => Ok(()),
+            };
+            if let Err(e) = result {
+                log::warn!("failed to draw the chat overlay: {e}");
+            }
+            shown = frame.is_some();
+
+            if !shown {
+                self.changed.notified().await;
+                continue;
+            }
+
+            // Messages only need redrawing when one expires, reactions move every frame.
+            let delay = if animating {
+                FRAME_INTERVAL
+            } else {
+                Duration::from_secs(1)
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_chat_filter() {
+    let filter = ChatFilter::new(20, vec!["Heck".to_string(), "".to_string()]);
+
+    assert_eq!(
+        filter.apply("  what the\n HECK! ", usize::MAX).unwrap(),
+        "what the *****"
+    );
+    assert!(matches!(filter.apply(" \n", usize::MAX), Err(Error::Empty)));
+    assert!(matches!(
+        filter.apply("this message is way too long", usize::MAX),
+        Err(Error::TooLong(20))
+    ));
+    assert!(matches!(filter.apply("🔥🔥🔥", 2), Err(Error::TooLong(2))));
+}
+
+#[test]
+fn test_chat_render() {
+    let now = Instant::now();
+    let mut visible = Visible::default();
+    visible
+        .messages
+        .push_back((now - Duration::from_secs(60), "old".to_string()));
+    visible.messages.push_back((now, "{\\fs200}hi".to_string()));
+    visible.reactions.push(Reaction {
+        text: "🎉".to_string(),
+        x: 640,
+        started: now,
+    });
+
+    visible.expire(now);
+    assert_eq!(
+        visible.render(now),
+        "{\\an1\\pos(20,700)\\fs26\\bord2\\shad0}\\{\\\u{feff}fs200\\}hi\n\
+         {\\an2\\pos(640,690)\\fs56\\bord1\\alpha&H00&}🎉"
+    );
+
+    visible.expire(now + MESSAGE_DURATION);
+    assert!(visible.is_empty());
+}
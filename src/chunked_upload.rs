@@ -0,0 +1,438 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::upload::{self, Reservation};
+
+/// Name of the directory inside of the upload dir where unfinished uploads are kept.
+pub const PARTIAL_DIR: &str = ".partial";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no such upload: {0}")]
+    NotFound(String),
+
+    #[error("upload is being written to by another request")]
+    Busy,
+
+    #[error("wrong offset, the upload is at {expected}")]
+    OffsetMismatch { expected: u64 },
+
+    #[error("chunk goes past the declared size of the upload")]
+    TooLarge,
+
+    #[error("checksum mismatch, the upload has been discarded")]
+    ChecksumMismatch,
+
+    #[error("invalid filename")]
+    InvalidFilename,
+
+    #[error(transparent)]
+    Upload(#[from] upload::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NewUpload {
+    pub filename: String,
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 of the whole file, checked once the last chunk is received.
+    pub sha256: Option<String>,
+}
+
+// What's saved to disk next to the data of the upload.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StoredUpload {
+    #[serde(flatten)]
+    upload: NewUpload,
+    owner: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+pub struct FinishedUpload {
+    pub path: PathBuf,
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 of the file.
+    pub sha256: String,
+
+    /// Address of the user who created the upload.
+    pub owner: Option<IpAddr>,
+
+    /// The space reserved when the upload was created, unless kameloso was restarted since.
+    pub reservation: Option<Reservation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadStatus {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub offset: u64,
+    pub complete: bool,
+}
+
+/// Marks an upload as busy until dropped, so that two chunks can't be written at the same time.
+struct BusyGuard {
+    busy: Arc<Mutex<HashSet<String>>>,
+    id: String,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub async fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Uploads that are sent in chunks and can be resumed if the connection drops.
+///
+/// The metadata of each upload is saved as `<id>.json` next to the data in `<id>.part`,
+/// and the length of the data on disk is the offset the client should resume from.
+#[derive(Debug, Clone)]
+pub struct ChunkedUploads {
+    dir: PathBuf,
+    busy: Arc<Mutex<HashSet<String>>>,
+    reservations: Arc<Mutex<HashMap<String, Reservation>>>,
+}
+
+impl ChunkedUploads {
+    pub fn new(upload_dir: &Path) -> Self {
+        ChunkedUploads {
+            dir: upload_dir.join(PARTIAL_DIR),
+            busy: Arc::new(Mutex::new(HashSet::new())),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn paths(&self, id: &str) -> Result<(PathBuf, PathBuf), Error> {
+        // IDs are only ever UUIDs, which also makes sure they can't escape the directory.
+        let id = uuid::Uuid::parse_str(id).map_err(|_| Error::NotFound(id.to_string()))?;
+
+        Ok((
+            self.dir.join(format!("{id}.json")),
+            self.dir.join(format!("{id}.part")),
+        ))
+    }
+
+    fn lock(&self, id: &str) -> Result<BusyGuard, Error> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return Err(Error::Busy);
+        }
+
+        Ok(BusyGuard {
+            busy: self.busy.clone(),
+            id: id.to_string(),
+        })
+    }
+
+    async fn read_upload(&self, id: &str) -> Result<(StoredUpload, u64), Error> {
+        let (meta_path, part_path) = self.paths(id)?;
+
+        let meta = match fs::read(&meta_path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::NotFound(id.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let upload: StoredUpload = serde_json::from_slice(&meta).map_err(io::Error::from)?;
+        let offset = fs::metadata(&part_path).await?.len();
+
+        Ok((upload, offset))
+    }
+
+    pub async fn create(
+        &self,
+        upload: NewUpload,
+        owner: Option<IpAddr>,
+        reservation: Reservation,
+    ) -> Result<UploadStatus, Error> {
+        if Path::new(&upload.filename).file_name().is_none() {
+            return Err(Error::InvalidFilename);
+        }
+
+        fs::create_dir_all(&self.dir).await?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let (meta_path, part_path) = self.paths(&id)?;
+
+        let stored = StoredUpload {
+            upload: upload.clone(),
+            owner,
+        };
+
+        fs::File::create(&part_path).await?;
+        fs::write(
+            &meta_path,
+            serde_json::to_vec(&stored).map_err(io::Error::from)?,
+        )
+        .await?;
+
+        let mut reservations = self.reservations.lock().unwrap();
+        // Give back the space of the uploads that were abandoned and cleaned up.
+        reservations.retain(|id, _| {
+            self.paths(id)
+                .is_ok_and(|(meta_path, _)| meta_path.exists())
+        });
+        reservations.insert(id.clone(), reservation);
+        drop(reservations);
+
+        Ok(UploadStatus {
+            id,
+            filename: upload.filename,
+            size: upload.size,
+            offset: 0,
+            complete: false,
+        })
+    }
+
+    pub async fn status(&self, id: &str) -> Result<UploadStatus, Error> {
+        let (StoredUpload { upload, .. }, offset) = self.read_upload(id).await?;
+
+        Ok(UploadStatus {
+            id: id.to_string(),
+            filename: upload.filename,
+            size: upload.size,
+            offset,
+            complete: offset == upload.size,
+        })
+    }
+
+    /// Appends a chunk at the given offset, which must be where the previous chunk ended.
+    pub async fn append(&self, id: &str, offset: u64, chunk: &[u8]) -> Result<UploadStatus, Error> {
+        let _guard = self.lock(id)?;
+        let (StoredUpload { upload, .. }, current_offset) = self.read_upload(id).await?;
+
+        if offset != current_offset {
+            return Err(Error::OffsetMismatch {
+                expected: current_offset,
+            });
+        }
+
+        if offset + chunk.len() as u64 > upload.size {
+            return Err(Error::TooLarge);
+        }
+
+        let (_, part_path) = self.paths(id)?;
+        let mut file = fs::OpenOptions::new().append(true).open(&part_path).await?;
+        file.write_all(chunk).await?;
+        file.flush().await?;
+
+        let offset = offset + chunk.len() as u64;
+
+        Ok(UploadStatus {
+            id: id.to_string(),
+            filename: upload.filename,
+            size: upload.size,
+            offset,
+            complete: offset == upload.size,
+        })
+    }
+
+    /// Verifies a complete upload and moves it to `dest_dir`.
+    /// If the checksum doesn't match or it isn't a media file the upload is discarded.
+    pub async fn finish(&self, id: &str, dest_dir: &Path) -> Result<FinishedUpload, Error> {
+        let _guard = self.lock(id)?;
+        let (StoredUpload { upload, owner }, offset) = self.read_upload(id).await?;
+        let (meta_path, part_path) = self.paths(id)?;
+
+        if offset != upload.size {
+            return Err(Error::OffsetMismatch { expected: offset });
+        }
+
+        let sha256 = sha256_file(&part_path).await?;
+
+        if let Some(expected) = &upload.sha256 {
+            if !sha256.eq_ignore_ascii_case(expected) {
+                self.discard(id, &meta_path, &part_path).await;
+                return Err(Error::ChecksumMismatch);
+            }
+        }
+
+        let mut head = Vec::with_capacity(upload::SNIFF_LEN);
+        fs::File::open(&part_path)
+            .await?
+            .take(upload::SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await?;
+
+        if let Err(e) = upload::sniff(&head, &upload.filename) {
+            self.discard(id, &meta_path, &part_path).await;
+            return Err(e.into());
+        }
+
+        let filename = Path::new(&upload.filename)
+            .file_name()
+            .ok_or(Error::InvalidFilename)?
+            .to_string_lossy();
+        let dest = dest_dir.join(format!("{id}-{filename}"));
+
+        fs::rename(&part_path, &dest).await?;
+        fs::remove_file(&meta_path).await?;
+
+        Ok(FinishedUpload {
+            path: dest,
+            size: upload.size,
+            sha256,
+            owner,
+            reservation: self.reservations.lock().unwrap().remove(id),
+        })
+    }
+
+    async fn discard(&self, id: &str, meta_path: &Path, part_path: &Path) {
+        let _ = fs::remove_file(part_path).await;
+        let _ = fs::remove_file(meta_path).await;
+        self.reservations.lock().unwrap().remove(id);
+    }
+
+    pub async fn cancel(&self, id: &str) -> Result<(), Error> {
+        let _guard = self.lock(id)?;
+        let (meta_path, part_path) = self.paths(id)?;
+
+        if !fs::try_exists(&meta_path).await? {
+            return Err(Error::NotFound(id.to_string()));
+        }
+
+        let _ = fs::remove_file(&part_path).await;
+        fs::remove_file(&meta_path).await?;
+        self.reservations.lock().unwrap().remove(id);
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_chunked_upload_resume() {
+    let dir = crate::test_dir::TestDir::new();
+    fs::create_dir_all(&dir).await.unwrap();
+
+    let uploads = ChunkedUploads::new(&dir);
+    let limits = upload::UploadLimits::new(1024, Some(1024), None, 0);
+    let user = Some("10.0.0.1".parse().unwrap());
+    let reserve = |size| limits.check(&dir, user, size);
+    let data = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00mp42isom";
+
+    let status = uploads
+        .create(
+            NewUpload {
+                filename: "video.mp4".to_string(),
+                size: data.len() as u64,
+                sha256: Some(format!("{:x}", Sha256::digest(data))),
+            },
+            user,
+            reserve(data.len() as u64).await.unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let status = uploads.append(&status.id, 0, &data[..5]).await.unwrap();
+    assert_eq!(status.offset, 5);
+    assert!(!status.complete);
+
+    // A retried chunk after a dropped connection is rejected with the offset to resume from.
+    assert!(matches!(
+        uploads.append(&status.id, 0, &data[..5]).await,
+        Err(Error::OffsetMismatch { expected: 5 })
+    ));
+    assert!(matches!(
+        uploads.append(&status.id, 5, &[0; 100]).await,
+        Err(Error::TooLarge)
+    ));
+    assert_eq!(uploads.status(&status.id).await.unwrap().offset, 5);
+
+    let status = uploads.append(&status.id, 5, &data[5..]).await.unwrap();
+    assert!(status.complete);
+
+    let finished = uploads.finish(&status.id, &dir).await.unwrap();
+    assert_eq!(fs::read(&finished.path).await.unwrap(), data);
+    finished.reservation.unwrap().record(finished.size);
+    assert!(matches!(
+        uploads.status(&status.id).await,
+        Err(Error::NotFound(_))
+    ));
+
+    assert!(matches!(
+        uploads.status("../../etc/passwd").await,
+        Err(Error::NotFound(_))
+    ));
+
+    let status = uploads
+        .create(
+            NewUpload {
+                filename: "corrupted.mp4".to_string(),
+                size: 3,
+                sha256: Some(format!("{:x}", Sha256::digest(b"abc"))),
+            },
+            user,
+            reserve(3).await.unwrap(),
+        )
+        .await
+        .unwrap();
+    uploads.append(&status.id, 0, b"abd").await.unwrap();
+    assert!(matches!(
+        uploads.finish(&status.id, &dir).await,
+        Err(Error::ChecksumMismatch)
+    ));
+
+    // The whole file is checked, not just the first chunk.
+    let script = b"#!/bin/sh\nrm -rf ~\n";
+    let status = uploads
+        .create(
+            NewUpload {
+                filename: "video.mp4".to_string(),
+                size: script.len() as u64,
+                sha256: None,
+            },
+            user,
+            reserve(script.len() as u64).await.unwrap(),
+        )
+        .await
+        .unwrap();
+    uploads.append(&status.id, 0, &script[..2]).await.unwrap();
+    uploads.append(&status.id, 2, &script[2..]).await.unwrap();
+    assert!(matches!(
+        uploads.finish(&status.id, &dir).await,
+        Err(Error::Upload(upload::Error::UnsupportedType(_)))
+    ));
+    assert!(!dir
+        .join(PARTIAL_DIR)
+        .join(format!("{}.part", status.id))
+        .exists());
+
+    // Only the finished upload is left counted against the quota.
+    assert!(matches!(
+        reserve(1024).await,
+        Err(upload::Error::UserQuotaExceeded { remaining }) if remaining == 1024 - data.len() as u64
+    ));
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc::UnboundedReceiver, RwLock};
+
+use crate::mpv::{
+    event::{EndFile, LogMessage, StartFile},
+    response::PlaylistEntry,
+    Client,
+};
+
+/// How long an error is shown after the entry failed.
+const KEEP_ERRORS: Duration = Duration::from_secs(10 * 60);
+
+/// How long the note about the error stays on screen.
+const OSD_DURATION_MS: u64 = 5000;
+
+/// Log messages kept for the entry that's loading, in case it fails.
+const MAX_LOG_LINES: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryError {
+    pub playlist_entry_id: i64,
+    pub url: Option<String>,
+    pub title: Option<String>,
+
+    /// What mpv said went wrong, like `loading failed` or `unrecognized file format`.
+    pub file_error: Option<String>,
+
+    /// The errors logged by yt-dlp while trying to open the entry, which usually say more.
+    pub log: Vec<String>,
+
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    #[serde(skip)]
+    failed_at: Instant,
+}
+
+impl EntryError {
+    /// The most useful explanation we have of why the entry failed.
+    pub fn message(&self) -> &str {
+        self.log
+            .last()
+            .or(self.file_error.as_ref())
+            .map_or("unknown error", |message| message.as_str())
+    }
+}
+
+/// Why the entries that mpv couldn't play failed, so guests can see it in the web UI.
+#[derive(Debug, Clone)]
+pub struct EntryErrors {
+    ipc: Client,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    show_on_screen: bool,
+    errors: Arc<Mutex<HashMap<i64, EntryError>>>,
+}
+
+/// Keeps the last errors of yt-dlp, which explain why an entry failed.
+fn keep_error(log: &mut Vec<String>, message: LogMessage) {
+    if message.prefix == "ytdl_hook" && (message.level == "error" || message.level == "fatal") {
+        if log.len() == MAX_LOG_LINES {
+            log.remove(0);
+        }
+        log.push(message.text.trim().to_string());
+    }
+}
+
+impl EntryErrors {
+    pub fn new(
+        ipc: Client,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+        show_on_screen: bool,
+    ) -> Self {
+        EntryErrors {
+            ipc,
+            playlist,
+            show_on_screen,
+            errors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The errors from the last few minutes, most recent first.
+    pub fn list(&self) -> Vec<EntryError> {
+        let mut errors = self.errors.lock().unwrap();
+        errors.retain(|_, error| error.failed_at.elapsed() < KEEP_ERRORS);
+
+        let mut errors: Vec<_> = errors.values().cloned().collect();
+        errors.sort_by_key(|error| std::cmp::Reverse(error.failed_at));
+        errors
+    }
+
+    async fn record(&self, started: Option<&PlaylistEntry>, end_file: EndFile, log: Vec<String>) {
+        let error = EntryError {
+            playlist_entry_id: end_file.playlist_entry_id,
+            url: started.map(|entry| entry.filename.clone()),
+            title: started.and_then(|entry| entry.title.clone()),
+            file_error: end_file.file_error,
+            log,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            failed_at: Instant::now(),
+        };
+
+        log::warn!(
+            "failed to play {}: {}",
+            error.url.as_deref().unwrap_or("unknown entry"),
+            error.message()
+        );
+
+        let text = format!(
+            "Couldn't play {}: {}",
+            error
+                .title
+                .as_deref()
+                .or(error.url.as_deref())
+                .unwrap_or("entry"),
+            error.message()
+        );
+
+        self.errors
+            .lock()
+            .unwrap()
+            .insert(error.playlist_entry_id, error);
+
+        if self.show_on_screen {
+            if let Err(e) = self.ipc.show_text(&text, OSD_DURATION_MS).await {
+                log::warn!("failed to show the error on screen: {e}");
+            }
+        }
+    }
+
+    pub async fn run(
+        self,
+        mut start_file_rx: UnboundedReceiver<StartFile>,
+        mut end_file_rx: UnboundedReceiver<EndFile>,
+        mut log_message_rx: UnboundedReceiver<LogMessage>,
+    ) {
+        // The entry may be gone from the playlist by the time it fails, so remember it when it starts.
+        let mut started = None;
+        let mut log = vec![];
+
+        loop {
+            tokio::select! {
+                // The log messages of an entry must be in before its end-file is handled.
+                biased;
+
+                log_message = log_message_rx.recv() => match log_message {
+                    Some(log_message) => keep_error(&mut log, log_message),
+                    None => break,
+                },
+
+                start_file = start_file_rx.recv() => match start_file {
+                    Some(StartFile { playlist_entry_id }) => {
+                        started = self
+                            .playlist
+                            .read()
+                            .await
+                            .iter()
+                            .find(|entry| entry.id as i64 == playlist_entry_id)
+                            .cloned();
+                        log.clear();
+                    }
+                    None => break,
+                },
+
+                end_file = end_file_rx.recv() => match end_file {
+                    Some(end_file) if end_file.reason == "error" => {
+                        // They come through a different channel, so some may still be waiting.
+                        while let Ok(log_message) = log_message_rx.try_recv() {
+                            keep_error(&mut log, log_message);
+                        }
+
+                        let started = started
+                            .take()
+                            .filter(|entry| entry.id as i64 == end_file.playlist_entry_id);
+                        self.record(started.as_ref(), end_file, std::mem::take(&mut log)).await;
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_entry_errors() {
+    let (ipc, mut commands) = Client::fake();
+    let playlist = Arc::new(RwLock::new(vec![PlaylistEntry {
+        id: 3,
+        filename: "https://example.com/private".to_string(),
+        title: None,
+        current: true,
+        playing: true,
+    }]));
+
+    let errors = EntryErrors::new(ipc, playlist, true);
+
+    let (start_file_tx, start_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (end_file_tx, end_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (log_message_tx, log_message_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(
+        errors
+            .clone()
+            .run(start_file_rx, end_file_rx, log_message_rx),
+    );
+
+    start_file_tx
+        .send(StartFile {
+            playlist_entry_id: 3,
+        })
+        .unwrap();
+    tokio::task::yield_now().await;
+
+    for (prefix, level, text) in [
+        ("cplayer", "error", "unrelated\n"),
+        (
+            "ytdl_hook",
+            "error",
+            "ERROR: [generic] private: Private video\n",
+        ),
+    ] {
+        log_message_tx
+            .send(LogMessage {
+                prefix: prefix.to_string(),
+                level: level.to_string(),
+                text: text.to_string(),
+            })
+            .unwrap();
+    }
+    tokio::task::yield_now().await;
+
+    end_file_tx
+        .send(EndFile {
+            playlist_entry_id: 3,
+            reason: "error".to_string(),
+            file_error: Some("loading failed".to_string()),
+        })
+        .unwrap();
+
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!([
+            "show-text",
+            "Couldn't play https://example.com/private: ERROR: [generic] private: Private video",
+            "5000"
+        ])
+    );
+
+    let listed = errors.list();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].playlist_entry_id, 3);
+    assert_eq!(listed[0].file_error.as_deref(), Some("loading failed"));
+    assert_eq!(listed[0].log, ["ERROR: [generic] private: Private video"]);
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{announcements::Announcement, chat::ChatMessage};
+
+/// Events missed by a client that can't keep up are dropped.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Something that happened, sent to everyone listening on `/api/events`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    Chat(ChatMessage),
+    Announcement(Announcement),
+}
+
+impl Event {
+    /// Name of the server-sent event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Chat(_) => "chat",
+            Event::Announcement(_) => "announcement",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Events { tx }
+    }
+
+    pub fn send(&self, event: Event) {
+        // It's fine if nobody is listening.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
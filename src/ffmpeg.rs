@@ -0,0 +1,106 @@
+use std::{io, path::Path, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to run ffmpeg: {0}")]
+    Spawn(#[from] io::Error),
+
+    #[error("ffmpeg failed: {0}")]
+    Failed(String),
+
+    #[error("no loudness measurement in the ffmpeg output")]
+    MissingMeasurement,
+}
+
+// The fields we care about from the summary printed by the loudnorm filter.
+#[derive(Debug, Deserialize)]
+struct LoudnormSummary {
+    // ffmpeg prints numbers as strings
+    input_i: String,
+}
+
+/// Finds the integrated loudness in the JSON summary that loudnorm prints at the end of its output.
+fn parse_loudnorm(stderr: &str) -> Option<f64> {
+    let start = stderr.rfind('{')?;
+    let end = start + stderr[start..].find('}')?;
+
+    let summary: LoudnormSummary = serde_json::from_str(&stderr[start..=end]).ok()?;
+    summary
+        .input_i
+        .parse()
+        .ok()
+        .filter(|lufs: &f64| lufs.is_finite())
+}
+
+#[derive(Debug, Clone)]
+pub struct FfMpeg {
+    path: String,
+}
+
+impl FfMpeg {
+    pub fn new(path: String) -> Self {
+        FfMpeg { path }
+    }
+
+    /// Measures the integrated loudness of the audio of a file, in LUFS.
+    /// This decodes the whole file, so it can take a while.
+    pub async fn integrated_loudness(&self, file: &Path) -> Result<f64, Error> {
+        let output = Command::new(&self.path)
+            .args(["-hide_banner", "-nostats", "-i"])
+            .arg(file)
+            .args([
+                "-vn",
+                "-af",
+                "loudnorm=print_format=json",
+                "-f",
+                "null",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if !output.status.success() {
+            let message = stderr.lines().last().unwrap_or_default();
+            return Err(Error::Failed(message.trim().to_string()));
+        }
+
+        parse_loudnorm(&stderr).ok_or(Error::MissingMeasurement)
+    }
+}
+
+#[test]
+fn test_parse_loudnorm() {
+    let stderr = r#"Input #0, mp3, from 'song.mp3':
+  Duration: 00:03:12.04, start: 0.025057, bitrate: 320 kb/s
+[Parsed_loudnorm_0 @ 0x5581c0c0]
+{
+	"input_i" : "-9.87",
+	"input_tp" : "0.45",
+	"input_lra" : "5.30",
+	"input_thresh" : "-20.01",
+	"output_i" : "-24.41",
+	"output_tp" : "-2.00",
+	"output_lra" : "4.60",
+	"output_thresh" : "-34.65",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.41"
+}
+"#;
+
+    assert_eq!(parse_loudnorm(stderr), Some(-9.87));
+    assert_eq!(
+        parse_loudnorm(r#"{ "input_i" : "-inf", "output_i" : "-inf" }"#),
+        None
+    );
+    assert_eq!(
+        parse_loudnorm("Output file is empty, nothing was encoded"),
+        None
+    );
+}
@@ -0,0 +1,79 @@
+use std::{collections::HashMap, io, path::Path, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to run ffprobe: {0}")]
+    Spawn(#[from] io::Error),
+
+    #[error("ffprobe failed: {0}")]
+    Failed(String),
+
+    #[error("failed to decode ffprobe output: {0}")]
+    InvalidOutput(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+    pub duration: Option<f64>,
+
+    /// Container tags with lowercased keys, since their case depends on the format.
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawOutput {
+    format: Option<RawFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawFormat {
+    // ffprobe prints numbers as strings
+    duration: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FfProbe {
+    path: String,
+}
+
+impl FfProbe {
+    pub fn new(path: String) -> Self {
+        FfProbe { path }
+    }
+
+    pub async fn probe(&self, file: &Path) -> Result<Probe, Error> {
+        let output = Command::new(&self.path)
+            .args(["-v", "error", "-print_format", "json", "-show_format", "-i"])
+            .arg(file)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(Error::Failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        let output: RawOutput = serde_json::from_slice(&output.stdout)?;
+
+        let Some(format) = output.format else {
+            return Ok(Probe::default());
+        };
+
+        Ok(Probe {
+            duration: format.duration.and_then(|d| d.parse().ok()),
+            tags: format
+                .tags
+                .into_iter()
+                .map(|(k, v)| (k.to_lowercase(), v))
+                .collect(),
+        })
+    }
+}
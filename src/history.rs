@@ -0,0 +1,534 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc::UnboundedReceiver, RwLock},
+};
+
+use crate::{
+    metadata::Resolver,
+    mpv::{
+        event::{EndFile, StartFile},
+        response::PlaylistEntry,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndReason {
+    /// Played until the end.
+    Eof,
+
+    /// Someone skipped to another entry or stopped playback.
+    Skip,
+
+    Error,
+
+    /// mpv was closed while it was playing.
+    Quit,
+}
+
+impl EndReason {
+    fn from_mpv(reason: &str) -> Option<Self> {
+        match reason {
+            "eof" => Some(EndReason::Eof),
+            "stop" => Some(EndReason::Skip),
+            "error" => Some(EndReason::Error),
+            "quit" => Some(EndReason::Quit),
+            // Playlists that mpv expanded into other entries were never really played.
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EndReason::Eof => "eof",
+            EndReason::Skip => "skip",
+            EndReason::Error => "error",
+            EndReason::Quit => "quit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    /// When the entry started playing, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub title: Option<String>,
+    pub url: String,
+
+    /// Address of the user who queued the entry, which is only shown to admins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued_by: Option<String>,
+
+    /// Seconds the entry played for, not counting the time spent paused.
+    pub watched: f64,
+    pub end_reason: EndReason,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The history in CSV, without the addresses of who queued each entry.
+pub fn to_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = "timestamp,title,url,watched,end_reason\n".to_string();
+
+    for entry in entries {
+        csv.push_str(&format!(
+            "{},{},{},{:.1},{}\n",
+            entry.timestamp,
+            csv_field(entry.title.as_deref().unwrap_or_default()),
+            csv_field(&entry.url),
+            entry.watched,
+            entry.end_reason.as_str(),
+        ));
+    }
+
+    csv
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub plays: usize,
+    pub total_hours: f64,
+    pub top_queuers: Vec<Count>,
+    pub most_skipped: Vec<Count>,
+}
+
+fn top(counts: HashMap<String, usize>, n: usize) -> Vec<Count> {
+    let mut counts: Vec<_> = counts
+        .into_iter()
+        .map(|(name, count)| Count { name, count })
+        .collect();
+
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    counts.truncate(n);
+
+    counts
+}
+
+/// Computes the stats of a history, keeping the top `n` of each ranking.
+pub fn stats(entries: &[HistoryEntry], n: usize) -> Stats {
+    let mut queuers = HashMap::new();
+    let mut skipped = HashMap::new();
+
+    for entry in entries {
+        if let Some(queued_by) = &entry.queued_by {
+            *queuers.entry(queued_by.clone()).or_default() += 1;
+        }
+
+        if entry.end_reason == EndReason::Skip {
+            let name = entry.title.clone().unwrap_or_else(|| entry.url.clone());
+            *skipped.entry(name).or_default() += 1;
+        }
+    }
+
+    Stats {
+        plays: entries.len(),
+        total_hours: entries.iter().map(|entry| entry.watched).sum::<f64>() / 3600.0,
+        top_queuers: top(queuers, n),
+        most_skipped: top(skipped, n),
+    }
+}
+
+struct Playing {
+    timestamp: u64,
+    url: Option<String>,
+
+    /// Time played before the last pause.
+    watched: Duration,
+
+    /// When playback last resumed, or None while paused.
+    resumed: Option<Instant>,
+}
+
+impl Playing {
+    fn pause(&mut self) {
+        if let Some(resumed) = self.resumed.take() {
+            self.watched += resumed.elapsed();
+        }
+    }
+
+    fn watched(&self) -> Duration {
+        self.watched
+            + self
+                .resumed
+                .map_or(Duration::ZERO, |resumed| resumed.elapsed())
+    }
+}
+
+/// Log of everything that was played, stored as one JSON object per line.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    metadata: Resolver,
+
+    /// Playlist entry ID to the user who queued it.
+    queued_by: Arc<Mutex<HashMap<i64, IpAddr>>>,
+
+    /// URLs of the entries that were replaced by a local copy.
+    original_urls: Arc<Mutex<HashMap<i64, String>>>,
+}
+
+impl History {
+    pub fn new(path: &Path, playlist: Arc<RwLock<Vec<PlaylistEntry>>>, metadata: Resolver) -> Self {
+        History {
+            path: path.to_path_buf(),
+            playlist,
+            metadata,
+            queued_by: Arc::new(Mutex::new(HashMap::new())),
+            original_urls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Remembers who queued a playlist entry.
+    pub fn queued(&self, playlist_entry_id: i64, queued_by: Option<IpAddr>) {
+        if let Some(queued_by) = queued_by {
+            self.queued_by
+                .lock()
+                .unwrap()
+                .insert(playlist_entry_id, queued_by);
+        }
+    }
+
+    /// Keeps the queuer and the URL of an entry that was replaced by a downloaded copy.
+    /// The URL is kept for as long as the copy is in the playlist.
+    pub fn replaced(&self, old_id: i64, new_id: i64, url: &str) {
+        let mut queued_by = self.queued_by.lock().unwrap();
+        if let Some(addr) = queued_by.remove(&old_id) {
+            queued_by.insert(new_id, addr);
+        }
+
+        self.original_urls
+            .lock()
+            .unwrap()
+            .insert(new_id, url.to_string());
+    }
+
+    /// Forgets the entries that were removed from the playlist before they ended.
+    pub fn update(&self, playlist: &[PlaylistEntry]) {
+        let ids: HashSet<i64> = playlist.iter().map(|entry| entry.id as i64).collect();
+
+        // Entries are remembered as soon as they're loaded, which can be before they show up in
+        // the playlist, so the ones newer than everything in it are kept for now.
+        let newest = ids.iter().copied().max().unwrap_or(0);
+        let keep = |id: &i64| *id > newest || ids.contains(id);
+
+        self.queued_by.lock().unwrap().retain(|id, _| keep(id));
+        self.original_urls.lock().unwrap().retain(|id, _| keep(id));
+    }
+
+    /// URLs of the entries that were replaced by a local copy, by the ID of the copy.
+    pub fn original_urls(&self) -> HashMap<i64, String> {
+        self.original_urls.lock().unwrap().clone()
+    }
+
+    pub async fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let history = match tokio::fs::read_to_string(&self.path).await {
+            Ok(history) => history,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        Ok(history
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("skipping invalid history entry: {e}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    async fn url_of(&self, playlist_entry_id: i64) -> Option<String> {
+        if let Some(url) = self.original_urls.lock().unwrap().get(&playlist_entry_id) {
+            return Some(url.clone());
+        }
+
+        self.playlist
+            .read()
+            .await
+            .iter()
+            .find(|entry| entry.id as i64 == playlist_entry_id)
+            .map(|entry| entry.filename.clone())
+    }
+
+    async fn title_of(&self, playlist_entry_id: i64) -> Option<String> {
+        if let Some(title) = self
+            .metadata
+            .read()
+            .await
+            .get(&(playlist_entry_id as u64))
+            .and_then(|metadata| metadata.title.clone())
+        {
+            return Some(title);
+        }
+
+        self.playlist
+            .read()
+            .await
+            .iter()
+            .find(|entry| entry.id as i64 == playlist_entry_id)
+            .and_then(|entry| entry.title.clone())
+    }
+
+    async fn finish(&self, playing: Option<Playing>, end_file: EndFile) {
+        let id = end_file.playlist_entry_id;
+
+        // Who queued the entry is only needed for this, but the original URL stays around
+        // for as long as the entry is in the playlist so that it can be exported.
+        let queued_by = self.queued_by.lock().unwrap().remove(&id);
+        let original_url = self.original_urls.lock().unwrap().get(&id).cloned();
+
+        let (Some(playing), Some(end_reason)) = (playing, EndReason::from_mpv(&end_file.reason))
+        else {
+            return;
+        };
+
+        let watched = playing.watched();
+        let entry = HistoryEntry {
+            timestamp: playing.timestamp,
+            title: self.title_of(id).await,
+            url: match playing.url.or(original_url) {
+                Some(url) => url,
+                None => self.url_of(id).await.unwrap_or_default(),
+            },
+            queued_by: queued_by.map(|addr| addr.to_string()),
+            watched: watched.as_secs_f64(),
+            end_reason,
+        };
+
+        if let Err(e) = self.append(&entry).await {
+            log::warn!("failed to write to the history: {e}");
+        }
+    }
+
+    pub async fn run(
+        self,
+        mut start_file_rx: UnboundedReceiver<StartFile>,
+        mut end_file_rx: UnboundedReceiver<EndFile>,
+        mut pause_rx: UnboundedReceiver<serde_json::Value>,
+    ) {
+        let mut playing: HashMap<i64, Playing> = HashMap::new();
+        let mut paused = false;
+
+        loop {
+            tokio::select! {
+                start_file = start_file_rx.recv() => match start_file {
+                    Some(StartFile { playlist_entry_id }) => {
+                        playing.insert(playlist_entry_id, Playing {
+                            timestamp: SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .map_or(0, |d| d.as_secs()),
+                            url: self.url_of(playlist_entry_id).await,
+                            watched: Duration::ZERO,
+                            resumed: (!paused).then(Instant::now),
+                        });
+                    }
+                    None => break,
+                },
+
+                end_file = end_file_rx.recv() => match end_file {
+                    Some(end_file) => {
+                        let started = playing.remove(&end_file.playlist_entry_id);
+                        self.finish(started, end_file).await;
+                    }
+                    None => break,
+                },
+
+                pause = pause_rx.recv() => match pause {
+                    Some(pause) => {
+                        paused = pause.as_bool().unwrap_or(false);
+                        for playing in playing.values_mut() {
+                            if paused {
+                                playing.pause();
+                            } else if playing.resumed.is_none() {
+                                playing.resumed = Some(Instant::now());
+                            }
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+    }
+}
+
+#[test]
+fn test_history_stats_and_csv() {
+    let entry = |title: &str, queued_by: Option<&str>, watched: f64, end_reason| HistoryEntry {
+        timestamp: 1700000000,
+        title: Some(title.to_string()),
+        url: format!("https://example.com/{title}"),
+        queued_by: queued_by.map(str::to_string),
+        watched,
+        end_reason,
+    };
+
+    let entries = vec![
+        entry("song", Some("10.0.0.2"), 1800.0, EndReason::Eof),
+        entry(
+            "cringe, \"the video\"",
+            Some("10.0.0.3"),
+            10.0,
+            EndReason::Skip,
+        ),
+        entry(
+            "cringe, \"the video\"",
+            Some("10.0.0.2"),
+            5.0,
+            EndReason::Skip,
+        ),
+        entry("broken", None, 0.0, EndReason::Error),
+    ];
+
+    let stats = stats(&entries, 1);
+    assert_eq!(stats.plays, 4);
+    assert!((stats.total_hours - 0.5041).abs() < 0.001);
+    assert_eq!(
+        stats.top_queuers,
+        vec![Count {
+            name: "10.0.0.2".to_string(),
+            count: 2
+        }]
+    );
+    assert_eq!(stats.most_skipped[0].name, "cringe, \"the video\"");
+    assert_eq!(stats.most_skipped[0].count, 2);
+
+    let csv = to_csv(&entries);
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines[0], "timestamp,title,url,watched,end_reason");
+    assert_eq!(
+        lines[2],
+        "1700000000,\"cringe, \"\"the video\"\"\",\"https://example.com/cringe, \"\"the video\"\"\",10.0,skip"
+    );
+    assert_eq!(
+        lines[4],
+        "1700000000,broken,https://example.com/broken,0.0,error"
+    );
+}
+
+#[tokio::test]
+async fn test_history_skips_paused_time() {
+    let dir = crate::test_dir::TestDir::new();
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+
+    let playlist = Arc::new(RwLock::new(vec![PlaylistEntry {
+        id: 1,
+        filename: "https://example.com/song".to_string(),
+        title: Some("song".to_string()),
+        current: true,
+        playing: true,
+    }]));
+    let metadata = Resolver::start(
+        crate::ytdlp::YtDlp::new("yt-dlp".to_string()),
+        crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+        1,
+    );
+    let history = History::new(&dir.join("history.jsonl"), playlist, metadata);
+    history.queued(1, Some("10.0.0.2".parse().unwrap()));
+
+    let (start_file_tx, start_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (end_file_tx, end_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (pause_tx, pause_rx) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::spawn(history.clone().run(start_file_rx, end_file_rx, pause_rx));
+
+    start_file_tx
+        .send(StartFile {
+            playlist_entry_id: 1,
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    pause_tx.send(serde_json::json!(true)).unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    end_file_tx
+        .send(EndFile {
+            playlist_entry_id: 1,
+            reason: "stop".to_string(),
+            file_error: None,
+        })
+        .unwrap();
+    drop(end_file_tx);
+    task.await.unwrap();
+
+    let entries = history.entries().await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].queued_by.as_deref(), Some("10.0.0.2"));
+    assert!(entries[0].watched < 0.2, "{}", entries[0].watched);
+    assert!(history.queued_by.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_history_forgets_removed_entries() {
+    let entry = |id| PlaylistEntry {
+        id,
+        filename: format!("https://example.com/{id}"),
+        title: None,
+        current: false,
+        playing: false,
+    };
+    let history = History::new(
+        Path::new("/nonexistent/history.jsonl"),
+        Arc::new(RwLock::new(vec![])),
+        Resolver::start(
+            crate::ytdlp::YtDlp::new("yt-dlp".to_string()),
+            crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+            1,
+        ),
+    );
+    for id in [1, 2, 5] {
+        history.queued(id, Some("10.0.0.2".parse().unwrap()));
+    }
+    history.replaced(2, 4, "https://example.com/2");
+
+    let queued = || {
+        let mut ids: Vec<_> = history.queued_by.lock().unwrap().keys().copied().collect();
+        ids.sort();
+        ids
+    };
+
+    // 5 was just queued and isn't in the playlist yet.
+    history.update(&[entry(3), entry(4)]);
+    assert_eq!(queued(), [4, 5]);
+    assert!(history.original_urls.lock().unwrap().contains_key(&4));
+
+    history.update(&[entry(6)]);
+    assert!(queued().is_empty());
+    assert!(history.original_urls.lock().unwrap().is_empty());
+}
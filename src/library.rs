@@ -0,0 +1,313 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+
+/// Extensions of the files that will be listed and queued from the library.
+pub const MEDIA_EXTENSIONS: &[&str] = &[
+    "3gp", "aac", "alac", "ape", "avi", "flac", "flv", "m2ts", "m4a", "m4v", "mka", "mkv", "mov",
+    "mp3", "mp4", "mpeg", "mpg", "ogg", "ogm", "ogv", "opus", "ts", "wav", "webm", "wma", "wmv",
+    "wv",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no such library path: {0}")]
+    NotFound(String),
+
+    #[error("path is outside of the library: {0}")]
+    OutsideRoot(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Root {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirEntry {
+    pub name: String,
+
+    /// Path relative to the library, starting with the name of the root.
+    pub path: String,
+
+    pub is_dir: bool,
+}
+
+pub fn is_media_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| MEDIA_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// The local folders guests are allowed to browse.
+/// Paths in the library are written as `<root name>/<path relative to the root>`
+/// and are never allowed to resolve outside of their root.
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    roots: Arc<Vec<Root>>,
+}
+
+impl Library {
+    pub fn new(dirs: &[PathBuf]) -> io::Result<Self> {
+        let mut roots: Vec<Root> = vec![];
+
+        for dir in dirs {
+            let path = dir.canonicalize()?;
+
+            let base_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "library".to_string());
+
+            let mut name = base_name.clone();
+            let mut i = 2;
+            while roots.iter().any(|root| root.name == name) {
+                name = format!("{base_name}-{i}");
+                i += 1;
+            }
+
+            roots.push(Root { name, path });
+        }
+
+        Ok(Library {
+            roots: Arc::new(roots),
+        })
+    }
+
+    pub fn roots(&self) -> &[Root] {
+        &self.roots
+    }
+
+    /// Resolves a library path to an absolute path inside of its root.
+    pub fn resolve(&self, library_path: &str) -> Result<PathBuf, Error> {
+        let library_path = library_path.trim_matches('/');
+        let (root_name, relative) = library_path.split_once('/').unwrap_or((library_path, ""));
+
+        let root = self
+            .roots
+            .iter()
+            .find(|root| root.name == root_name)
+            .ok_or_else(|| Error::NotFound(library_path.to_string()))?;
+
+        let relative = Path::new(relative);
+
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::OutsideRoot(library_path.to_string()));
+        }
+
+        let path = root
+            .path
+            .join(relative)
+            .canonicalize()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => Error::NotFound(library_path.to_string()),
+                _ => Error::Io(e),
+            })?;
+
+        // Symlinks may still point outside of the root.
+        if !path.starts_with(&root.path) {
+            return Err(Error::OutsideRoot(library_path.to_string()));
+        }
+
+        Ok(path)
+    }
+
+    /// Turns an absolute path back into a library path, if it's inside one of the roots.
+    pub fn library_path(&self, path: &Path) -> Option<String> {
+        self.roots.iter().find_map(|root| {
+            let relative = path.strip_prefix(&root.path).ok()?;
+
+            let mut library_path = root.name.clone();
+            for component in relative.components() {
+                library_path.push('/');
+                library_path.push_str(component.as_os_str().to_str()?);
+            }
+
+            Some(library_path)
+        })
+    }
+
+    /// Lists the directories and media files in a directory, or the roots if the path is empty.
+    pub async fn list(&self, library_path: &str) -> Result<Vec<DirEntry>, Error> {
+        if library_path.trim_matches('/').is_empty() {
+            return Ok(self
+                .roots
+                .iter()
+                .map(|root| DirEntry {
+                    name: root.name.clone(),
+                    path: root.name.clone(),
+                    is_dir: true,
+                })
+                .collect());
+        }
+
+        let dir = self.resolve(library_path)?;
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+
+        let mut entries = vec![];
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let Ok(path) = tokio::fs::canonicalize(entry.path()).await else {
+                continue;
+            };
+
+            let Some(path_in_library) = self.library_path(&path) else {
+                continue;
+            };
+
+            let is_dir = path.is_dir();
+            if !is_dir && !is_media_file(&path) {
+                continue;
+            }
+
+            entries.push(DirEntry {
+                name,
+                path: path_in_library,
+                is_dir,
+            });
+        }
+
+        entries.sort_by(|a, b| {
+            b.is_dir
+                .cmp(&a.is_dir)
+                .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        });
+
+        Ok(entries)
+    }
+
+    /// Lists all the media files in a directory and its subdirectories, sorted by path.
+    pub async fn media_files(&self, library_path: &str) -> Result<Vec<PathBuf>, Error> {
+        let root = self.resolve(library_path)?;
+        let mut visited = HashSet::from([root.clone()]);
+        let mut dirs = vec![root];
+        let mut files = vec![];
+
+        while let Some(dir) = dirs.pop() {
+            let mut read_dir = tokio::fs::read_dir(&dir).await?;
+
+            while let Some(entry) = read_dir.next_entry().await? {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                let Ok(path) = tokio::fs::canonicalize(entry.path()).await else {
+                    continue;
+                };
+
+                // Skip symlinks to outside the library.
+                if self.library_path(&path).is_none() {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    // Symlinks can lead back to a directory we've already been through.
+                    if visited.insert(path.clone()) {
+                        dirs.push(path);
+                    }
+                } else if is_media_file(&path) {
+                    files.push(path);
+                }
+            }
+        }
+
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_library_stays_inside_root() {
+    let tmp = crate::test_dir::TestDir::new();
+    let music = tmp.join("music");
+    std::fs::create_dir_all(music.join("album")).unwrap();
+    std::fs::create_dir_all(tmp.join("secret")).unwrap();
+    std::fs::write(music.join("album/01 song.flac"), b"").unwrap();
+    std::fs::write(music.join("album/cover.jpg"), b"").unwrap();
+    std::fs::write(music.join("02 song.MP3"), b"").unwrap();
+    std::fs::write(tmp.join("secret/passwords.mp3"), b"").unwrap();
+    std::os::unix::fs::symlink(tmp.join("secret"), music.join("escape")).unwrap();
+
+    let library = Library::new(std::slice::from_ref(&music)).unwrap();
+    let music = music.canonicalize().unwrap();
+
+    assert_eq!(
+        library.resolve("music/album/01 song.flac").unwrap(),
+        music.join("album/01 song.flac")
+    );
+    assert_eq!(library.resolve("music").unwrap(), music);
+
+    for path in [
+        "music/..",
+        "music/../secret/passwords.mp3",
+        "music/album/../../secret",
+        "music/escape/passwords.mp3",
+        "music//etc/passwd",
+    ] {
+        assert!(
+            matches!(library.resolve(path), Err(Error::OutsideRoot(_))),
+            "{path} should be rejected"
+        );
+    }
+
+    assert!(matches!(
+        library.resolve("secret/passwords.mp3"),
+        Err(Error::NotFound(_))
+    ));
+
+    let entries = library.list("music").await.unwrap();
+    assert_eq!(
+        entries.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+        ["music/album", "music/02 song.MP3"]
+    );
+
+    assert_eq!(
+        library.media_files("music").await.unwrap(),
+        [music.join("02 song.MP3"), music.join("album/01 song.flac")]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_library_symlink_cycle() {
+    let tmp = crate::test_dir::TestDir::new();
+    let music = tmp.join("music");
+    std::fs::create_dir_all(music.join("a")).unwrap();
+    std::fs::create_dir_all(music.join("b")).unwrap();
+    std::fs::write(music.join("a/song.mp3"), b"").unwrap();
+    std::fs::write(music.join("b/song.mp3"), b"").unwrap();
+    std::os::unix::fs::symlink(music.join("b"), music.join("a/to-b")).unwrap();
+    std::os::unix::fs::symlink(music.join("a"), music.join("b/to-a")).unwrap();
+    std::os::unix::fs::symlink(&music, music.join("a/up")).unwrap();
+
+    let library = Library::new(std::slice::from_ref(&music)).unwrap();
+    let music = music.canonicalize().unwrap();
+
+    let files = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        library.media_files("music"),
+    )
+    .await
+    .expect("the walk should end")
+    .unwrap();
+    assert_eq!(files, [music.join("a/song.mp3"), music.join("b/song.mp3")]);
+}
@@ -0,0 +1,538 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use notify::{RecursiveMode, Watcher};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::{
+    ffprobe::FfProbe,
+    library::{self, Library},
+};
+
+/// How long to wait for a file to stop changing before indexing it again.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// How many files of a directory are checked against the index at the same time.
+const SCAN_CONCURRENCY: usize = 32;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    library_path TEXT NOT NULL,
+    mtime INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    title TEXT,
+    artist TEXT,
+    album TEXT,
+    duration REAL
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS files_fts USING fts5(
+    title, artist, album, library_path,
+    content = 'files', content_rowid = 'id'
+);
+
+CREATE TRIGGER IF NOT EXISTS files_ai AFTER INSERT ON files BEGIN
+    INSERT INTO files_fts (rowid, title, artist, album, library_path)
+    VALUES (new.id, new.title, new.artist, new.album, new.library_path);
+END;
+
+CREATE TRIGGER IF NOT EXISTS files_ad AFTER DELETE ON files BEGIN
+    INSERT INTO files_fts (files_fts, rowid, title, artist, album, library_path)
+    VALUES ('delete', old.id, old.title, old.artist, old.album, old.library_path);
+END;
+
+CREATE TRIGGER IF NOT EXISTS files_au AFTER UPDATE ON files BEGIN
+    INSERT INTO files_fts (files_fts, rowid, title, artist, album, library_path)
+    VALUES ('delete', old.id, old.title, old.artist, old.album, old.library_path);
+    INSERT INTO files_fts (rowid, title, artist, album, library_path)
+    VALUES (new.id, new.title, new.artist, new.album, new.library_path);
+END;
+"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Notify(#[from] notify::Error),
+
+    #[error(transparent)]
+    Library(#[from] library::Error),
+
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    /// Library path that can be passed to the library enqueue endpoint.
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug)]
+struct IndexedFile {
+    path: String,
+    library_path: String,
+    mtime: i64,
+    size: i64,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    duration: Option<f64>,
+}
+
+/// Turns free text into an FTS5 query matching every word as a prefix,
+/// so that user input can't be interpreted as query syntax.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Full-text index of the media files in the library, kept in an SQLite database.
+#[derive(Clone)]
+pub struct LibraryIndex {
+    conn: Arc<Mutex<Connection>>,
+    library: Library,
+    ffprobe: FfProbe,
+    probes: Arc<Semaphore>,
+}
+
+impl std::fmt::Debug for LibraryIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LibraryIndex")
+            .field("library", &self.library)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LibraryIndex {
+    pub fn open(
+        db_path: &Path,
+        library: Library,
+        ffprobe: FfProbe,
+        max_jobs: usize,
+    ) -> Result<Self, Error> {
+        let conn = Connection::open(db_path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(LibraryIndex {
+            conn: Arc::new(Mutex::new(conn)),
+            library,
+            ffprobe,
+            probes: Arc::new(Semaphore::new(max_jobs.max(1))),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await??;
+        Ok(result)
+    }
+
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>, Error> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT f.library_path, f.title, f.artist, f.album, f.duration
+                FROM files_fts JOIN files f ON f.id = files_fts.rowid
+                WHERE files_fts MATCH ?1
+                ORDER BY bm25(files_fts, 10.0, 5.0, 2.0, 1.0)
+                LIMIT ?2",
+            )?;
+
+            let results = stmt
+                .query_map(params![query, limit as i64], |row| {
+                    Ok(SearchResult {
+                        path: row.get(0)?,
+                        title: row.get(1)?,
+                        artist: row.get(2)?,
+                        album: row.get(3)?,
+                        duration: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+
+            Ok(results)
+        })
+        .await
+    }
+
+    /// Indexes a single file, skipping it if it didn't change since the last time.
+    async fn index_file(&self, path: &Path) -> Result<(), Error> {
+        let Some(library_path) = self.library.library_path(path) else {
+            return Ok(());
+        };
+
+        let Ok(meta) = tokio::fs::metadata(path).await else {
+            return Ok(());
+        };
+
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64);
+        let size = meta.len() as i64;
+        let path_str = path.to_string_lossy().to_string();
+
+        let unchanged = self
+            .with_conn({
+                let path_str = path_str.clone();
+                move |conn| {
+                    conn.query_row(
+                        "SELECT 1 FROM files WHERE path = ?1 AND mtime = ?2 AND size = ?3",
+                        params![path_str, mtime, size],
+                        |_| Ok(()),
+                    )
+                    .optional()
+                }
+            })
+            .await?
+            .is_some();
+
+        if unchanged {
+            return Ok(());
+        }
+
+        let probe = {
+            let _permit = self.probes.acquire().await;
+            match self.ffprobe.probe(path).await {
+                Ok(probe) => probe,
+                Err(e) => {
+                    log::warn!("failed to probe {path_str}: {e}");
+                    Default::default()
+                }
+            }
+        };
+
+        let mut tags = probe.tags;
+        let file = IndexedFile {
+            path: path_str,
+            library_path,
+            mtime,
+            size,
+            title: tags.remove("title"),
+            artist: tags
+                .remove("artist")
+                .or_else(|| tags.remove("album_artist")),
+            album: tags.remove("album"),
+            duration: probe.duration,
+        };
+
+        log::debug!("indexing {file:?}");
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO files (path, library_path, mtime, size, title, artist, album, duration)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (path) DO UPDATE SET
+                    library_path = excluded.library_path, mtime = excluded.mtime,
+                    size = excluded.size, title = excluded.title, artist = excluded.artist,
+                    album = excluded.album, duration = excluded.duration",
+                params![
+                    file.path,
+                    file.library_path,
+                    file.mtime,
+                    file.size,
+                    file.title,
+                    file.artist,
+                    file.album,
+                    file.duration
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes a file, or everything under a directory, from the index.
+    async fn remove_path(&self, path: &Path) -> Result<(), Error> {
+        let path = path.to_string_lossy().to_string();
+        let prefix = format!("{}{}", path, std::path::MAIN_SEPARATOR);
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM files WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+                params![path, prefix],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Indexes all the media files under a directory and forgets those that disappeared.
+    async fn scan_dir(&self, dir: &Path) -> Result<(), Error> {
+        let Some(library_path) = self.library.library_path(dir) else {
+            return Ok(());
+        };
+
+        let files = self.library.media_files(&library_path).await?;
+
+        futures::stream::iter(&files)
+            .for_each_concurrent(SCAN_CONCURRENCY, |file| async move {
+                if let Err(e) = self.index_file(file).await {
+                    log::warn!("failed to index {}: {e}", file.to_string_lossy());
+                }
+            })
+            .await;
+
+        let seen: HashSet<String> = files
+            .iter()
+            .map(|file| file.to_string_lossy().to_string())
+            .collect();
+        let prefix = format!("{}{}", dir.to_string_lossy(), std::path::MAIN_SEPARATOR);
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut select =
+                    tx.prepare("SELECT path FROM files WHERE substr(path, 1, length(?1)) = ?1")?;
+                let stale: Vec<String> = select
+                    .query_map([&prefix], |row| row.get(0))?
+                    .filter_map(Result::ok)
+                    .filter(|path: &String| !seen.contains(path))
+                    .collect();
+
+                let mut delete = tx.prepare("DELETE FROM files WHERE path = ?1")?;
+                for path in stale {
+                    delete.execute([path])?;
+                }
+            }
+            tx.commit()
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the files of library roots that were removed from the configuration.
+    async fn remove_other_roots(&self) -> Result<(), Error> {
+        let prefixes: Vec<String> = self
+            .library
+            .roots()
+            .iter()
+            .map(|root| {
+                format!(
+                    "{}{}",
+                    root.path.to_string_lossy(),
+                    std::path::MAIN_SEPARATOR
+                )
+            })
+            .collect();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let removed = {
+                let mut select = tx.prepare("SELECT path FROM files")?;
+                let stale: Vec<String> = select
+                    .query_map([], |row| row.get(0))?
+                    .filter_map(Result::ok)
+                    .filter(|path: &String| !prefixes.iter().any(|p| path.starts_with(p)))
+                    .collect();
+
+                let mut delete = tx.prepare("DELETE FROM files WHERE path = ?1")?;
+                for path in &stale {
+                    delete.execute([path])?;
+                }
+                stale.len()
+            };
+            tx.commit()?;
+
+            if removed > 0 {
+                log::info!("removed {removed} files of old library roots from the index");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn handle_change(&self, path: &Path) {
+        let result = if path.is_dir() {
+            self.scan_dir(path).await
+        } else if path.is_file() && library::is_media_file(path) {
+            self.index_file(path).await
+        } else if !path.exists() {
+            self.remove_path(path).await
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = result {
+            log::warn!("failed to update index for {}: {e}", path.to_string_lossy());
+        }
+    }
+
+    /// Scans the library roots and keeps the index up to date with the changes on disk.
+    pub async fn run(self) -> Result<(), Error> {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => {
+                    for path in event.paths {
+                        let _ = events_tx.send(path);
+                    }
+                }
+                Err(e) => log::warn!("library watcher error: {e}"),
+            })?;
+
+        for root in self.library.roots() {
+            watcher.watch(&root.path, RecursiveMode::Recursive)?;
+        }
+
+        if let Err(e) = self.remove_other_roots().await {
+            log::warn!("failed to clean up the library index: {e}");
+        }
+
+        for root in self.library.roots() {
+            log::info!("indexing library {}", root.path.to_string_lossy());
+            if let Err(e) = self.scan_dir(&root.path).await {
+                log::warn!("failed to index {}: {e}", root.path.to_string_lossy());
+            }
+        }
+
+        log::info!("library indexed");
+
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+        let mut deadline = tokio::time::Instant::now();
+
+        loop {
+            tokio::select! {
+                path = events_rx.recv() => match path {
+                    Some(path) => {
+                        // Handle the changes a while after the first one, even if more keep coming.
+                        if changed.is_empty() {
+                            deadline = tokio::time::Instant::now() + WATCH_DEBOUNCE;
+                        }
+                        changed.insert(path);
+                    }
+                    None => break,
+                },
+
+                _ = tokio::time::sleep_until(deadline), if !changed.is_empty() => {
+                    for path in changed.drain() {
+                        self.handle_change(&path).await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_library_index_search() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = crate::test_dir::TestDir::new();
+    let music = tmp.join("music");
+    std::fs::create_dir_all(music.join("rick")).unwrap();
+    std::fs::write(music.join("rick/track1.mp3"), b"1").unwrap();
+    std::fs::write(music.join("rick/never.mp3"), b"2").unwrap();
+    std::fs::write(music.join("other.flac"), b"3").unwrap();
+    std::fs::create_dir_all(music.join("covers")).unwrap();
+    std::os::unix::fs::symlink(music.join("covers"), music.join("rick/covers")).unwrap();
+    std::os::unix::fs::symlink(music.join("rick"), music.join("covers/rick")).unwrap();
+
+    let ffprobe = tmp.join("ffprobe");
+    std::fs::write(
+        &ffprobe,
+        r#"#!/bin/sh
+case "$7" in
+    */track1.mp3) echo '{"format":{"duration":"213.1","tags":{"TITLE":"Never Gonna Give You Up","ARTIST":"Rick Astley"}}}' ;;
+    */other.flac) echo '{"format":{"duration":"10.0","tags":{"title":"Something Else","artist":"Never"}}}' ;;
+    *) echo '{"format":{}}' ;;
+esac
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&ffprobe, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let library = Library::new(std::slice::from_ref(&music)).unwrap();
+    let music = music.canonicalize().unwrap();
+    let index = LibraryIndex::open(
+        &tmp.join("library.sqlite"),
+        library,
+        FfProbe::new(ffprobe.to_string_lossy().to_string()),
+        2,
+    )
+    .unwrap();
+
+    // The symlinks between rick and covers mustn't keep the scan going forever.
+    tokio::time::timeout(std::time::Duration::from_secs(5), index.scan_dir(&music))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Title matches rank above artist matches, which rank above path matches.
+    let results = index.search("never", 10).await.unwrap();
+    assert_eq!(
+        results.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+        [
+            "music/rick/track1.mp3",
+            "music/other.flac",
+            "music/rick/never.mp3"
+        ]
+    );
+    assert_eq!(results[0].artist.as_deref(), Some("Rick Astley"));
+    assert_eq!(results[0].duration, Some(213.1));
+
+    let results = index.search("astl giv", 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+
+    assert!(index.search("\"unbalanced AND (", 10).await.is_ok());
+
+    std::fs::remove_file(music.join("rick/never.mp3")).unwrap();
+    index.scan_dir(&music).await.unwrap();
+    assert_eq!(index.search("never", 10).await.unwrap().len(), 2);
+
+    index.remove_path(&music.join("rick")).await.unwrap();
+    let results = index.search("never", 10).await.unwrap();
+    assert_eq!(
+        results.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+        ["music/other.flac"]
+    );
+
+    index
+        .with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO files (path, library_path, mtime, size, title)
+                VALUES ('/old/root/song.mp3', 'old/song.mp3', 0, 0, 'Never Again')",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+    assert_eq!(index.search("never", 10).await.unwrap().len(), 2);
+    index.remove_other_roots().await.unwrap();
+    assert_eq!(index.search("never", 10).await.unwrap().len(), 1);
+}
@@ -0,0 +1,436 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{mpsc::UnboundedReceiver, Notify, RwLock},
+};
+
+use crate::{
+    ffmpeg::FfMpeg,
+    metadata::is_url,
+    mpv::{event::StartFile, response::PlaylistEntry, Client, Error as IpcError},
+};
+
+/// Label of the audio filter we manage, so that filters passed to mpv by hand are left alone.
+const FILTER_LABEL: &str = "@kameloso-normalize";
+
+/// How often the playlist is checked for files to measure.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// mpv doesn't allow raising the volume more than this by default.
+const MAX_GAIN: f64 = 12.0;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown normalization mode {0}, use off, dynaudnorm or loudnorm")]
+    UnknownMode(String),
+
+    #[error("the target loudness must be between -40 and -5 LUFS")]
+    InvalidTarget,
+
+    #[error(transparent)]
+    Ipc(#[from] IpcError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    Off,
+
+    /// Evens out the volume within each entry as it plays.
+    Dynaudnorm,
+
+    /// Brings each entry to the target loudness as it plays.
+    Loudnorm,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Mode::Off),
+            "dynaudnorm" => Ok(Mode::Dynaudnorm),
+            "loudnorm" => Ok(Mode::Loudnorm),
+            _ => Err(Error::UnknownMode(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Settings {
+    pub mode: Mode,
+
+    /// Measure the loudness of local files with ffmpeg and adjust the volume of each one.
+    pub measure: bool,
+
+    /// Target integrated loudness in LUFS.
+    pub target: f64,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(-40.0..=-5.0).contains(&self.target) {
+            return Err(Error::InvalidTarget);
+        }
+
+        Ok(())
+    }
+
+    /// Whether local files are measured and their volume adjusted. The filters already even out
+    /// the volume as things play, so the adjustment would only be applied on top of them.
+    fn adjusts_volume(&self) -> bool {
+        self.measure && self.mode == Mode::Off
+    }
+
+    /// The value for mpv's `af add`, or None if no filter should be applied.
+    fn filter(&self) -> Option<String> {
+        let filter = match self.mode {
+            Mode::Off => return None,
+            Mode::Dynaudnorm => "dynaudnorm=f=150:g=15".to_string(),
+            Mode::Loudnorm => format!("loudnorm=I={}:TP=-1.5:LRA=11", self.target),
+        };
+
+        Some(format!("{FILTER_LABEL}:lavfi=[{filter}]"))
+    }
+}
+
+/// Keeps the volume even across the queue, with an audio filter applied to everything that plays
+/// and a volume adjustment for each local file based on its measured loudness.
+#[derive(Debug, Clone)]
+pub struct Loudness {
+    ipc: Client,
+    ffmpeg: FfMpeg,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    settings: Arc<Mutex<Settings>>,
+
+    /// Integrated loudness of the files that were measured, by filename.
+    measured: Arc<Mutex<HashMap<String, f64>>>,
+    changed: Arc<Notify>,
+}
+
+impl Loudness {
+    pub fn new(
+        ipc: Client,
+        ffmpeg: FfMpeg,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+        settings: Settings,
+    ) -> Self {
+        Loudness {
+            ipc,
+            ffmpeg,
+            playlist,
+            settings: Arc::new(Mutex::new(settings)),
+            measured: Arc::new(Mutex::new(HashMap::new())),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        *self.settings.lock().unwrap()
+    }
+
+    async fn apply_filter(&self, settings: &Settings) -> Result<(), Error> {
+        // Fails when the filter isn't there, which is fine.
+        if let Err(e) = self.ipc.af("remove", FILTER_LABEL).await {
+            log::debug!("failed to remove the normalization filter: {e}");
+        }
+
+        if let Some(filter) = settings.filter() {
+            self.ipc.af("add", &filter).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The volume adjustment in dB for a file, if its loudness is known.
+    fn gain(&self, filename: &str) -> Option<f64> {
+        let settings = self.settings();
+        if !settings.adjusts_volume() {
+            return None;
+        }
+
+        let lufs = *self.measured.lock().unwrap().get(filename)?;
+        Some((settings.target - lufs).clamp(-MAX_GAIN, MAX_GAIN))
+    }
+
+    async fn apply_gain(&self, filename: &str) {
+        let gain = self.gain(filename).unwrap_or(0.0);
+
+        if let Err(e) = self.ipc.set_volume_gain(gain).await {
+            log::warn!("failed to set the volume of {filename}: {e}");
+        }
+    }
+
+    pub async fn set(&self, settings: Settings) -> Result<(), Error> {
+        settings.validate()?;
+        self.apply_filter(&settings).await?;
+        *self.settings.lock().unwrap() = settings;
+        self.changed.notify_one();
+
+        let playing = self
+            .playlist
+            .read()
+            .await
+            .iter()
+            .find(|entry| entry.playing)
+            .map(|entry| entry.filename.clone());
+        if let Some(filename) = playing {
+            self.apply_gain(&filename).await;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the loudness of the files that aren't in the playlist anymore.
+    pub fn update(&self, playlist: &[PlaylistEntry]) {
+        self.measured
+            .lock()
+            .unwrap()
+            .retain(|filename, _| playlist.iter().any(|entry| entry.filename == *filename));
+    }
+
+    /// Measures the playing and upcoming local files that weren't measured yet.
+    async fn measure_upcoming(&self, failed: &mut HashSet<String>) {
+        loop {
+            if !self.settings().adjusts_volume() {
+                return;
+            }
+
+            // The files are checked once the locks are released, so that a slow disk holds nothing up.
+            let candidates: Vec<_> = {
+                let playlist = self.playlist.read().await;
+                let position = playlist.iter().position(|entry| entry.playing).unwrap_or(0);
+                let measured = self.measured.lock().unwrap();
+
+                playlist[position..]
+                    .iter()
+                    .filter(|entry| {
+                        !is_url(&entry.filename)
+                            && !measured.contains_key(&entry.filename)
+                            && !failed.contains(&entry.filename)
+                    })
+                    .map(|entry| (entry.filename.clone(), entry.playing))
+                    .collect()
+            };
+
+            let mut next = None;
+            for (filename, playing) in candidates {
+                if fs::metadata(&filename)
+                    .await
+                    .is_ok_and(|meta| meta.is_file())
+                {
+                    next = Some((filename, playing));
+                    break;
+                }
+            }
+            let Some((filename, playing)) = next else {
+                return;
+            };
+
+            match self.ffmpeg.integrated_loudness(Path::new(&filename)).await {
+                Ok(lufs) => {
+                    log::info!("{filename} is at {lufs} LUFS");
+                    self.measured.lock().unwrap().insert(filename.clone(), lufs);
+
+                    if playing {
+                        self.apply_gain(&filename).await;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("failed to measure the loudness of {filename}: {e}");
+                    failed.insert(filename);
+                }
+            }
+        }
+    }
+
+    pub async fn run(self, mut start_file_rx: UnboundedReceiver<StartFile>) {
+        if let Err(e) = self.apply_filter(&self.settings()).await {
+            log::warn!("failed to apply the normalization filter: {e}");
+        }
+
+        tokio::spawn({
+            let loudness = self.clone();
+            async move {
+                let mut failed = HashSet::new();
+                let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = loudness.changed.notified() => {}
+                    }
+                    loudness.measure_upcoming(&mut failed).await;
+                }
+            }
+        });
+
+        while let Some(StartFile { playlist_entry_id }) = start_file_rx.recv().await {
+            if !self.settings().measure {
+                continue;
+            }
+
+            let filename = match self.ipc.get_playlist().await {
+                Ok(playlist) => playlist
+                    .into_iter()
+                    .find(|entry| entry.id as i64 == playlist_entry_id)
+                    .map(|entry| entry.filename),
+                Err(e) => {
+                    log::warn!("failed to get the playlist: {e}");
+                    continue;
+                }
+            };
+
+            // Entries that weren't measured play at the normal volume.
+            self.apply_gain(filename.as_deref().unwrap_or_default())
+                .await;
+        }
+    }
+}
+
+#[test]
+fn test_normalization_filter() {
+    let settings = |mode| Settings {
+        mode,
+        measure: false,
+        target: -16.0,
+    };
+
+    assert_eq!(settings(Mode::Off).filter(), None);
+    assert_eq!(
+        settings(Mode::Dynaudnorm).filter().unwrap(),
+        "@kameloso-normalize:lavfi=[dynaudnorm=f=150:g=15]"
+    );
+    assert_eq!(
+        settings(Mode::Loudnorm).filter().unwrap(),
+        "@kameloso-normalize:lavfi=[loudnorm=I=-16:TP=-1.5:LRA=11]"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_loudness_stub() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = crate::test_dir::TestDir::new();
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("ffmpeg");
+
+    std::fs::write(
+        &script,
+        r#"#!/bin/sh
+case "$4" in
+    */quiet.mp3) echo '{ "input_i" : "-30.5" }' >&2 ;;
+    */loud.mp3) echo '{ "input_i" : "-8.0" }' >&2 ;;
+    *) echo "Invalid data found when processing input" >&2; exit 1 ;;
+esac
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let entry = |id, filename: &str, playing| PlaylistEntry {
+        id,
+        filename: filename.to_string(),
+        title: None,
+        current: playing,
+        playing,
+    };
+    let file = |name: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, b"").unwrap();
+        path.to_string_lossy().to_string()
+    };
+    let (old, quiet, broken, loud) = (
+        file("old.mp3"),
+        file("quiet.mp3"),
+        file("broken.mp3"),
+        file("loud.mp3"),
+    );
+    let playlist = Arc::new(RwLock::new(vec![
+        entry(1, &old, false),
+        entry(2, &quiet, true),
+        entry(3, "https://example.com/video", false),
+        entry(4, &broken, false),
+        entry(5, "rtmp://example.com/live", false),
+        entry(6, &loud, false),
+    ]));
+
+    let (ipc, mut commands) = Client::fake();
+    let loudness = Loudness::new(
+        ipc,
+        FfMpeg::new(script.to_string_lossy().to_string()),
+        playlist,
+        Settings {
+            mode: Mode::Off,
+            measure: true,
+            target: -16.0,
+        },
+    );
+
+    let mut failed = HashSet::new();
+    loudness.measure_upcoming(&mut failed).await;
+
+    // Only the playing file has its volume adjusted right away.
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["set_property", "volume-gain", "12"])
+    );
+    assert!(commands.try_recv().is_err());
+    assert_eq!(loudness.gain(&loud), Some(-8.0));
+    assert_eq!(loudness.gain(&old), None);
+    assert_eq!(failed, HashSet::from([broken]));
+
+    assert!(matches!(
+        loudness
+            .set(Settings {
+                mode: Mode::Loudnorm,
+                measure: false,
+                target: 0.0,
+            })
+            .await,
+        Err(Error::InvalidTarget)
+    ));
+
+    loudness
+        .set(Settings {
+            mode: Mode::Dynaudnorm,
+            measure: true,
+            target: -16.0,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["af", "remove", "@kameloso-normalize"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!([
+            "af",
+            "add",
+            "@kameloso-normalize:lavfi=[dynaudnorm=f=150:g=15]"
+        ])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["set_property", "volume-gain", "0"])
+    );
+    // The filter already evens out the volume, so the measurements aren't applied on top of it.
+    assert_eq!(loudness.gain(&loud), None);
+
+    // The measurements are forgotten along with the entries.
+    loudness.update(&[entry(2, &quiet, true)]);
+    assert_eq!(
+        loudness.measured.lock().unwrap().keys().collect::<Vec<_>>(),
+        [&quiet]
+    );
+}
@@ -1,9 +1,39 @@
+mod announcements;
+mod aria2;
+mod autoplay;
+mod chat;
+mod chunked_upload;
+mod entry_errors;
+mod events;
+mod ffmpeg;
+mod ffprobe;
+mod history;
 mod kopipe;
+mod library;
+mod library_index;
+mod loudness;
+mod mdns;
+mod metadata;
 pub mod mpv;
+mod mpv_log;
+mod osd;
+mod playlist_file;
+mod prefetch;
 mod qr;
+mod retention;
+mod saved_queues;
+mod scheduler;
+mod search;
 mod server_endpoints;
 mod server_hyper;
 mod server_state;
+mod sleep_timer;
+mod subtitles;
+#[cfg(test)]
+mod test_dir;
+mod upload;
+mod upload_hashes;
+mod ytdlp;
 
 use clap::Parser;
 use std::{
@@ -18,6 +48,8 @@ use tokio::{
 
 use crate::mpv::response::PlaylistEntry;
 
+const MIB: u64 = 1024 * 1024;
+
 #[derive(Debug, Parser)]
 #[command(version)]
 struct CliOptions {
@@ -25,6 +57,125 @@ struct CliOptions {
     #[arg(long, default_value = "mpv")]
     pub mpv_path: String,
 
+    /// Least severe mpv log messages to show and keep for `/api/logs`:
+    /// fatal, error, warn, info, status, v, debug or trace.
+    #[arg(
+        long,
+        default_value = "info",
+        value_parser = clap::builder::PossibleValuesParser::new(mpv_log::LEVELS),
+    )]
+    pub mpv_log_level: String,
+
+    /// Maximum size of an uploaded file, in MiB.
+    #[arg(long, default_value_t = 4096)]
+    pub max_upload_size: u64,
+
+    /// Maximum number of MiB each user can upload while kameloso is running.
+    #[arg(long)]
+    pub upload_quota_per_user: Option<u64>,
+
+    /// Maximum size of the upload directory, in MiB.
+    #[arg(long)]
+    pub upload_quota_total: Option<u64>,
+
+    /// Reject uploads that would leave less than this many MiB free on the disk.
+    #[arg(long, default_value_t = 1024)]
+    pub min_free_space: u64,
+
+    /// When to delete uploaded files: forever, played, hours=<N> to keep them for N hours,
+    /// or gb=<N> to delete the oldest ones when they take up more than N GB.
+    #[arg(long, default_value = "forever")]
+    pub upload_retention: retention::RetentionPolicy,
+
+    /// Token required by the admin endpoints, sent as `Authorization: Bearer <token>`.
+    /// When unset the admin endpoints are only reachable from localhost.
+    #[arg(long)]
+    pub admin_token: Option<String>,
+
+    /// Local folder that guests can browse and queue files from. Can be passed multiple times.
+    #[arg(long = "library", value_name = "DIR")]
+    pub library_dirs: Vec<PathBuf>,
+
+    /// Don't index the library folders for searching.
+    #[arg(long)]
+    pub no_library_index: bool,
+
+    /// Directory where kameloso keeps its databases and other persistent data.
+    #[arg(long, default_value = "data")]
+    pub data_dir: PathBuf,
+
+    /// Path to the yt-dlp binary.
+    #[arg(long, default_value = "yt-dlp")]
+    pub ytdlp_path: String,
+
+    /// Path to the ffprobe binary.
+    #[arg(long, default_value = "ffprobe")]
+    pub ffprobe_path: String,
+
+    /// Path to the ffmpeg binary, used to measure the loudness of local files.
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg_path: String,
+
+    /// Audio filter that evens out the volume of everything that plays: off, dynaudnorm or loudnorm.
+    #[arg(long, default_value = "off")]
+    pub normalize: loudness::Mode,
+
+    /// Measure the loudness of local files with ffmpeg and adjust the volume of each one, when no
+    /// normalization filter is applied.
+    #[arg(long)]
+    pub measure_loudness: bool,
+
+    /// Loudness in LUFS that loudnorm and the measured files are brought to.
+    #[arg(long, default_value_t = -16.0, allow_hyphen_values = true)]
+    pub loudness_target: f64,
+
+    /// Maximum number of yt-dlp or ffprobe processes resolving metadata at once.
+    #[arg(long, default_value_t = 2)]
+    pub metadata_jobs: usize,
+
+    /// Maximum number of entries queued when expanding a playlist or channel.
+    #[arg(long, default_value_t = 100)]
+    pub max_playlist_entries: usize,
+
+    /// Number of results returned by a search.
+    #[arg(long, default_value_t = 10)]
+    pub search_results: usize,
+
+    /// Download this many of the upcoming links in the background and play the local files
+    /// instead of streaming them. Set to 0 to disable.
+    #[arg(long, default_value_t = 0)]
+    pub prefetch: usize,
+
+    /// Maximum size of the prefetched files in MiB.
+    #[arg(long, default_value_t = 4096)]
+    pub prefetch_cache_size: u64,
+
+    /// Playlist file (.m3u8, .xspf or .json) to enqueue at startup. Can be passed multiple times.
+    #[arg(long = "import-playlist", value_name = "FILE")]
+    pub import_playlists: Vec<PathBuf>,
+
+    /// Save the playlist to this file (.m3u8, .xspf or .json) when mpv exits.
+    #[arg(long, value_name = "FILE")]
+    pub export_playlist: Option<PathBuf>,
+
+    /// Path to the aria2c binary, used to download magnet links and .torrent files.
+    #[arg(long, default_value = "aria2c")]
+    pub aria2c_path: String,
+
+    /// Directory where downloaded torrents are saved.
+    #[arg(long, default_value = "downloads")]
+    pub torrent_dir: PathBuf,
+
+    /// Play something when the queue runs out: history to replay random entries from the history,
+    /// library=<library path> to play random files from a folder of the library, or related
+    /// to play videos related to the last one on YouTube.
+    #[arg(long, value_name = "SOURCE")]
+    pub autoplay: Option<autoplay::Source>,
+
+    /// Don't start aria2c and reject magnet links and .torrent files.
+    #[arg(long)]
+    pub no_torrents: bool,
+
     /// Bind the HTTP server to this address.
     #[arg(long, default_value = "0.0.0.0:8080")]
     pub bind_address: SocketAddr,
@@ -37,6 +188,34 @@ struct CliOptions {
     #[arg(long, default_value = "uploads")]
     pub upload_dir: PathBuf,
 
+    /// Hostname to advertise on the local network via mDNS, without the .local suffix.
+    #[arg(long, default_value = "kameloso")]
+    pub mdns_hostname: String,
+
+    /// Maximum length of a chat message.
+    #[arg(long, default_value_t = 200)]
+    pub chat_max_length: usize,
+
+    /// Maximum number of chat messages and reactions each guest can send per minute.
+    #[arg(long, default_value_t = 10)]
+    pub chat_rate_limit: usize,
+
+    /// File with words to mask in chat messages, one per line.
+    #[arg(long, value_name = "FILE")]
+    pub chat_blocklist: Option<PathBuf>,
+
+    /// Show a note on screen when a link can't be played.
+    #[arg(long)]
+    pub osd_errors: bool,
+
+    /// Don't advertise the server on the local network via mDNS.
+    #[arg(long)]
+    pub no_mdns: bool,
+
+    /// Put the mDNS hostname in the QR code instead of the local IP address.
+    #[arg(long, conflicts_with = "no_mdns")]
+    pub qr_use_mdns_hostname: bool,
+
     /// Extra arguments to pass to mpv after --
     #[arg()]
     pub mpv_args: Vec<String>,
@@ -87,6 +266,7 @@ async fn main() {
     let opts: CliOptions = CliOptions::parse();
 
     let _ = fs::create_dir(&opts.upload_dir).await;
+    let _ = fs::create_dir_all(&opts.data_dir).await;
 
     let runtime_dir = if cfg!(unix) {
         get_runtime_dir_unix()
@@ -146,7 +326,15 @@ async fn main() {
 
     let local_ip =
         local_ip_address::local_ip().unwrap_or(std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-    let qr_code_address = format!("http://{}:{}", local_ip, opts.bind_address.port());
+    let qr_code_address = if opts.qr_use_mdns_hostname {
+        format!(
+            "http://{}:{}",
+            mdns::local_hostname(&opts.mdns_hostname),
+            opts.bind_address.port()
+        )
+    } else {
+        format!("http://{}:{}", local_ip, opts.bind_address.port())
+    };
 
     let qr_code_path = runtime_dir.join("qr-code.bgra");
     let magnification = 4;
@@ -166,15 +354,125 @@ async fn main() {
         .await
         .unwrap();
 
+    let library =
+        library::Library::new(&opts.library_dirs).expect("library dir cannot be accessed");
+
+    let ytdlp = ytdlp::YtDlp::new(opts.ytdlp_path);
+    let ffprobe = ffprobe::FfProbe::new(opts.ffprobe_path);
+
+    let metadata = metadata::Resolver::start(ytdlp.clone(), ffprobe.clone(), opts.metadata_jobs);
+
+    let library_index = if opts.library_dirs.is_empty() || opts.no_library_index {
+        None
+    } else {
+        let index = library_index::LibraryIndex::open(
+            &opts.data_dir.join("library.sqlite"),
+            library.clone(),
+            ffprobe,
+            opts.metadata_jobs,
+        )
+        .expect("failed to open the library index");
+
+        tokio::spawn({
+            let index = index.clone();
+            async move {
+                if let Err(e) = index.run().await {
+                    log::error!("library indexer stopped: {e}");
+                }
+            }
+        });
+
+        Some(index)
+    };
+
+    let search = search::Search::new(vec![
+        Box::new(search::YtDlpSearch::new(
+            "youtube",
+            ytdlp.clone(),
+            "ytsearch",
+            opts.search_results,
+        )),
+        Box::new(search::YtDlpSearch::new(
+            "soundcloud",
+            ytdlp.clone(),
+            "scsearch",
+            opts.search_results,
+        )),
+    ]);
+
     let playlist: Arc<RwLock<Vec<PlaylistEntry>>> = Arc::new(RwLock::new(vec![]));
+    let mut imported = vec![];
+    for path in &opts.import_playlists {
+        match playlist_file::import_file(path).await {
+            Ok(items) => {
+                imported.extend(items.iter().map(|item| item.url.clone()));
+                for item in items {
+                    if let Err(e) = mpv_ipc
+                        .load_file(&item.url, &mpv::LoadFileOptions::AppendPlay)
+                        .await
+                    {
+                        log::warn!("failed to enqueue {}: {e}", item.url);
+                    }
+                }
+            }
+            Err(e) => log::error!("failed to import {}: {e}", path.display()),
+        }
+    }
+
+    let subtitles = subtitles::Subtitles::new(&opts.upload_dir, mpv_ipc.clone(), playlist.clone());
+    subtitles.remove_old().await;
+
+    tokio::spawn(subtitles.clone().run(
+        mpv_ipc.observe_event("start-file").unwrap(),
+        mpv_ipc.observe_event("file-loaded").unwrap(),
+    ));
+
+    let history = history::History::new(
+        &opts.data_dir.join("history.jsonl"),
+        playlist.clone(),
+        metadata.clone(),
+    );
+
+    tokio::spawn(history.clone().run(
+        mpv_ipc.observe_event("start-file").unwrap(),
+        mpv_ipc.observe_event("end-file").unwrap(),
+        mpv_ipc.observe_property("pause").await.unwrap(),
+    ));
+
+    let loudness_settings = loudness::Settings {
+        mode: opts.normalize,
+        measure: opts.measure_loudness,
+        target: opts.loudness_target,
+    };
+    loudness_settings
+        .validate()
+        .expect("invalid loudness target");
+
+    let loudness = loudness::Loudness::new(
+        mpv_ipc.clone(),
+        ffmpeg::FfMpeg::new(opts.ffmpeg_path),
+        playlist.clone(),
+        loudness_settings,
+    );
+
+    // Watched once the state kept about each entry exists, so that it's forgotten along with the
+    // entries that are removed.
     let mut data_stream = mpv_ipc.observe_property("playlist").await.unwrap();
 
     tokio::spawn({
         let playlist = playlist.clone();
+        let metadata = metadata.clone();
+        let subtitles = subtitles.clone();
+        let history = history.clone();
+        let loudness = loudness.clone();
         async move {
             while let Some(p) = data_stream.recv().await {
                 if let Ok(v) = serde_json::from_value::<Vec<PlaylistEntry>>(p) {
                     log::info!("playlist: {v:?}");
+                    metadata.update(&v).await;
+                    subtitles.update(&v).await;
+                    history.update(&v);
+                    loudness.update(&v);
                     *playlist.write().await = v;
                 } else {
                     log::error!("failed to decode playlist")
@@ -183,19 +481,217 @@ async fn main() {
         }
     });
 
+    let entry_errors =
+        entry_errors::EntryErrors::new(mpv_ipc.clone(), playlist.clone(), opts.osd_errors);
+
+    // This is also how yt-dlp's errors reach us.
+    if let Err(e) = mpv_ipc.request_log_messages(&opts.mpv_log_level).await {
+        log::warn!("failed to request log messages from mpv: {e}");
+    }
+
+    let mpv_log = mpv_log::MpvLog::new();
+    tokio::spawn(
+        mpv_log
+            .clone()
+            .run(mpv_ipc.observe_event("log-message").unwrap()),
+    );
+
+    tokio::spawn(entry_errors.clone().run(
+        mpv_ipc.observe_event("start-file").unwrap(),
+        mpv_ipc.observe_event("end-file").unwrap(),
+        mpv_ipc.observe_event("log-message").unwrap(),
+    ));
+
+    let chat_filter = match &opts.chat_blocklist {
+        Some(path) => chat::ChatFilter::load(opts.chat_max_length, path)
+            .await
+            .expect("failed to read the chat blocklist"),
+        None => chat::ChatFilter::new(opts.chat_max_length, vec![]),
+    };
+
+    let autoplay = autoplay::Autoplay::new(
+        opts.autoplay,
+        mpv_ipc.clone(),
+        history.clone(),
+        library.clone(),
+        ytdlp.clone(),
+    );
+    tokio::spawn(autoplay.clone().run(
+        mpv_ipc.observe_event("start-file").unwrap(),
+        mpv_ipc.observe_property("idle-active").await.unwrap(),
+    ));
+
+    tokio::spawn(
+        loudness
+            .clone()
+            .run(mpv_ipc.observe_event("start-file").unwrap()),
+    );
+
+    let events = events::Events::new();
+    let chat = chat::Chat::new(
+        mpv_ipc.clone(),
+        events.clone(),
+        chat_filter,
+        opts.chat_rate_limit,
+    );
+    tokio::spawn(chat.clone().run());
+
+    let sleep_timer = sleep_timer::SleepTimer::new(mpv_ipc.clone());
+
+    let scheduler = scheduler::Scheduler::load(
+        &opts.data_dir.join("schedule.json"),
+        mpv_ipc.clone(),
+        playlist.clone(),
+    )
+    .expect("failed to load the schedule");
+    tokio::spawn(scheduler.clone().run(
+        mpv_ipc.observe_event("start-file").unwrap(),
+        mpv_ipc.observe_event("file-loaded").unwrap(),
+    ));
+
+    let saved_queues = saved_queues::SavedQueues::new(&opts.data_dir.join("queues"));
+
+    // The imported entries may not be in our copy of the playlist yet, and uploads in saved queues
+    // or in the schedule will be played later, so none of them are orphans.
+    let mut referenced = imported;
+    referenced.extend(scheduler.urls().await);
+    match saved_queues.urls().await {
+        Ok(urls) => referenced.extend(urls),
+        Err(e) => log::warn!("failed to read the saved queues: {e}"),
+    }
+
+    let retention = retention::Retention::new(
+        opts.upload_retention,
+        opts.upload_dir.clone(),
+        playlist.clone(),
+    );
+
+    tokio::spawn(
+        retention
+            .clone()
+            .run(mpv_ipc.observe_event("end-file").unwrap(), referenced),
+    );
+
+    // Started once everything it moves to the copies of the entries exists.
+    if opts.prefetch > 0 {
+        tokio::spawn(
+            prefetch::Prefetcher::new(
+                ytdlp.clone(),
+                opts.data_dir.join("prefetch"),
+                opts.prefetch,
+                opts.prefetch_cache_size * MIB,
+                mpv_ipc.clone(),
+                playlist.clone(),
+                history.clone(),
+                metadata.clone(),
+                subtitles.clone(),
+                autoplay.clone(),
+                retention.clone(),
+            )
+            .run(),
+        );
+    }
+
+    let announcements = announcements::Announcements::new(mpv_ipc.clone(), events.clone());
+    tokio::spawn(announcements.clone().run());
+
+    let upload_limits = upload::UploadLimits::new(
+        opts.max_upload_size * MIB,
+        opts.upload_quota_per_user.map(|quota| quota * MIB),
+        opts.upload_quota_total.map(|quota| quota * MIB),
+        opts.min_free_space * MIB,
+    );
+
+    // Keep the aria2c process around until kameloso exits, it's killed when dropped.
+    let (torrents, _aria2c) = if opts.no_torrents {
+        (None, None)
+    } else {
+        let _ = fs::create_dir(&opts.torrent_dir).await;
+
+        // aria2c reports the paths relative to this, and mpv might not share our working directory.
+        let torrent_dir = fs::canonicalize(&opts.torrent_dir)
+            .await
+            .unwrap_or(opts.torrent_dir);
+
+        match aria2::Aria2::spawn(&opts.aria2c_path, &torrent_dir) {
+            Ok((aria2, child)) => {
+                let torrents = aria2::Torrents::new(
+                    aria2,
+                    torrent_dir,
+                    upload_limits.clone(),
+                    opts.upload_retention,
+                    mpv_ipc.clone(),
+                    playlist.clone(),
+                    history.clone(),
+                    autoplay.clone(),
+                );
+                tokio::spawn(torrents.clone().run());
+                (Some(torrents), Some(child))
+            }
+            Err(e) => {
+                log::warn!("torrents are disabled: {e}");
+                (None, None)
+            }
+        }
+    };
+
+    let exported = opts
+        .export_playlist
+        .map(|path| (path, playlist.clone(), metadata.clone(), history.clone()));
+
     let server_handle = tokio::spawn(server_hyper::start(
         opts.bind_address,
         server_state::ServerState {
             ipc: mpv_ipc,
             serve_dir,
+            chunked_uploads: chunked_upload::ChunkedUploads::new(&opts.upload_dir),
+            upload_limits,
+            upload_hashes: upload_hashes::UploadHashes::load(&opts.upload_dir)
+                .expect("failed to load the upload hash index"),
             upload_dir: opts.upload_dir,
             qr_code_params: Arc::new(Mutex::new(qr_code_params)),
             playlist,
+            ytdlp,
+            metadata,
+            search,
+            library,
+            library_index,
+            retention,
+            subtitles,
+            history,
+            autoplay,
+            entry_errors,
+            mpv_log,
+            events,
+            chat,
+            announcements,
+            sleep_timer,
+            scheduler,
+            loudness,
+            saved_queues,
+            torrents,
+            admin_token: opts.admin_token,
+            max_playlist_entries: opts.max_playlist_entries,
         },
+        (!opts.no_mdns).then_some(opts.mdns_hostname),
     ));
 
     let _ = reactor_handle.await;
     server_handle.abort();
+
+    if let Some((path, playlist, metadata, history)) = exported {
+        let items = playlist_file::from_playlist(
+            &playlist.read().await,
+            &*metadata.read().await,
+            &history.original_urls(),
+        );
+
+        match playlist_file::export_file(&path, &items).await {
+            Ok(()) => log::info!("saved the playlist to {}", path.display()),
+            Err(e) => log::error!("failed to save the playlist to {}: {e}", path.display()),
+        }
+    }
+
     let _ = mpv_process.wait().await;
     // let _ = mpv_process.kill().await;
 }
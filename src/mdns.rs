@@ -0,0 +1,96 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_http._tcp.local.";
+
+/// Keeps the `_http._tcp` service registered for as long as it's alive.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// The name the service will be reachable at, e.g. `kameloso.local`.
+pub fn local_hostname(hostname: &str) -> String {
+    format!("{hostname}.local")
+}
+
+fn register(daemon: &ServiceDaemon, hostname: &str, port: u16) -> Result<String, mdns_sd::Error> {
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        hostname,
+        &format!("{}.", local_hostname(hostname)),
+        "",
+        port,
+        &[("path", "/")][..],
+    )?
+    .enable_addr_auto();
+
+    let fullname = service.get_fullname().to_string();
+
+    daemon.register(service)?;
+
+    Ok(fullname)
+}
+
+pub fn advertise(hostname: &str, port: u16) -> Result<Advertisement, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let fullname = register(&daemon, hostname, port)?;
+
+    log::info!(
+        "advertising http://{}:{port} via mDNS",
+        local_hostname(hostname)
+    );
+
+    Ok(Advertisement { daemon, fullname })
+}
+
+#[test]
+fn test_advertise_on_loopback() {
+    use mdns_sd::{IfKind, ServiceEvent};
+    use std::time::{Duration, Instant};
+
+    fn loopback_daemon() -> ServiceDaemon {
+        let daemon = ServiceDaemon::new().unwrap();
+        daemon.disable_interface(IfKind::All).unwrap();
+        daemon.enable_interface(IfKind::LoopbackV4).unwrap();
+        daemon
+    }
+
+    let responder = loopback_daemon();
+    let fullname = register(&responder, "kameloso-test", 8123).unwrap();
+    let _advertisement = Advertisement {
+        daemon: responder,
+        fullname,
+    };
+
+    let listener = loopback_daemon();
+    let events = listener.browse(SERVICE_TYPE).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let service = loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(timeout) {
+            Ok(ServiceEvent::ServiceResolved(info))
+                if info.get_hostname() == "kameloso-test.local." =>
+            {
+                break info
+            }
+            Ok(_) => {}
+            Err(e) => panic!("service was not resolved: {e}"),
+        }
+    };
+
+    assert_eq!(service.get_port(), 8123);
+    assert!(service
+        .get_addresses()
+        .iter()
+        .any(|addr| addr.is_loopback()));
+
+    let _ = listener.shutdown();
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, RwLock, RwLockReadGuard, Semaphore};
+
+use crate::{ffprobe::FfProbe, mpv::response::PlaylistEntry, ytdlp::YtDlp};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub duration: Option<f64>,
+    pub thumbnail: Option<String>,
+}
+
+struct Job {
+    id: u64,
+    filename: String,
+}
+
+/// Resolves metadata for playlist entries in the background, before mpv gets to load them.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    cache: Arc<RwLock<HashMap<u64, Metadata>>>,
+    submitted: Arc<Mutex<HashSet<u64>>>,
+    jobs_tx: mpsc::UnboundedSender<Job>,
+}
+
+pub fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+async fn resolve(ytdlp: &YtDlp, ffprobe: &FfProbe, filename: &str) -> Option<Metadata> {
+    if is_url(filename) {
+        match ytdlp.video_info(filename).await {
+            Ok(info) => Some(Metadata {
+                title: info.title,
+                uploader: info.uploader,
+                duration: info.duration,
+                thumbnail: info.thumbnail,
+            }),
+            Err(e) => {
+                log::warn!("failed to resolve metadata for {filename}: {e}");
+                None
+            }
+        }
+    } else if Path::new(filename).is_file() {
+        match ffprobe.probe(Path::new(filename)).await {
+            Ok(mut probe) => Some(Metadata {
+                title: probe.tags.remove("title"),
+                uploader: probe
+                    .tags
+                    .remove("artist")
+                    .or_else(|| probe.tags.remove("album_artist")),
+                duration: probe.duration,
+                thumbnail: None,
+            }),
+            Err(e) => {
+                log::warn!("failed to probe {filename}: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+impl Resolver {
+    /// Starts the background task, which will run at most `max_jobs` yt-dlp or ffprobe processes at once.
+    pub fn start(ytdlp: YtDlp, ffprobe: FfProbe, max_jobs: usize) -> Self {
+        let cache = Arc::new(RwLock::new(HashMap::new()));
+        let submitted = Arc::new(Mutex::new(HashSet::new()));
+        let (jobs_tx, mut jobs_rx) = mpsc::unbounded_channel::<Job>();
+
+        tokio::spawn({
+            let cache = cache.clone();
+            let submitted = submitted.clone();
+            let semaphore = Arc::new(Semaphore::new(max_jobs.max(1)));
+
+            async move {
+                while let Some(job) = jobs_rx.recv().await {
+                    let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                        break;
+                    };
+
+                    let cache = cache.clone();
+                    let submitted = submitted.clone();
+                    let ytdlp = ytdlp.clone();
+                    let ffprobe = ffprobe.clone();
+
+                    tokio::spawn(async move {
+                        if let Some(metadata) = resolve(&ytdlp, &ffprobe, &job.filename).await {
+                            log::debug!("resolved metadata for entry {}: {metadata:?}", job.id);
+                            let mut cache = cache.write().await;
+
+                            // The entry may have been removed while it was being resolved.
+                            if submitted.lock().unwrap().contains(&job.id) {
+                                cache.insert(job.id, metadata);
+                            }
+                        }
+                        drop(permit);
+                    });
+                }
+            }
+        });
+
+        Resolver {
+            cache,
+            submitted,
+            jobs_tx,
+        }
+    }
+
+    /// Queues the entries that haven't been seen before for resolution
+    /// and forgets the ones that aren't in the playlist anymore.
+    pub async fn update(&self, playlist: &[PlaylistEntry]) {
+        let ids: HashSet<u64> = playlist.iter().map(|entry| entry.id).collect();
+        self.cache.write().await.retain(|id, _| ids.contains(id));
+
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.retain(|id| ids.contains(id));
+
+        for entry in playlist {
+            if submitted.insert(entry.id) {
+                let _ = self.jobs_tx.send(Job {
+                    id: entry.id,
+                    filename: entry.filename.clone(),
+                });
+            }
+        }
+    }
+
+    /// Moves the metadata of an entry to the one that replaced it, so that it isn't resolved again.
+    pub async fn replaced(&self, old_id: u64, new_id: u64) {
+        let mut cache = self.cache.write().await;
+
+        if let Some(metadata) = cache.get(&old_id).cloned() {
+            self.submitted.lock().unwrap().insert(new_id);
+            cache.insert(new_id, metadata);
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, HashMap<u64, Metadata>> {
+        self.cache.read().await
+    }
+}
+
+#[tokio::test]
+async fn test_resolver_forgets_removed_entries() {
+    let resolver = Resolver::start(
+        YtDlp::new("yt-dlp".to_string()),
+        FfProbe::new("ffprobe".to_string()),
+        1,
+    );
+    let entry = |id| PlaylistEntry {
+        id,
+        filename: format!("/nonexistent/{id}.mp3"),
+        title: None,
+        current: false,
+        playing: false,
+    };
+
+    resolver.update(&[entry(1), entry(2)]).await;
+    for id in [1, 2] {
+        resolver.cache.write().await.insert(id, Metadata::default());
+    }
+
+    resolver.update(&[entry(2), entry(3)]).await;
+    assert_eq!(
+        resolver.read().await.keys().copied().collect::<Vec<_>>(),
+        vec![2]
+    );
+    assert_eq!(*resolver.submitted.lock().unwrap(), HashSet::from([2, 3]));
+}
@@ -25,7 +25,7 @@ pub mod response {
         pub playlist_entry_id: i64,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct PlaylistEntry {
         pub id: u64,
         pub filename: String,
@@ -35,6 +35,82 @@ pub mod response {
         #[serde(default)]
         pub playing: bool,
     }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TrackType {
+        Video,
+        Audio,
+        Sub,
+    }
+
+    /// An entry of the `track-list` property.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct Track {
+        /// Unique among the tracks of the same type, and what `aid`, `sid` and `vid` are set to.
+        pub id: i64,
+        #[serde(rename = "type")]
+        pub track_type: TrackType,
+        pub title: Option<String>,
+        pub lang: Option<String>,
+        pub codec: Option<String>,
+        #[serde(default)]
+        pub selected: bool,
+
+        /// 0 when selected as the main track, 1 when selected as the secondary subtitles.
+        pub main_selection: Option<u8>,
+        #[serde(default)]
+        pub default: bool,
+
+        /// Added with `sub-add` or `audio-add` rather than contained in the file.
+        #[serde(default)]
+        pub external: bool,
+        pub external_filename: Option<String>,
+    }
+
+    /// An entry of the `chapter-list` property.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct Chapter {
+        pub title: Option<String>,
+
+        /// Start of the chapter in seconds.
+        pub time: f64,
+    }
+}
+
+// Fields of the events sent by mpv, search for mp_event_property in
+// https://github.com/mpv-player/mpv/blob/master/player/client.c
+pub mod event {
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct FileLoaded {}
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct StartFile {
+        pub playlist_entry_id: i64,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct EndFile {
+        pub playlist_entry_id: i64,
+
+        /// One of eof, stop, quit, error or redirect.
+        pub reason: String,
+
+        /// Only set when the reason is error.
+        pub file_error: Option<String>,
+    }
+
+    /// Only sent after `request_log_messages`.
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct LogMessage {
+        /// The module that logged the message, like `ytdl_hook` or `cplayer`.
+        pub prefix: String,
+        pub level: String,
+        pub text: String,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +197,122 @@ impl Client {
         self.command_reply(&["playlist-next"]).await
     }
 
+    pub async fn playlist_remove(&self, index: u64) -> Result<(), Error> {
+        self.command_reply(&["playlist-remove", &index.to_string()])
+            .await
+    }
+
+    pub async fn get_track_list(&self) -> Result<Vec<Track>, Error> {
+        self.command_reply(&["get_property", "track-list"]).await
+    }
+
+    async fn set_property(&self, property: &str, value: &str) -> Result<(), Error> {
+        self.command_reply(&["set_property", property, value]).await
+    }
+
+    /// Selects a track of the given type, or disables that type of track if `id` is None.
+    pub async fn set_track(&self, track_type: TrackType, id: Option<i64>) -> Result<(), Error> {
+        let property = match track_type {
+            TrackType::Video => "vid",
+            TrackType::Audio => "aid",
+            TrackType::Sub => "sid",
+        };
+
+        let id = id.map_or_else(|| "no".to_string(), |id| id.to_string());
+
+        self.set_property(property, &id).await
+    }
+
+    /// Selects a subtitle track to show at the top of the screen along with the main one.
+    pub async fn set_secondary_sub_track(&self, id: Option<i64>) -> Result<(), Error> {
+        let id = id.map_or_else(|| "no".to_string(), |id| id.to_string());
+
+        self.set_property("secondary-sid", &id).await
+    }
+
+    /// Delay of the subtitles relative to the video, in seconds.
+    pub async fn get_sub_delay(&self) -> Result<f64, Error> {
+        self.command_reply(&["get_property", "sub-delay"]).await
+    }
+
+    pub async fn set_sub_delay(&self, delay: f64) -> Result<(), Error> {
+        self.set_property("sub-delay", &delay.to_string()).await
+    }
+
+    /// Delay of the audio relative to the video, in seconds.
+    pub async fn get_audio_delay(&self) -> Result<f64, Error> {
+        self.command_reply(&["get_property", "audio-delay"]).await
+    }
+
+    pub async fn set_audio_delay(&self, delay: f64) -> Result<(), Error> {
+        self.set_property("audio-delay", &delay.to_string()).await
+    }
+
+    pub async fn get_chapter_list(&self) -> Result<Vec<Chapter>, Error> {
+        self.command_reply(&["get_property", "chapter-list"]).await
+    }
+
+    /// Index of the current chapter in the chapter list,
+    /// or -1 if the file has chapters but playback is before the first one.
+    pub async fn get_chapter(&self) -> Result<i64, Error> {
+        self.command_reply(&["get_property", "chapter"]).await
+    }
+
+    pub async fn set_chapter(&self, index: i64) -> Result<(), Error> {
+        self.set_property("chapter", &index.to_string()).await
+    }
+
+    /// Adds an external subtitle file to the file that's currently playing.
+    pub async fn sub_add(&self, url: &str, select: bool) -> Result<(), Error> {
+        self.command_reply(&["sub-add", url, if select { "select" } else { "auto" }])
+            .await
+    }
+
+    /// Draws ASS events on the window, replacing the ones previously drawn with the same ID.
+    pub async fn osd_overlay(
+        &self,
+        id: u64,
+        data: &str,
+        res_x: u32,
+        res_y: u32,
+    ) -> Result<(), Error> {
+        let _: serde_json::Value = self
+            .command_reply_json(serde_json::json!({
+                "name": "osd-overlay",
+                "id": id,
+                "format": "ass-events",
+                "data": data,
+                "res_x": res_x,
+                "res_y": res_y,
+            }))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn osd_overlay_remove(&self, id: u64) -> Result<(), Error> {
+        let _: serde_json::Value = self
+            .command_reply_json(serde_json::json!({
+                "name": "osd-overlay",
+                "id": id,
+                "format": "none",
+                "data": "",
+            }))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn show_text(&self, text: &str, duration_ms: u64) -> Result<(), Error> {
+        self.command_reply(&["show-text", text, &duration_ms.to_string()])
+            .await
+    }
+
+    /// Starts sending `log-message` events for messages at this level or more severe.
+    pub async fn request_log_messages(&self, level: &str) -> Result<(), Error> {
+        self.command_reply(&["request_log_messages", level]).await
+    }
+
     pub async fn overlay_add(&self, opts: &OverlayAddOptions) -> Result<(), Error> {
         self.command_reply(&[
             "overlay-add",
@@ -150,10 +342,40 @@ impl Client {
         self.command_reply(&["get_property", "time-pos/full"]).await
     }
 
+    /// Seeks to a position in the current file, in seconds.
+    pub async fn seek(&self, position: f64) -> Result<(), Error> {
+        self.command_reply(&["seek", &position.to_string(), "absolute"])
+            .await
+    }
+
     pub async fn get_paused(&self) -> Result<bool, Error> {
         self.command_reply(&["get_property", "pause"]).await
     }
 
+    /// Volume in percent, where 100 is the original volume.
+    pub async fn get_volume(&self) -> Result<f64, Error> {
+        self.command_reply(&["get_property", "volume"]).await
+    }
+
+    pub async fn set_volume(&self, volume: f64) -> Result<(), Error> {
+        self.set_property("volume", &volume.to_string()).await
+    }
+
+    /// Volume adjustment in dB on top of the volume, which mpv clamps to `volume-gain-max`.
+    pub async fn set_volume_gain(&self, gain: f64) -> Result<(), Error> {
+        self.set_property("volume-gain", &gain.to_string()).await
+    }
+
+    /// Changes the audio filter chain, with one of the operations of mpv's `af` command.
+    pub async fn af(&self, operation: &str, value: &str) -> Result<(), Error> {
+        self.command_reply(&["af", operation, value]).await
+    }
+
+    pub async fn set_paused(&self, paused: bool) -> Result<(), Error> {
+        self.set_property("pause", if paused { "yes" } else { "no" })
+            .await
+    }
+
     pub async fn observe_property(
         &self,
         property: &str,
@@ -169,4 +391,124 @@ impl Client {
 
         Ok(data_stream)
     }
+
+    /// Receives the fields of every event with this name, e.g. `end-file`.
+    pub fn observe_event<T: DeserializeOwned + Send + 'static>(
+        &self,
+        event: &str,
+    ) -> Result<UnboundedReceiver<T>, Error> {
+        let mut raw_events = reactor::observe_event(event.to_string(), &self.commands_tx)
+            .map_err(|_| Error::CommandsChannelClosed)?;
+
+        let (events_tx, events_rx) = tokio::sync::mpsc::unbounded_channel();
+        let event = event.to_string();
+
+        tokio::spawn(async move {
+            while let Some(fields) = raw_events.recv().await {
+                match serde_json::from_value(fields) {
+                    Ok(decoded) => {
+                        if events_tx.send(decoded).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => log::warn!("failed to decode {event} event: {e}"),
+                }
+            }
+        });
+
+        Ok(events_rx)
+    }
+}
+
+#[cfg(test)]
+impl Client {
+    /// A client that isn't connected to mpv, which answers `loadfile` with a new entry ID
+    /// and everything else with `null`. Commands are sent to the receiver as they come in.
+    pub fn fake() -> (Self, UnboundedReceiver<serde_json::Value>) {
+        Client::fake_with(|_| serde_json::Value::Null)
+    }
+
+    /// Like [`Client::fake`], but commands other than `loadfile` are answered by `reply`.
+    pub fn fake_with(
+        reply: impl Fn(&serde_json::Value) -> serde_json::Value + Send + 'static,
+    ) -> (Self, UnboundedReceiver<serde_json::Value>) {
+        let (commands_tx, mut commands_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (sent_tx, sent_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut next_entry_id = 1;
+
+            while let Some(command) = commands_rx.recv().await {
+                match command {
+                    Command::WithResponse { command, handler } => {
+                        let reply = if command[0] == "loadfile" {
+                            next_entry_id += 1;
+                            serde_json::json!({ "playlist_entry_id": next_entry_id - 1 })
+                        } else {
+                            reply(&command)
+                        };
+                        let _ = sent_tx.send(command);
+                        let _ = handler.send(Ok(reply));
+                    }
+                    Command::ObserveProperty {
+                        property, handler, ..
+                    } => {
+                        let _ = sent_tx.send(serde_json::json!(["observe_property", property]));
+                        let _ = handler.send(Ok(serde_json::Value::Null));
+                    }
+                    Command::ObserveEvent { .. } => {}
+                }
+            }
+        });
+
+        (Client::new(commands_tx), sent_rx)
+    }
+}
+
+#[test]
+fn test_decode_tracks_and_chapters() {
+    let tracks: Vec<Track> = serde_json::from_value(serde_json::json!([
+        {
+            "id": 1,
+            "type": "video",
+            "src-id": 0,
+            "albumart": false,
+            "default": true,
+            "forced": false,
+            "external": false,
+            "selected": true,
+            "codec": "h264"
+        },
+        {
+            "id": 2,
+            "type": "sub",
+            "title": "Signs & Songs",
+            "lang": "eng",
+            "external": true,
+            "external-filename": "/uploads/.subtitles/signs.ass",
+            "selected": true,
+            "main-selection": 1,
+            "codec": "ass"
+        }
+    ]))
+    .unwrap();
+
+    assert_eq!(tracks[0].track_type, TrackType::Video);
+    assert!(tracks[0].selected);
+    assert_eq!(tracks[1].track_type, TrackType::Sub);
+    assert_eq!(tracks[1].lang.as_deref(), Some("eng"));
+    assert_eq!(tracks[1].main_selection, Some(1));
+    assert_eq!(
+        tracks[1].external_filename.as_deref(),
+        Some("/uploads/.subtitles/signs.ass")
+    );
+
+    let chapters: Vec<Chapter> = serde_json::from_value(serde_json::json!([
+        { "title": "Opening", "time": 0.0 },
+        { "time": 90.5 }
+    ]))
+    .unwrap();
+
+    assert_eq!(chapters[0].title.as_deref(), Some("Opening"));
+    assert_eq!(chapters[1].time, 90.5);
 }
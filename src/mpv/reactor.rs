@@ -19,6 +19,8 @@ pub type ResponseHandler = oneshot::Sender<Response>;
 
 pub type PropertyChangeSender = UnboundedSender<serde_json::Value>;
 
+pub type EventSender = UnboundedSender<serde_json::Value>;
+
 pub enum Command {
     WithResponse {
         command: serde_json::Value,
@@ -29,6 +31,10 @@ pub enum Command {
         handler: ResponseHandler,
         data_tx: PropertyChangeSender,
     },
+    ObserveEvent {
+        event: String,
+        data_tx: EventSender,
+    },
 }
 
 #[derive(Debug)]
@@ -53,6 +59,7 @@ struct Reactor<NamedPipe> {
     command_handlers: HashMap<i64, ResponseHandler>,
     next_request_id: i64,
     observe_property_handlers: HashMap<String, PropertyChangeSender>,
+    observe_event_handlers: HashMap<String, Vec<EventSender>>,
 
     // This field is only here because Windows likes to return an empty buffer
     // the first time we poll for a read, so we check if this is the first time
@@ -71,6 +78,7 @@ impl<NamedPipe: AsyncRead + AsyncWrite + Unpin> Reactor<NamedPipe> {
             command_handlers: HashMap::new(),
             next_request_id: 0,
             observe_property_handlers: HashMap::new(),
+            observe_event_handlers: HashMap::new(),
             is_maybe_eof: false,
         }
     }
@@ -119,6 +127,12 @@ impl<NamedPipe: AsyncRead + AsyncWrite + Unpin> Reactor<NamedPipe> {
                 }
                 Ok(Message::Event { event, fields }) => {
                     log::debug!("received event: {event} {fields:?}");
+
+                    if let Some(handlers) = self.observe_event_handlers.get_mut(&event) {
+                        // Unlike properties, events can have more than one observer,
+                        // so we just forget about the ones that went away.
+                        handlers.retain(|handler| handler.send(fields.clone()).is_ok());
+                    }
                 }
                 Err(e) => {
                     log::error!("failed to decode mpv message: {e}");
@@ -187,6 +201,9 @@ impl<NamedPipe: AsyncRead + AsyncWrite + Unpin> Reactor<NamedPipe> {
                 Some(Command::ObserveProperty { property, handler, data_tx }) => {
                     self.observe_property(property, handler, data_tx).await?;
                 }
+                Some(Command::ObserveEvent { event, data_tx }) => {
+                    self.observe_event_handlers.entry(event).or_default().push(data_tx);
+                }
             },
         }
 
@@ -245,3 +262,14 @@ pub async fn observe_property(
     })?;
     Ok((handler_rx, data_rx))
 }
+
+/// Events are always sent by mpv, so there's no command to send
+/// and the receiver is ready as soon as this returns.
+pub fn observe_event(
+    event: String,
+    commands_tx: &UnboundedSender<Command>,
+) -> Result<UnboundedReceiver<serde_json::Value>, SendError<Command>> {
+    let (data_tx, data_rx) = mpsc::unbounded_channel();
+    commands_tx.send(Command::ObserveEvent { event, data_tx })?;
+    Ok(data_rx)
+}
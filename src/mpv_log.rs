@@ -0,0 +1,130 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::mpv::event::LogMessage;
+
+/// Number of lines kept for the admin log view.
+const MAX_LINES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The level as mpv calls it, like `v` or `status`.
+    pub level: String,
+    pub prefix: String,
+    pub text: String,
+
+    #[serde(skip)]
+    severity: log::Level,
+}
+
+/// mpv's log levels, from the most to the least severe.
+pub const LEVELS: &[&str] = &[
+    "fatal", "error", "warn", "info", "status", "v", "debug", "trace",
+];
+
+/// Maps mpv's log levels to ours.
+pub fn severity(level: &str) -> log::Level {
+    match level {
+        "fatal" | "error" => log::Level::Error,
+        "warn" => log::Level::Warn,
+        "info" | "status" => log::Level::Info,
+        "v" | "debug" => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+/// Forwards mpv's log messages to our logger and keeps the most recent ones around.
+#[derive(Debug, Clone)]
+pub struct MpvLog {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+}
+
+impl MpvLog {
+    pub fn new() -> Self {
+        MpvLog {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_LINES))),
+        }
+    }
+
+    /// The most recent `limit` lines at `level` or more severe, oldest first.
+    pub fn lines(&self, level: log::Level, limit: usize) -> Vec<LogLine> {
+        let lines = self.lines.lock().unwrap();
+
+        let mut lines: Vec<_> = lines
+            .iter()
+            .rev()
+            .filter(|line| line.severity <= level)
+            .take(limit)
+            .cloned()
+            .collect();
+        lines.reverse();
+        lines
+    }
+
+    fn push(&self, message: LogMessage) {
+        let severity = severity(&message.level);
+        let text = message.text.trim_end().to_string();
+
+        log::log!(target: "mpv", severity, "[{}] {text}", message.prefix);
+
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == MAX_LINES {
+            lines.pop_front();
+        }
+
+        lines.push_back(LogLine {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            level: message.level,
+            prefix: message.prefix,
+            text,
+            severity,
+        });
+    }
+
+    pub async fn run(self, mut log_message_rx: UnboundedReceiver<LogMessage>) {
+        while let Some(message) = log_message_rx.recv().await {
+            self.push(message);
+        }
+    }
+}
+
+#[test]
+fn test_mpv_log_ring_buffer() {
+    let log = MpvLog::new();
+
+    for i in 0..MAX_LINES + 10 {
+        log.push(LogMessage {
+            prefix: "cplayer".to_string(),
+            level: if i % 2 == 0 { "v" } else { "error" }.to_string(),
+            text: format!("line {i}\n"),
+        });
+    }
+
+    let all = log.lines(log::Level::Trace, usize::MAX);
+    assert_eq!(all.len(), MAX_LINES);
+    assert_eq!(all[0].text, "line 10");
+
+    let errors = log.lines(log::Level::Warn, 2);
+    assert_eq!(
+        errors
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>(),
+        [
+            format!("line {}", MAX_LINES + 7),
+            format!("line {}", MAX_LINES + 9)
+        ]
+    );
+    assert!(errors.iter().all(|line| line.level == "error"));
+}
@@ -0,0 +1,35 @@
+//! Text drawn on the mpv window with `osd-overlay`.
+
+/// IDs of the `osd-overlay` overlays. These are separate from the IDs used by `overlay-add`.
+pub const CHAT_OVERLAY_ID: u64 = 1;
+pub const ANNOUNCEMENT_OVERLAY_ID: u64 = 2;
+
+/// Size of the canvas the ASS coordinates refer to, which is scaled to the window.
+pub const RES_X: u32 = 1280;
+pub const RES_Y: u32 = 720;
+
+/// Makes user-provided text safe to put in an ASS event, so it can't add override tags or line breaks.
+pub fn escape_ass(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            // A zero-width no-break space after the backslash keeps it from starting an escape like \N.
+            '\\' => escaped.push_str("\\\u{feff}"),
+            '{' => escaped.push_str("\\{"),
+            '}' => escaped.push_str("\\}"),
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[test]
+fn test_escape_ass() {
+    assert_eq!(
+        escape_ass("{\\an5}big\\Ntext\nhere"),
+        "\\{\\\u{feff}an5\\}big\\\u{feff}Ntext here"
+    );
+}
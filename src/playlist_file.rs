@@ -0,0 +1,309 @@
+use std::{collections::HashMap, fmt::Write, io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{metadata::Metadata, mpv::response::PlaylistEntry};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown playlist format: {0}")]
+    UnknownFormat(String),
+
+    #[error("invalid JSON playlist: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("invalid XSPF playlist: {0}")]
+    Xml(#[from] roxmltree::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "m3u8" | "m3u" => Ok(Format::M3u8),
+            "xspf" => Ok(Format::Xspf),
+            "json" => Ok(Format::Json),
+            _ => Err(Error::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        path.extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .parse()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::M3u8 => "m3u8",
+            Format::Xspf => "xspf",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Format::Xspf => "application/xspf+xml",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Item {
+    /// URL or path of a local file.
+    pub url: String,
+    pub title: Option<String>,
+
+    /// Seconds.
+    pub duration: Option<f64>,
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// XSPF locations are URIs, so local files are written as file:// URLs.
+fn to_location(url: &str) -> String {
+    if Path::new(url).is_absolute() {
+        if let Ok(file_url) = url::Url::from_file_path(url) {
+            return file_url.to_string();
+        }
+    }
+
+    url.to_string()
+}
+
+fn from_location(location: &str) -> String {
+    match url::Url::parse(location) {
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| location.to_string()),
+        _ => location.to_string(),
+    }
+}
+
+pub fn export(format: Format, items: &[Item]) -> String {
+    match format {
+        Format::M3u8 => {
+            let mut m3u = "#EXTM3U\n".to_string();
+
+            for item in items {
+                if item.title.is_some() || item.duration.is_some() {
+                    let _ = writeln!(
+                        m3u,
+                        "#EXTINF:{},{}",
+                        item.duration.map_or(-1, |duration| duration.round() as i64),
+                        item.title.as_deref().unwrap_or_default().replace('\n', " ")
+                    );
+                }
+                let _ = writeln!(m3u, "{}", item.url);
+            }
+
+            m3u
+        }
+
+        Format::Xspf => {
+            let mut xspf = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n"
+                .to_string();
+
+            for item in items {
+                xspf.push_str("    <track>\n");
+                let _ = writeln!(
+                    xspf,
+                    "      <location>{}</location>",
+                    xml_escape(&to_location(&item.url))
+                );
+                if let Some(title) = &item.title {
+                    let _ = writeln!(xspf, "      <title>{}</title>", xml_escape(title));
+                }
+                if let Some(duration) = item.duration {
+                    let _ = writeln!(
+                        xspf,
+                        "      <duration>{}</duration>",
+                        (duration * 1000.0).round() as u64
+                    );
+                }
+                xspf.push_str("    </track>\n");
+            }
+
+            xspf.push_str("  </trackList>\n</playlist>\n");
+            xspf
+        }
+
+        Format::Json => serde_json::to_string_pretty(items).unwrap_or_default(),
+    }
+}
+
+fn import_m3u8(content: &str) -> Vec<Item> {
+    let mut items = vec![];
+    let mut extinf = None;
+
+    for line in content.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = info.split_once(',').unwrap_or((info, ""));
+            extinf = Some((
+                duration.trim().parse::<f64>().ok().filter(|&d| d >= 0.0),
+                Some(title.trim().to_string()).filter(|title| !title.is_empty()),
+            ));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (duration, title) = extinf.take().unwrap_or_default();
+            items.push(Item {
+                url: line.to_string(),
+                title,
+                duration,
+            });
+        }
+    }
+
+    items
+}
+
+fn import_xspf(content: &str) -> Result<Vec<Item>, Error> {
+    let doc = roxmltree::Document::parse(content)?;
+
+    let child_text = |node: roxmltree::Node, name: &str| {
+        node.children()
+            .find(|child| child.tag_name().name() == name)
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_string())
+    };
+
+    Ok(doc
+        .descendants()
+        .filter(|node| node.tag_name().name() == "track")
+        .filter_map(|track| {
+            Some(Item {
+                url: from_location(&child_text(track, "location")?),
+                title: child_text(track, "title"),
+                duration: child_text(track, "duration")
+                    .and_then(|ms| ms.parse::<f64>().ok())
+                    .map(|ms| ms / 1000.0),
+            })
+        })
+        .collect())
+}
+
+/// Reads a playlist. Relative paths are resolved against `base_dir` if there is one.
+pub fn import(format: Format, content: &str, base_dir: Option<&Path>) -> Result<Vec<Item>, Error> {
+    let mut items = match format {
+        Format::M3u8 => import_m3u8(content),
+        Format::Xspf => import_xspf(content)?,
+        Format::Json => serde_json::from_str(content)?,
+    };
+
+    if let Some(base_dir) = base_dir {
+        for item in &mut items {
+            if !item.url.contains("://") && Path::new(&item.url).is_relative() {
+                item.url = base_dir.join(&item.url).to_string_lossy().to_string();
+            }
+        }
+    }
+
+    Ok(items)
+}
+
+/// Reads a playlist file, figuring out the format from its extension.
+pub async fn import_file(path: &Path) -> Result<Vec<Item>, Error> {
+    let format = Format::from_path(path)?;
+    let content = tokio::fs::read_to_string(path).await?;
+
+    import(format, &content, path.parent())
+}
+
+/// Writes a playlist file, figuring out the format from its extension.
+pub async fn export_file(path: &Path, items: &[Item]) -> Result<(), Error> {
+    let format = Format::from_path(path)?;
+    tokio::fs::write(path, export(format, items)).await?;
+
+    Ok(())
+}
+
+/// Turns the entries of mpv's playlist into items, with their metadata if it was resolved.
+/// Entries that were replaced by a local copy are exported with the URL they were queued with,
+/// since the copy is deleted once it's played.
+pub fn from_playlist(
+    playlist: &[PlaylistEntry],
+    metadata: &HashMap<u64, Metadata>,
+    original_urls: &HashMap<i64, String>,
+) -> Vec<Item> {
+    playlist
+        .iter()
+        .map(|entry| {
+            let metadata = metadata.get(&entry.id);
+
+            Item {
+                url: original_urls
+                    .get(&(entry.id as i64))
+                    .cloned()
+                    .unwrap_or_else(|| entry.filename.clone()),
+                title: metadata
+                    .and_then(|metadata| metadata.title.clone())
+                    .or_else(|| entry.title.clone()),
+                duration: metadata.and_then(|metadata| metadata.duration),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_round_trip() {
+    let items = vec![
+        Item {
+            url: "https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string(),
+            title: Some("Never Gonna Give You Up".to_string()),
+            duration: Some(213.0),
+        },
+        Item {
+            url: "/music/Tom & Jerry/<theme>.mp3".to_string(),
+            title: None,
+            duration: None,
+        },
+    ];
+
+    for format in [Format::M3u8, Format::Xspf, Format::Json] {
+        let exported = export(format, &items);
+        assert_eq!(
+            import(format, &exported, None).unwrap(),
+            items,
+            "{format:?}"
+        );
+    }
+
+    let m3u = "#EXTM3U\n#EXTINF:-1,Intro\nintro.mp3\n\n# comment\nhttps://example.com/a.mp4\n";
+    assert_eq!(
+        import(Format::M3u8, m3u, Some(Path::new("/party"))).unwrap(),
+        vec![
+            Item {
+                url: "/party/intro.mp3".to_string(),
+                title: Some("Intro".to_string()),
+                duration: None,
+            },
+            Item {
+                url: "https://example.com/a.mp4".to_string(),
+                title: None,
+                duration: None,
+            },
+        ]
+    );
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{fs, sync::RwLock};
+
+use crate::{
+    autoplay::Autoplay,
+    history::History,
+    metadata::{is_url, Resolver},
+    mpv::{response::PlaylistEntry, Client, LoadFileOptions},
+    retention::Retention,
+    subtitles::Subtitles,
+    ytdlp::YtDlp,
+};
+
+/// How often the playlist is checked for entries to download.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Downloads the next few remote entries of the playlist with yt-dlp and replaces them with
+/// the local files before they play, so they don't depend on the Wi-Fi holding up.
+/// When a download fails the entry is left alone and streamed as usual.
+#[derive(Debug, Clone)]
+pub struct Prefetcher {
+    ytdlp: YtDlp,
+    cache_dir: PathBuf,
+    max_entries: usize,
+    max_cache_size: u64,
+    ipc: Client,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    history: History,
+    metadata: Resolver,
+    subtitles: Subtitles,
+    autoplay: Autoplay,
+    retention: Retention,
+}
+
+async fn cached_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = vec![];
+    let mut read_dir = fs::read_dir(dir).await?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.push((
+                entry.path(),
+                metadata.len(),
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            ));
+        }
+    }
+
+    Ok(files)
+}
+
+impl Prefetcher {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ytdlp: YtDlp,
+        cache_dir: PathBuf,
+        max_entries: usize,
+        max_cache_size: u64,
+        ipc: Client,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+        history: History,
+        metadata: Resolver,
+        subtitles: Subtitles,
+        autoplay: Autoplay,
+        retention: Retention,
+    ) -> Self {
+        Prefetcher {
+            ytdlp,
+            cache_dir,
+            max_entries,
+            max_cache_size,
+            ipc,
+            playlist,
+            history,
+            metadata,
+            subtitles,
+            autoplay,
+            retention,
+        }
+    }
+
+    /// Deletes the cached files that aren't in the playlist anymore and, if the cache is still
+    /// too large, the ones that were already played. Returns how much space is left in the cache.
+    async fn clean_cache(&self, playlist: &[PlaylistEntry]) -> std::io::Result<u64> {
+        let position = playlist
+            .iter()
+            .position(|entry| entry.playing || entry.current)
+            .unwrap_or(0);
+
+        let in_playlist: HashSet<PathBuf> = playlist
+            .iter()
+            .map(|entry| PathBuf::from(&entry.filename))
+            .collect();
+        let upcoming: HashSet<PathBuf> = playlist[position..]
+            .iter()
+            .map(|entry| PathBuf::from(&entry.filename))
+            .collect();
+
+        let mut files = cached_files(&self.cache_dir).await?;
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut used = 0;
+        let mut played = vec![];
+
+        for (path, size, _) in files {
+            if !in_playlist.contains(&path) {
+                log::debug!("removing {} from the prefetch cache", path.display());
+                fs::remove_file(&path).await?;
+            } else if upcoming.contains(&path) {
+                used += size;
+            } else {
+                used += size;
+                played.push((path, size));
+            }
+        }
+
+        for (path, size) in played {
+            if used <= self.max_cache_size {
+                break;
+            }
+
+            log::debug!("removing {} from the prefetch cache", path.display());
+            fs::remove_file(&path).await?;
+            used -= size;
+        }
+
+        Ok(self.max_cache_size.saturating_sub(used))
+    }
+
+    /// Replaces a playlist entry with the downloaded file, unless it started playing in the meantime.
+    /// Returns whether the downloaded file is in the playlist.
+    async fn swap(&self, entry_id: u64, url: &str, path: &Path) -> bool {
+        let index = {
+            let playlist = self.playlist.read().await;
+
+            match playlist.iter().position(|entry| entry.id == entry_id) {
+                Some(index) if !playlist[index].playing && !playlist[index].current => index,
+                _ => return false,
+            }
+        };
+
+        let loaded = match self
+            .ipc
+            .load_file(
+                &path.to_string_lossy(),
+                &LoadFileOptions::InsertAt(index as u64),
+            )
+            .await
+        {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                log::warn!("failed to enqueue {}: {e}", path.display());
+                return false;
+            }
+        };
+
+        // The new entry should have gone right in front of the old one, unless the playlist
+        // changed in the meantime. If the old one moved or started playing, keep it instead.
+        let playlist = match self.ipc.get_playlist().await {
+            Ok(playlist) => playlist,
+            Err(e) => {
+                // Both copies stay in the playlist, which is better than removing the wrong entry.
+                log::warn!("failed to get the playlist after enqueueing {url}: {e}");
+                return true;
+            }
+        };
+
+        let old_index = playlist.iter().position(|entry| entry.id == entry_id);
+        let new_index = playlist
+            .iter()
+            .position(|entry| entry.id as i64 == loaded.playlist_entry_id);
+
+        let swapped = match (old_index, new_index) {
+            (Some(old_index), Some(new_index))
+                if old_index == new_index + 1
+                    && !playlist[old_index].playing
+                    && !playlist[old_index].current =>
+            {
+                old_index
+            }
+            (_, Some(new_index)) => {
+                if let Err(e) = self.ipc.playlist_remove(new_index as u64).await {
+                    log::warn!("failed to remove the downloaded entry for {url}: {e}");
+                }
+                return false;
+            }
+            (_, None) => return false,
+        };
+
+        if let Err(e) = self.ipc.playlist_remove(swapped as u64).await {
+            log::warn!("failed to remove the streamed entry for {url}: {e}");
+        }
+
+        // Everything that was kept about the entry moves to its copy.
+        let new_id = loaded.playlist_entry_id;
+        self.history.replaced(entry_id as i64, new_id, url);
+        self.metadata.replaced(entry_id, new_id as u64).await;
+        self.subtitles.replaced(entry_id as i64, new_id);
+        self.autoplay.replaced(entry_id, new_id as u64);
+        self.retention.replaced(entry_id as i64, new_id);
+
+        true
+    }
+
+    /// Downloads and swaps in the next entries that haven't been tried yet.
+    async fn prefetch(&self, attempted: &mut HashSet<u64>) {
+        let playlist = self.playlist.read().await.clone();
+
+        let upcoming = match playlist.iter().position(|entry| entry.playing) {
+            Some(position) => &playlist[position + 1..],
+            None => &playlist[..],
+        };
+
+        let candidates: Vec<_> = upcoming
+            .iter()
+            .filter(|entry| is_url(&entry.filename))
+            .take(self.max_entries)
+            .filter(|entry| !attempted.contains(&entry.id))
+            .collect();
+
+        if candidates.is_empty() {
+            return;
+        }
+
+        let mut available = match self.clean_cache(&playlist).await {
+            Ok(available) => available,
+            Err(e) => {
+                log::warn!("failed to clean the prefetch cache: {e}");
+                return;
+            }
+        };
+
+        for entry in candidates {
+            if available == 0 {
+                return;
+            }
+
+            attempted.insert(entry.id);
+
+            let name = uuid::Uuid::new_v4().to_string();
+            let path = match self
+                .ytdlp
+                .download(&entry.filename, &self.cache_dir, &name, available)
+                .await
+            {
+                Ok(path) => {
+                    let size = fs::metadata(&path).await.map_or(0, |m| m.len());
+                    available = available.saturating_sub(size);
+                    path
+                }
+                Err(e) => {
+                    log::warn!(
+                        "failed to prefetch {}, it will be streamed: {e}",
+                        entry.filename
+                    );
+                    continue;
+                }
+            };
+
+            if self.swap(entry.id, &entry.filename, &path).await {
+                log::info!("prefetched {} to {}", entry.filename, path.display());
+            } else {
+                let _ = fs::remove_file(&path).await;
+            }
+        }
+    }
+
+    pub async fn run(mut self) {
+        // Entries don't survive a restart, so neither does the cache.
+        let _ = fs::remove_dir_all(&self.cache_dir).await;
+        if let Err(e) = fs::create_dir_all(&self.cache_dir).await {
+            log::error!("failed to create the prefetch cache, prefetching is disabled: {e}");
+            return;
+        }
+
+        // mpv might not share our working directory.
+        if let Ok(cache_dir) = fs::canonicalize(&self.cache_dir).await {
+            self.cache_dir = cache_dir;
+        }
+
+        let mut attempted = HashSet::new();
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.prefetch(&mut attempted).await;
+        }
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_prefetch_stub() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = crate::test_dir::TestDir::new();
+    let cache_dir = dir.join("prefetch");
+    std::fs::create_dir_all(&cache_dir).unwrap();
+    let script = dir.join("yt-dlp");
+
+    std::fs::write(
+        &script,
+        r#"#!/bin/sh
+for arg; do
+    case "$prev" in
+        -o) out="$arg" ;;
+    esac
+    prev="$arg"
+done
+case "$prev" in
+    https://example.com/broken) echo "ERROR: unavailable" >&2; exit 1 ;;
+esac
+path=$(echo "$out" | sed 's/%(ext)s/mp4/')
+echo video > "$path"
+echo "$path"
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let entry = |id, filename: &str, playing| PlaylistEntry {
+        id,
+        filename: filename.to_string(),
+        title: None,
+        current: playing,
+        playing,
+    };
+    let stale = cache_dir.join("stale.mp4");
+    std::fs::write(&stale, b"old").unwrap();
+
+    let playlist = Arc::new(RwLock::new(vec![
+        entry(11, "/music/song.mp3", true),
+        entry(12, "https://example.com/broken", false),
+        entry(13, "https://example.com/video", false),
+        entry(14, "https://example.com/later", false),
+    ]));
+
+    let ytdlp = YtDlp::new(script.to_string_lossy().to_string());
+    let metadata = Resolver::start(
+        ytdlp.clone(),
+        crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+        1,
+    );
+
+    // What mpv's playlist looks like after each entry is inserted.
+    let after_insert = Arc::new(std::sync::Mutex::new(vec![]));
+    let (ipc, mut commands) = Client::fake_with({
+        let after_insert = after_insert.clone();
+        move |command| {
+            if command == &serde_json::json!(["get_property", "playlist"]) {
+                serde_json::to_value(after_insert.lock().unwrap().remove(0)).unwrap()
+            } else {
+                serde_json::Value::Null
+            }
+        }
+    });
+    after_insert.lock().unwrap().extend([
+        vec![
+            entry(11, "/music/song.mp3", true),
+            entry(12, "https://example.com/broken", false),
+            entry(1, "/cache/video.mp4", false),
+            entry(13, "https://example.com/video", false),
+        ],
+        // Someone moved the entry while it was downloading.
+        vec![
+            entry(11, "/music/song.mp3", true),
+            entry(14, "https://example.com/later", false),
+            entry(2, "/cache/later.mp4", false),
+        ],
+    ]);
+
+    let history = History::new(
+        &dir.join("history.jsonl"),
+        playlist.clone(),
+        metadata.clone(),
+    );
+    let subtitles = Subtitles::new(&dir.join("uploads"), ipc.clone(), playlist.clone());
+    std::fs::create_dir_all(subtitles.dir()).unwrap();
+    let subtitle = subtitles.dir().join("video.srt");
+    std::fs::write(&subtitle, b"1").unwrap();
+    subtitles.attach(13, &subtitle).await.unwrap();
+
+    let music = dir.join("music");
+    std::fs::create_dir_all(&music).unwrap();
+    let autoplay = crate::autoplay::Autoplay::new(
+        None,
+        ipc.clone(),
+        history.clone(),
+        crate::library::Library::new(std::slice::from_ref(&music)).unwrap(),
+        ytdlp.clone(),
+    );
+
+    let prefetcher = Prefetcher::new(
+        ytdlp,
+        cache_dir.clone(),
+        2,
+        1024,
+        ipc,
+        playlist.clone(),
+        history.clone(),
+        metadata.clone(),
+        subtitles.clone(),
+        autoplay,
+        Retention::new(
+            crate::retention::RetentionPolicy::Forever,
+            dir.join("uploads"),
+            playlist,
+        ),
+    );
+
+    prefetcher.prefetch(&mut HashSet::new()).await;
+
+    // The broken entry is left to be streamed, the other one is replaced in place.
+    let loadfile = commands.recv().await.unwrap();
+    assert_eq!(loadfile[0], "loadfile");
+    assert!(loadfile[1]
+        .as_str()
+        .unwrap()
+        .starts_with(&cache_dir.to_string_lossy().to_string()));
+    assert_eq!(loadfile[2], "insert-at");
+    assert_eq!(loadfile[3], "2");
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["get_property", "playlist"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["playlist-remove", "3"])
+    );
+    assert!(commands.try_recv().is_err());
+
+    // The copy is exported with the URL it was queued with, and keeps its subtitles.
+    let swapped = [entry(1, loadfile[1].as_str().unwrap(), false)];
+    assert_eq!(
+        crate::playlist_file::from_playlist(
+            &swapped,
+            &Default::default(),
+            &history.original_urls()
+        )[0]
+        .url,
+        "https://example.com/video"
+    );
+    subtitles.update(&swapped).await;
+    assert!(subtitle.exists());
+
+    // The downloaded copy is taken out again instead of removing the wrong entry.
+    let later = cache_dir.join("later.mp4");
+    assert!(
+        !prefetcher
+            .swap(14, "https://example.com/later", &later)
+            .await
+    );
+    assert_eq!(commands.recv().await.unwrap()[3], "3");
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["get_property", "playlist"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["playlist-remove", "2"])
+    );
+
+    assert!(!stale.exists());
+    assert_eq!(
+        std::fs::read_to_string(loadfile[1].as_str().unwrap()).unwrap(),
+        "video\n"
+    );
+}
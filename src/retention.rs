@@ -0,0 +1,502 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc::UnboundedReceiver, RwLock};
+
+use crate::mpv::{event::EndFile, response::PlaylistEntry};
+
+/// How often uploads are checked against age and size limits.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Unfinished chunked uploads older than this are considered abandoned.
+const PARTIAL_UPLOAD_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    Forever,
+
+    /// Delete uploads once they're done playing.
+    AfterPlayed,
+
+    /// Delete uploads older than this.
+    MaxAge(Duration),
+
+    /// Delete the oldest uploads when they take up more than this many bytes.
+    MaxSize(u64),
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "forever" => Ok(RetentionPolicy::Forever),
+            None if s == "played" => Ok(RetentionPolicy::AfterPlayed),
+            Some(("hours", hours)) => hours
+                .parse::<u64>()
+                .map(|hours| RetentionPolicy::MaxAge(Duration::from_secs(hours * 60 * 60)))
+                .map_err(|e| format!("invalid number of hours: {e}")),
+            Some(("gb", gb)) => gb
+                .parse::<u64>()
+                .map(|gb| RetentionPolicy::MaxSize(gb * 1024 * 1024 * 1024))
+                .map_err(|e| format!("invalid number of GB: {e}")),
+            _ => Err("expected one of forever, played, hours=<N> or gb=<N>".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredUpload {
+    pub name: String,
+    pub size: u64,
+
+    /// Seconds since the Unix epoch.
+    pub modified: u64,
+
+    /// The upload is playing or waiting to be played.
+    pub queued: bool,
+}
+
+/// Names of the uploads that are playing or coming up in the playlist,
+/// which should never be deleted by a policy.
+fn queued_uploads(playlist: &[PlaylistEntry]) -> HashSet<String> {
+    let current = playlist
+        .iter()
+        .position(|entry| entry.current || entry.playing)
+        .unwrap_or(0);
+
+    playlist[current..]
+        .iter()
+        .filter_map(|entry| Path::new(&entry.filename).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect()
+}
+
+// A file or directory of a download, which is deleted as a whole.
+struct StoredDownload {
+    path: PathBuf,
+    size: u64,
+    age: Duration,
+}
+
+async fn list_downloads(dir: &Path) -> io::Result<Vec<StoredDownload>> {
+    let mut downloads = vec![];
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let meta = entry.metadata().await?;
+        let size = if meta.is_dir() {
+            crate::upload::dir_size(&entry.path()).await?
+        } else {
+            meta.len()
+        };
+
+        downloads.push(StoredDownload {
+            path: entry.path(),
+            size,
+            age: meta.modified()?.elapsed().unwrap_or_default(),
+        });
+    }
+
+    // Oldest first.
+    downloads.sort_by_key(|download| std::cmp::Reverse(download.age));
+
+    Ok(downloads)
+}
+
+/// Applies a retention policy to a directory of downloads, where each file or directory
+/// directly inside of it is a download. Those in `keep` are never deleted.
+pub async fn sweep_downloads(
+    policy: RetentionPolicy,
+    dir: &Path,
+    keep: &HashSet<PathBuf>,
+) -> io::Result<()> {
+    if policy == RetentionPolicy::Forever {
+        return Ok(());
+    }
+
+    let downloads = list_downloads(dir).await?;
+    let mut total: u64 = downloads.iter().map(|download| download.size).sum();
+
+    for download in downloads {
+        let expired = match policy {
+            RetentionPolicy::Forever => false,
+            RetentionPolicy::AfterPlayed => true,
+            RetentionPolicy::MaxAge(max_age) => download.age > max_age,
+            RetentionPolicy::MaxSize(max_size) => total > max_size,
+        };
+
+        if !expired || keep.contains(&download.path) {
+            continue;
+        }
+
+        log::info!("deleting download {}", download.path.display());
+        if download.path.is_dir() {
+            tokio::fs::remove_dir_all(&download.path).await?;
+        } else {
+            tokio::fs::remove_file(&download.path).await?;
+        }
+        total -= download.size;
+    }
+
+    Ok(())
+}
+
+/// Keeps track of the uploaded files and deletes them according to the retention policy.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    policy: RetentionPolicy,
+    upload_dir: PathBuf,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+
+    /// Playlist entry ID to the upload it plays.
+    entries: Arc<Mutex<HashMap<i64, PathBuf>>>,
+}
+
+impl Retention {
+    pub fn new(
+        policy: RetentionPolicy,
+        upload_dir: PathBuf,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    ) -> Self {
+        Retention {
+            policy,
+            upload_dir,
+            playlist,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Remembers that a playlist entry plays an upload.
+    pub fn track(&self, playlist_entry_id: i64, path: &Path) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(playlist_entry_id, path.to_path_buf());
+    }
+
+    /// Keeps tracking an upload when its entry is replaced by another one.
+    pub fn replaced(&self, old_id: i64, new_id: i64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(path) = entries.remove(&old_id) {
+            entries.insert(new_id, path);
+        }
+    }
+
+    /// Lists the uploaded files, oldest first.
+    pub async fn list(&self) -> io::Result<Vec<StoredUpload>> {
+        let mut queued = queued_uploads(&self.playlist.read().await);
+
+        // Uploads that were just enqueued may not be in our copy of the playlist yet.
+        queued.extend(
+            self.entries
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy().to_string()),
+        );
+
+        let mut uploads = vec![];
+        let mut read_dir = tokio::fs::read_dir(&self.upload_dir).await?;
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let meta = entry.metadata().await?;
+
+            if name.starts_with('.') || !meta.is_file() {
+                continue;
+            }
+
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+
+            uploads.push(StoredUpload {
+                queued: queued.contains(&name),
+                name,
+                size: meta.len(),
+                modified,
+            });
+        }
+
+        uploads.sort_by_key(|upload| upload.modified);
+
+        Ok(uploads)
+    }
+
+    /// Deletes an upload by its name in the upload directory.
+    pub async fn delete(&self, name: &str) -> io::Result<()> {
+        if name.starts_with('.') || Path::new(name).file_name() != Some(name.as_ref()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such upload"));
+        }
+
+        tokio::fs::remove_file(self.upload_dir.join(name)).await?;
+        log::info!("deleted upload {name}");
+
+        Ok(())
+    }
+
+    /// Deletes all the uploads that aren't queued. Returns how many were deleted.
+    pub async fn purge(&self) -> io::Result<usize> {
+        let mut deleted = 0;
+
+        for upload in self.list().await? {
+            if !upload.queued {
+                self.delete(&upload.name).await?;
+                deleted += 1;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    async fn remove_abandoned_partial_uploads(&self) -> io::Result<()> {
+        let partial_dir = self.upload_dir.join(crate::chunked_upload::PARTIAL_DIR);
+        let Ok(mut read_dir) = tokio::fs::read_dir(&partial_dir).await else {
+            return Ok(());
+        };
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let age = entry
+                .metadata()
+                .await?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+
+            if age > PARTIAL_UPLOAD_MAX_AGE {
+                log::info!(
+                    "deleting abandoned partial upload {}",
+                    entry.file_name().to_string_lossy()
+                );
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the age and size limits.
+    async fn sweep(&self) -> io::Result<()> {
+        self.remove_abandoned_partial_uploads().await?;
+
+        match self.policy {
+            RetentionPolicy::Forever | RetentionPolicy::AfterPlayed => {}
+            RetentionPolicy::MaxAge(max_age) => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+
+                for upload in self.list().await? {
+                    if !upload.queued
+                        && now.as_secs().saturating_sub(upload.modified) > max_age.as_secs()
+                    {
+                        self.delete(&upload.name).await?;
+                    }
+                }
+            }
+            RetentionPolicy::MaxSize(max_size) => {
+                let uploads = self.list().await?;
+                let mut total: u64 = uploads.iter().map(|upload| upload.size).sum();
+
+                for upload in uploads {
+                    if total <= max_size {
+                        break;
+                    }
+
+                    if !upload.queued {
+                        self.delete(&upload.name).await?;
+                        total -= upload.size;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Uploads left over from a previous run aren't in the playlist anymore,
+    /// so they will never be played and deleted.
+    /// `referenced` are the URLs that may still be enqueued later, like those of saved queues.
+    async fn startup_sweep(&self, referenced: &[String]) -> io::Result<()> {
+        if self.policy == RetentionPolicy::AfterPlayed {
+            let referenced: HashSet<_> = referenced
+                .iter()
+                .filter_map(|url| Path::new(url).file_name())
+                .map(|name| name.to_string_lossy().to_string())
+                .collect();
+
+            let mut deleted = 0;
+            for upload in self.list().await? {
+                if !upload.queued && !referenced.contains(&upload.name) {
+                    self.delete(&upload.name).await?;
+                    deleted += 1;
+                }
+            }
+
+            if deleted > 0 {
+                log::info!("deleted {deleted} orphaned uploads");
+            }
+        }
+
+        self.sweep().await
+    }
+
+    async fn handle_end_file(&self, end_file: EndFile) {
+        let Some(path) = self
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&end_file.playlist_entry_id)
+        else {
+            return;
+        };
+
+        if self.policy != RetentionPolicy::AfterPlayed
+            || !matches!(end_file.reason.as_str(), "eof" | "stop" | "error")
+        {
+            return;
+        }
+
+        // The same file may have been queued more than once.
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if queued_uploads(&self.playlist.read().await).contains(name.as_ref()) {
+            return;
+        }
+
+        if let Err(e) = self.delete(&name).await {
+            log::warn!("failed to delete played upload {name}: {e}");
+        }
+    }
+
+    pub async fn run(self, mut end_file_rx: UnboundedReceiver<EndFile>, referenced: Vec<String>) {
+        if let Err(e) = self.startup_sweep(&referenced).await {
+            log::warn!("failed to clean up the upload directory: {e}");
+        }
+
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                end_file = end_file_rx.recv() => match end_file {
+                    Some(end_file) => self.handle_end_file(end_file).await,
+                    None => break,
+                },
+
+                _ = interval.tick() => {
+                    if let Err(e) = self.sweep().await {
+                        log::warn!("failed to clean up the upload directory: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_retention_policy() {
+    assert_eq!("forever".parse(), Ok(RetentionPolicy::Forever));
+    assert_eq!("played".parse(), Ok(RetentionPolicy::AfterPlayed));
+    assert_eq!(
+        "hours=12".parse(),
+        Ok(RetentionPolicy::MaxAge(Duration::from_secs(12 * 60 * 60)))
+    );
+    assert_eq!(
+        "gb=100".parse(),
+        Ok(RetentionPolicy::MaxSize(100 * 1024 * 1024 * 1024))
+    );
+    assert!("hours=soon".parse::<RetentionPolicy>().is_err());
+    assert!("sometimes".parse::<RetentionPolicy>().is_err());
+}
+
+#[tokio::test]
+async fn test_delete_played_uploads() {
+    let dir = crate::test_dir::TestDir::new();
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+
+    let playing = dir.join("playing.mp4");
+    let played = dir.join("played.mp4");
+    let orphan = dir.join("orphan.mp4");
+    let saved = dir.join("saved.mp4");
+    for path in [&playing, &played, &orphan, &saved] {
+        tokio::fs::write(path, b"upload").await.unwrap();
+    }
+
+    let playlist = Arc::new(RwLock::new(vec![PlaylistEntry {
+        id: 2,
+        filename: playing.to_string_lossy().to_string(),
+        title: None,
+        current: true,
+        playing: true,
+    }]));
+
+    let retention = Retention::new(RetentionPolicy::AfterPlayed, dir.to_path_buf(), playlist);
+    retention.track(1, &played);
+    retention.track(2, &playing);
+
+    let (end_file_tx, end_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::spawn(retention.run(end_file_rx, vec![saved.to_string_lossy().to_string()]));
+
+    for (playlist_entry_id, reason) in [(2, "redirect"), (1, "eof")] {
+        end_file_tx
+            .send(EndFile {
+                playlist_entry_id,
+                reason: reason.to_string(),
+                file_error: None,
+            })
+            .unwrap();
+    }
+    drop(end_file_tx);
+    task.await.unwrap();
+
+    assert!(playing.exists());
+    assert!(!played.exists());
+
+    // Uploads left over from a previous run are deleted unless they're in a saved queue.
+    assert!(!orphan.exists());
+    assert!(saved.exists());
+}
+
+#[tokio::test]
+async fn test_sweep_downloads() {
+    let dir = crate::test_dir::TestDir::new();
+    let movie = dir.join("Movie");
+    let album = dir.join("Album");
+    let single = dir.join("single.mp3");
+    tokio::fs::create_dir_all(&movie).await.unwrap();
+    tokio::fs::create_dir_all(&album).await.unwrap();
+    tokio::fs::write(movie.join("movie.mkv"), [0; 100])
+        .await
+        .unwrap();
+    tokio::fs::write(album.join("track.flac"), [0; 50])
+        .await
+        .unwrap();
+    tokio::fs::write(&single, [0; 10]).await.unwrap();
+
+    let keep = HashSet::from([album.clone()]);
+
+    // Directories count as a whole, and the ones in use are never deleted even to make room.
+    sweep_downloads(RetentionPolicy::MaxSize(60), &dir, &keep)
+        .await
+        .unwrap();
+    assert!(!movie.exists());
+    assert!(album.exists());
+
+    sweep_downloads(RetentionPolicy::AfterPlayed, &dir, &keep)
+        .await
+        .unwrap();
+    assert!(album.exists());
+    assert!(!movie.exists());
+    assert!(!single.exists());
+}
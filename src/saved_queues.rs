@@ -0,0 +1,213 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use tokio::{fs, sync::Mutex};
+
+use crate::playlist_file::Item;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid name, use letters, numbers, spaces, - and _")]
+    InvalidName,
+
+    #[error("no saved queue called {0}")]
+    NotFound(String),
+
+    #[error("saved queue {0} is corrupted: {1}")]
+    Corrupted(String, serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedQueueInfo {
+    pub name: String,
+    pub entries: usize,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+}
+
+/// Named lists of entries that can be loaded into the queue,
+/// each saved as `<name>.json` in the same format as exported JSON playlists.
+#[derive(Debug, Clone)]
+pub struct SavedQueues {
+    dir: PathBuf,
+
+    /// Held while writing so that concurrent appends don't lose entries.
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl SavedQueues {
+    pub fn new(dir: &Path) -> Self {
+        SavedQueues {
+            dir: dir.to_path_buf(),
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+
+        Ok(self.dir.join(format!("{name}.json")))
+    }
+
+    pub async fn list(&self) -> Result<Vec<SavedQueueInfo>, Error> {
+        let mut queues = vec![];
+
+        let mut read_dir = match fs::read_dir(&self.dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(queues),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".json"))
+                .filter(|name| is_valid_name(name))
+            else {
+                continue;
+            };
+
+            match self.get(name).await {
+                Ok(items) => queues.push(SavedQueueInfo {
+                    name: name.to_string(),
+                    entries: items.len(),
+                }),
+                Err(e) => log::warn!("{e}"),
+            }
+        }
+
+        queues.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(queues)
+    }
+
+    /// The URLs of the entries of every saved queue.
+    pub async fn urls(&self) -> Result<Vec<String>, Error> {
+        let mut urls = vec![];
+
+        for queue in self.list().await? {
+            match self.get(&queue.name).await {
+                Ok(items) => urls.extend(items.into_iter().map(|item| item.url)),
+                Err(e) => log::warn!("{e}"),
+            }
+        }
+
+        Ok(urls)
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Vec<Item>, Error> {
+        let content = match fs::read(self.path(name)?).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::NotFound(name.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        serde_json::from_slice(&content).map_err(|e| Error::Corrupted(name.to_string(), e))
+    }
+
+    /// Writes to a temporary file first so that a crash never leaves a half-written queue.
+    async fn write(&self, name: &str, items: &[Item]) -> Result<(), Error> {
+        let path = self.path(name)?;
+        let tmp_path = self.dir.join(format!(".{name}.json.tmp"));
+
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(
+            &tmp_path,
+            serde_json::to_vec_pretty(items).map_err(io::Error::from)?,
+        )
+        .await?;
+        fs::rename(&tmp_path, &path).await?;
+
+        Ok(())
+    }
+
+    /// Creates a saved queue, or replaces it if it already exists.
+    pub async fn put(&self, name: &str, items: &[Item]) -> Result<(), Error> {
+        let _guard = self.write_lock.lock().await;
+
+        self.write(name, items).await
+    }
+
+    /// Adds an entry to the end of a saved queue, creating it if it doesn't exist.
+    pub async fn append(&self, name: &str, item: Item) -> Result<(), Error> {
+        let _guard = self.write_lock.lock().await;
+
+        let mut items = match self.get(name).await {
+            Ok(items) => items,
+            Err(Error::NotFound(_)) => vec![],
+            Err(e) => return Err(e),
+        };
+        items.push(item);
+
+        self.write(name, &items).await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), Error> {
+        let _guard = self.write_lock.lock().await;
+
+        match fs::remove_file(self.path(name)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::NotFound(name.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_saved_queues() {
+    let dir = crate::test_dir::TestDir::new();
+    let queues = SavedQueues::new(&dir);
+
+    let item = |url: &str| Item {
+        url: url.to_string(),
+        title: None,
+        duration: None,
+    };
+
+    assert!(queues.list().await.unwrap().is_empty());
+    assert!(matches!(
+        queues.put("../escape", &[]).await,
+        Err(Error::InvalidName)
+    ));
+
+    queues.put("late night", &[item("a.mp4")]).await.unwrap();
+    queues.append("late night", item("b.mp4")).await.unwrap();
+    queues.append("warmup", item("c.mp4")).await.unwrap();
+
+    assert_eq!(
+        queues.get("late night").await.unwrap(),
+        vec![item("a.mp4"), item("b.mp4")]
+    );
+
+    let list = queues.list().await.unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, "late night");
+    assert_eq!(list[0].entries, 2);
+
+    queues.delete("warmup").await.unwrap();
+    assert!(matches!(
+        queues.get("warmup").await,
+        Err(Error::NotFound(_))
+    ));
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{mpsc::UnboundedReceiver, Mutex, Notify, RwLock},
+};
+
+use crate::mpv::{
+    event::{FileLoaded, StartFile},
+    response::PlaylistEntry,
+    Client, LoadFileOptions,
+};
+
+/// Longest the runner sleeps without looking at the clock, in case the system clock jumps.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Items that were due while kameloso wasn't running are dropped if they're later than this,
+/// the countdown video isn't much use an hour after midnight.
+const MAX_LATENESS: u64 = 5 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no scheduled item with id {0}")]
+    NotFound(u64),
+
+    #[error("unknown mode {0}, use interrupt or insert-next")]
+    UnknownMode(String),
+
+    #[error("jingles have to play at least every track")]
+    InvalidInterval,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Stop what's playing, play the item and then resume from where it was stopped.
+    Interrupt,
+
+    /// Play the item after the current entry.
+    InsertNext,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interrupt" => Ok(Mode::Interrupt),
+            "insert-next" => Ok(Mode::InsertNext),
+            _ => Err(Error::UnknownMode(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledItem {
+    pub id: u64,
+    pub url: String,
+    pub mode: Mode,
+
+    /// Seconds since the Unix epoch.
+    pub fires_at: u64,
+}
+
+/// An entry that's inserted after every few tracks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jingle {
+    pub url: String,
+    pub every: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    pub items: Vec<ScheduledItem>,
+
+    #[serde(default)]
+    pub jingle: Option<Jingle>,
+}
+
+/// What the runner keeps track of between events.
+#[derive(Debug, Default)]
+struct RunState {
+    tracks_since_jingle: usize,
+
+    /// Entries inserted by the scheduler, which don't count towards the jingle.
+    inserted: HashSet<i64>,
+
+    /// The copy of an interrupted entry and the position to resume it from.
+    resume: Option<(i64, f64)>,
+
+    /// The entry that started last, for seeking once it's loaded.
+    starting: Option<i64>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Entries that play at a given time and jingles that play every few tracks,
+/// saved to a JSON file so that they survive a restart.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    path: PathBuf,
+    ipc: Client,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    schedule: Arc<Mutex<Schedule>>,
+    changed: Arc<Notify>,
+}
+
+impl Scheduler {
+    pub fn load(
+        path: &Path,
+        ipc: Client,
+        playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    ) -> io::Result<Self> {
+        let schedule = match std::fs::read(path) {
+            Ok(schedule) => serde_json::from_slice(&schedule).unwrap_or_else(|e| {
+                log::warn!("schedule is corrupted, starting over: {e}");
+                Schedule::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Schedule::default(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Scheduler {
+            path: path.to_path_buf(),
+            ipc,
+            playlist,
+            schedule: Arc::new(Mutex::new(schedule)),
+            changed: Arc::new(Notify::new()),
+        })
+    }
+
+    async fn save(&self, schedule: &Schedule) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(schedule)?).await?;
+        fs::rename(&tmp_path, &self.path).await
+    }
+
+    pub async fn schedule(&self) -> Schedule {
+        self.schedule.lock().await.clone()
+    }
+
+    /// The URLs of the scheduled items and the jingle.
+    pub async fn urls(&self) -> Vec<String> {
+        let schedule = self.schedule.lock().await;
+
+        schedule
+            .items
+            .iter()
+            .map(|item| item.url.clone())
+            .chain(schedule.jingle.iter().map(|jingle| jingle.url.clone()))
+            .collect()
+    }
+
+    pub async fn add(&self, url: &str, mode: Mode, fires_at: u64) -> Result<ScheduledItem, Error> {
+        let mut schedule = self.schedule.lock().await;
+
+        let item = ScheduledItem {
+            id: schedule
+                .items
+                .iter()
+                .map(|item| item.id + 1)
+                .max()
+                .unwrap_or(1),
+            url: url.to_string(),
+            mode,
+            fires_at,
+        };
+        schedule.items.push(item.clone());
+        schedule.items.sort_by_key(|item| item.fires_at);
+        self.save(&schedule).await?;
+        self.changed.notify_one();
+
+        Ok(item)
+    }
+
+    pub async fn remove(&self, id: u64) -> Result<(), Error> {
+        let mut schedule = self.schedule.lock().await;
+
+        let Some(index) = schedule.items.iter().position(|item| item.id == id) else {
+            return Err(Error::NotFound(id));
+        };
+        schedule.items.remove(index);
+        self.save(&schedule).await?;
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    /// Sets the jingle, or stops playing it when `None`.
+    pub async fn set_jingle(&self, jingle: Option<Jingle>) -> Result<(), Error> {
+        if jingle.as_ref().is_some_and(|jingle| jingle.every == 0) {
+            return Err(Error::InvalidInterval);
+        }
+
+        let mut schedule = self.schedule.lock().await;
+        schedule.jingle = jingle;
+        self.save(&schedule).await?;
+
+        Ok(())
+    }
+
+    /// Removes the items that are due and returns them.
+    async fn take_due(&self, now: u64) -> Vec<ScheduledItem> {
+        let mut schedule = self.schedule.lock().await;
+
+        let (due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut schedule.items)
+            .into_iter()
+            .partition(|item| item.fires_at <= now);
+        schedule.items = later;
+
+        if !due.is_empty() {
+            if let Err(e) = self.save(&schedule).await {
+                log::warn!("failed to save the schedule: {e}");
+            }
+        }
+
+        due
+    }
+
+    async fn fire(&self, item: ScheduledItem, state: &mut RunState, now: u64) {
+        if now - item.fires_at > MAX_LATENESS {
+            log::warn!("skipping {}, it was scheduled too long ago", item.url);
+            return;
+        }
+
+        log::info!("playing scheduled entry {}", item.url);
+
+        let playing = self
+            .playlist
+            .read()
+            .await
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| entry.playing)
+            .map(|(index, entry)| (index, entry.clone()));
+
+        let (Mode::Interrupt, Some((index, playing))) = (item.mode, playing) else {
+            match self
+                .ipc
+                .load_file(&item.url, &LoadFileOptions::InsertNextPlay)
+                .await
+            {
+                Ok(loaded) => {
+                    state.inserted.insert(loaded.playlist_entry_id);
+                }
+                Err(e) => log::warn!("failed to enqueue {}: {e}", item.url),
+            }
+            return;
+        };
+
+        let position = self.ipc.get_time_pos_ms().await.unwrap_or(0.0);
+
+        match self
+            .ipc
+            .load_file(&item.url, &LoadFileOptions::InsertNext)
+            .await
+        {
+            Ok(loaded) => {
+                state.inserted.insert(loaded.playlist_entry_id);
+            }
+            Err(e) => {
+                log::warn!("failed to enqueue {}: {e}", item.url);
+                return;
+            }
+        }
+
+        // A copy of the current entry goes after the interruption, so that it plays again once
+        // the interruption is over and the original can be left to end like a skipped entry.
+        match self
+            .ipc
+            .load_file(
+                &playing.filename,
+                &LoadFileOptions::InsertAt(index as u64 + 2),
+            )
+            .await
+        {
+            Ok(resume) => {
+                state.inserted.insert(resume.playlist_entry_id);
+                state.resume = Some((resume.playlist_entry_id, position));
+            }
+            Err(e) => log::warn!("failed to enqueue {} again: {e}", playing.filename),
+        }
+
+        if let Err(e) = self.ipc.playlist_next().await {
+            log::warn!("failed to interrupt {}: {e}", playing.filename);
+        }
+    }
+
+    async fn started(&self, entry_id: i64, state: &mut RunState) {
+        state.starting = Some(entry_id);
+
+        if state.inserted.remove(&entry_id) {
+            return;
+        }
+
+        let Some(jingle) = self.schedule.lock().await.jingle.clone() else {
+            return;
+        };
+
+        state.tracks_since_jingle += 1;
+        if state.tracks_since_jingle < jingle.every {
+            return;
+        }
+        state.tracks_since_jingle = 0;
+
+        match self
+            .ipc
+            .load_file(&jingle.url, &LoadFileOptions::InsertNext)
+            .await
+        {
+            Ok(loaded) => {
+                state.inserted.insert(loaded.playlist_entry_id);
+            }
+            Err(e) => log::warn!("failed to enqueue the jingle {}: {e}", jingle.url),
+        }
+    }
+
+    async fn loaded(&self, state: &mut RunState) {
+        let Some((entry_id, position)) = state.resume else {
+            return;
+        };
+
+        if state.starting != Some(entry_id) {
+            return;
+        }
+        state.resume = None;
+
+        if let Err(e) = self.ipc.seek(position).await {
+            log::warn!("failed to resume the interrupted entry: {e}");
+        }
+    }
+
+    pub async fn run(
+        self,
+        mut start_file_rx: UnboundedReceiver<StartFile>,
+        mut file_loaded_rx: UnboundedReceiver<FileLoaded>,
+    ) {
+        let mut state = RunState::default();
+
+        loop {
+            let now = unix_now();
+            for item in self.take_due(now).await {
+                self.fire(item, &mut state, now).await;
+            }
+
+            let delay = match self.schedule.lock().await.items.first() {
+                Some(item) => Duration::from_secs(item.fires_at.saturating_sub(now)).min(MAX_SLEEP),
+                None => MAX_SLEEP,
+            };
+
+            tokio::select! {
+                // The copy of an interrupted entry has to be seen starting before it's loaded.
+                biased;
+
+                start_file = start_file_rx.recv() => match start_file {
+                    Some(StartFile { playlist_entry_id }) => {
+                        self.started(playlist_entry_id, &mut state).await
+                    }
+                    None => break,
+                },
+
+                file_loaded = file_loaded_rx.recv() => match file_loaded {
+                    Some(_) => self.loaded(&mut state).await,
+                    None => break,
+                },
+
+                _ = self.changed.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_scheduler() {
+    let dir = crate::test_dir::TestDir::new();
+    fs::create_dir_all(&dir).await.unwrap();
+    let path = dir.join("schedule.json");
+
+    let (ipc, mut commands) = Client::fake();
+    let playlist = Arc::new(RwLock::new(vec![PlaylistEntry {
+        id: 10,
+        filename: "/music/song.mp3".to_string(),
+        title: None,
+        current: true,
+        playing: true,
+    }]));
+
+    let scheduler = Scheduler::load(&path, ipc.clone(), playlist.clone()).unwrap();
+    let now = unix_now();
+    scheduler
+        .add("https://example.com/countdown", Mode::Interrupt, now - 1)
+        .await
+        .unwrap();
+    let later = scheduler
+        .add(
+            "https://example.com/fireworks",
+            Mode::InsertNext,
+            now + 3600,
+        )
+        .await
+        .unwrap();
+    scheduler
+        .add("https://example.com/missed", Mode::InsertNext, now - 3600)
+        .await
+        .unwrap();
+    scheduler
+        .set_jingle(Some(Jingle {
+            url: "/jingles/station-id.mp3".to_string(),
+            every: 2,
+        }))
+        .await
+        .unwrap();
+    assert!(matches!(
+        scheduler.remove(100).await,
+        Err(Error::NotFound(100))
+    ));
+
+    let (start_file_tx, start_file_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (file_loaded_tx, file_loaded_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(scheduler.clone().run(start_file_rx, file_loaded_rx));
+
+    // The missed item is dropped, the current entry is queued again after the interruption.
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["get_property", "time-pos/full"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "https://example.com/countdown", "insert-next"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "/music/song.mp3", "insert-at", "2"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["playlist-next"])
+    );
+
+    // The fired items are gone from the saved schedule.
+    let saved = Scheduler::load(&path, ipc, playlist).unwrap();
+    assert_eq!(saved.schedule().await.items, vec![later]);
+
+    // Neither the interruption nor the resumed entry count towards the jingle.
+    let start_file = |id| {
+        start_file_tx
+            .send(StartFile {
+                playlist_entry_id: id,
+            })
+            .unwrap()
+    };
+    start_file(1);
+    start_file(2);
+    file_loaded_tx.send(FileLoaded {}).unwrap();
+
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["seek", "0", "absolute"])
+    );
+
+    start_file(3);
+    start_file(4);
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "/jingles/station-id.mp3", "insert-next"])
+    );
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+
+use crate::ytdlp::{self, YtDlp};
+
+/// How many results are remembered so they can be enqueued by ID.
+const MAX_REMEMBERED_RESULTS: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown search provider: {0}")]
+    UnknownProvider(String),
+
+    #[error(transparent)]
+    YtDlp(#[from] ytdlp::Error),
+}
+
+/// A result as returned by a provider.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub url: String,
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+}
+
+/// A result as returned to the client, which can be enqueued by its `id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub id: String,
+    pub provider: String,
+    pub url: String,
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+}
+
+pub trait SearchProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn search<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Candidate>, Error>>;
+}
+
+/// Searches using one of yt-dlp's search prefixes, e.g. `ytsearch` or `scsearch`.
+pub struct YtDlpSearch {
+    name: String,
+    ytdlp: YtDlp,
+    prefix: String,
+    max_results: usize,
+}
+
+impl YtDlpSearch {
+    pub fn new(name: &str, ytdlp: YtDlp, prefix: &str, max_results: usize) -> Self {
+        YtDlpSearch {
+            name: name.to_string(),
+            ytdlp,
+            prefix: prefix.to_string(),
+            max_results,
+        }
+    }
+}
+
+impl SearchProvider for YtDlpSearch {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn search<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Candidate>, Error>> {
+        Box::pin(async move {
+            let url = format!("{}{}:{}", self.prefix, self.max_results, query);
+            let playlist = self.ytdlp.flat_playlist(&url, self.max_results).await?;
+
+            Ok(playlist
+                .entries
+                .into_iter()
+                .map(|entry| Candidate {
+                    url: entry.url,
+                    title: entry.title,
+                    duration: entry.duration,
+                })
+                .collect())
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct RememberedResults {
+    urls: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl RememberedResults {
+    fn insert(&mut self, id: String, url: String) {
+        if self.order.len() >= MAX_REMEMBERED_RESULTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.urls.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(id.clone());
+        self.urls.insert(id, url);
+    }
+}
+
+#[derive(Clone)]
+pub struct Search {
+    /// The first provider is the default one.
+    providers: Arc<Vec<Box<dyn SearchProvider>>>,
+    results: Arc<Mutex<RememberedResults>>,
+}
+
+impl std::fmt::Debug for Search {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Search")
+            .field(
+                "providers",
+                &self.providers.iter().map(|p| p.name()).collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Search {
+    pub fn new(providers: Vec<Box<dyn SearchProvider>>) -> Self {
+        Search {
+            providers: Arc::new(providers),
+            results: Arc::new(Mutex::new(RememberedResults::default())),
+        }
+    }
+
+    pub fn provider_names(&self) -> Vec<&str> {
+        self.providers.iter().map(|p| p.name()).collect()
+    }
+
+    pub async fn search(
+        &self,
+        query: &str,
+        provider: Option<&str>,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let provider = match provider {
+            None => self.providers.first(),
+            Some(name) => self.providers.iter().find(|p| p.name() == name),
+        }
+        .ok_or_else(|| Error::UnknownProvider(provider.unwrap_or_default().to_string()))?;
+
+        let candidates = provider.search(query).await?;
+
+        let mut results = self.results.lock().unwrap();
+
+        Ok(candidates
+            .into_iter()
+            .map(|candidate| {
+                let id = uuid::Uuid::new_v4().to_string();
+                results.insert(id.clone(), candidate.url.clone());
+
+                SearchResult {
+                    id,
+                    provider: provider.name().to_string(),
+                    url: candidate.url,
+                    title: candidate.title,
+                    duration: candidate.duration,
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the URL of a result returned by a previous search.
+    pub fn result_url(&self, id: &str) -> Option<String> {
+        self.results.lock().unwrap().urls.get(id).cloned()
+    }
+}
+
+#[tokio::test]
+async fn test_search_fake_provider() {
+    struct FakeProvider;
+
+    impl SearchProvider for FakeProvider {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn search<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Candidate>, Error>> {
+            Box::pin(async move {
+                Ok(vec![
+                    Candidate {
+                        url: format!("https://example.com/{query}/1"),
+                        title: Some("first".to_string()),
+                        duration: Some(12.0),
+                    },
+                    Candidate {
+                        url: format!("https://example.com/{query}/2"),
+                        title: None,
+                        duration: None,
+                    },
+                ])
+            })
+        }
+    }
+
+    let search = Search::new(vec![Box::new(FakeProvider)]);
+
+    let results = search.search("cats", None).await.unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].provider, "fake");
+    assert_eq!(results[0].title.as_deref(), Some("first"));
+    assert_ne!(results[0].id, results[1].id);
+
+    assert_eq!(
+        search.result_url(&results[1].id).as_deref(),
+        Some("https://example.com/cats/2")
+    );
+    assert_eq!(search.result_url("not-an-id"), None);
+
+    assert!(matches!(
+        search.search("cats", Some("nope")).await,
+        Err(Error::UnknownProvider(name)) if name == "nope"
+    ));
+}
@@ -1,17 +1,29 @@
-use futures::{StreamExt, TryStreamExt};
+use std::net::{IpAddr, SocketAddr};
+
+use futures::StreamExt;
 use serde::Serialize;
-use tokio::io::AsyncWriteExt;
 use warp::http::StatusCode;
 use warp::multipart::FormData;
-use warp::{reply, Buf};
+use warp::reply;
 
-use crate::qr;
 use crate::{
-    mpv::{Error as IpcError, LoadFileOptions},
+    announcements, aria2, chat, chunked_upload, history, library, library_index, loudness,
+    playlist_file, qr, saved_queues, scheduler, search, sleep_timer, upload, ytdlp,
+};
+use crate::{
+    mpv::{
+        response::{Chapter, LoadFile, TrackType},
+        Error as IpcError, LoadFileOptions,
+    },
     server_state::ServerState,
 };
 
-use self::request::EnqueueUrl;
+use self::request::{
+    Announce, ChatPost, EnqueueExistingUpload, EnqueueLibraryFolder, EnqueueLibraryPath,
+    EnqueueSearchResult, EnqueueUrl, LibraryPath, LibrarySearchQuery, LogsQuery, PreviewUrl,
+    ScheduleEntry, SearchQuery, SelectTrack, SetChapter, SetDelays, SetJingle, SetLoudness,
+    SetSleepTimer,
+};
 
 mod request {
     use serde::Deserialize;
@@ -22,6 +34,191 @@ mod request {
 
         #[serde(default)]
         pub next: bool,
+
+        /// Expand playlists and channels into one queue entry per video.
+        #[serde(default)]
+        pub expand: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct PreviewUrl {
+        pub url: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SearchQuery {
+        pub q: String,
+        pub provider: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct LibraryPath {
+        #[serde(default)]
+        pub path: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Announce {
+        pub text: String,
+
+        /// banner or fullscreen.
+        #[serde(default = "default_announce_style")]
+        pub style: String,
+
+        /// Seconds.
+        #[serde(default = "default_announce_duration")]
+        pub duration: f64,
+
+        #[serde(default)]
+        pub pause: bool,
+    }
+
+    fn default_announce_style() -> String {
+        "banner".to_string()
+    }
+
+    fn default_announce_duration() -> f64 {
+        10.0
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetSleepTimer {
+        /// after-current, minutes, at or fade.
+        pub mode: String,
+
+        /// For minutes and fade.
+        pub minutes: Option<f64>,
+
+        /// Clock time as HH:MM, for at.
+        pub at: Option<String>,
+
+        /// Minutes east of UTC of the clock time, the timezone kameloso runs in if not given.
+        pub utc_offset: Option<i64>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct ScheduleEntry {
+        pub url: String,
+
+        /// Clock time as HH:MM.
+        pub at: String,
+
+        /// Minutes east of UTC of the clock time, the timezone kameloso runs in if not given.
+        pub utc_offset: Option<i64>,
+
+        /// interrupt or insert-next.
+        #[serde(default = "default_schedule_mode")]
+        pub mode: String,
+    }
+
+    fn default_schedule_mode() -> String {
+        "insert-next".to_string()
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetJingle {
+        pub url: String,
+
+        /// Number of tracks between jingles.
+        pub every: usize,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetLoudness {
+        /// off, dynaudnorm or loudnorm.
+        pub mode: String,
+
+        #[serde(default)]
+        pub measure: bool,
+
+        /// LUFS, the current target is kept when missing.
+        pub target: Option<f64>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct ChatPost {
+        pub text: String,
+
+        /// Float the text up the screen for a moment instead of adding it to the chat.
+        #[serde(default)]
+        pub reaction: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct LogsQuery {
+        /// One of error, warn, info, debug or trace.
+        #[serde(default = "default_logs_level")]
+        pub level: String,
+
+        #[serde(default = "default_logs_limit")]
+        pub limit: usize,
+    }
+
+    fn default_logs_level() -> String {
+        "trace".to_string()
+    }
+
+    fn default_logs_limit() -> usize {
+        200
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct LibrarySearchQuery {
+        pub q: String,
+
+        #[serde(default = "default_library_search_limit")]
+        pub limit: usize,
+    }
+
+    fn default_library_search_limit() -> usize {
+        50
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct EnqueueLibraryPath {
+        pub path: String,
+
+        #[serde(default)]
+        pub next: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct EnqueueLibraryFolder {
+        pub path: String,
+
+        #[serde(default)]
+        pub shuffle: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct EnqueueExistingUpload {
+        /// Hex-encoded SHA-256 of the file that would be uploaded.
+        pub sha256: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SelectTrack {
+        /// Disables this type of track when missing.
+        pub id: Option<i64>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetDelays {
+        pub sub_delay: Option<f64>,
+        pub audio_delay: Option<f64>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetChapter {
+        pub index: i64,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct EnqueueSearchResult {
+        pub id: String,
+
+        #[serde(default)]
+        pub next: bool,
     }
 }
 
@@ -47,8 +244,8 @@ impl warp::Reply for ApiError {
     }
 }
 
-impl From<IpcError> for ApiError {
-    fn from(value: IpcError) -> Self {
+impl ApiError {
+    fn from_ipc_error(value: &IpcError) -> Self {
         // match value {
         //     mpv_ipc::IpcError::MpvError(_) => todo!(),
         //     mpv_ipc::IpcError::Transport(_) => todo!(),
@@ -63,6 +260,194 @@ impl From<IpcError> for ApiError {
     }
 }
 
+impl From<IpcError> for ApiError {
+    fn from(value: IpcError) -> Self {
+        ApiError::from_ipc_error(&value)
+    }
+}
+
+impl From<ytdlp::Error> for ApiError {
+    fn from(value: ytdlp::Error) -> Self {
+        let status = match value {
+            ytdlp::Error::Failed(_) => StatusCode::BAD_REQUEST,
+            ytdlp::Error::Spawn(_) | ytdlp::Error::InvalidOutput(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        ApiError {
+            status,
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<search::Error> for ApiError {
+    fn from(value: search::Error) -> Self {
+        match value {
+            search::Error::UnknownProvider(_) => ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: value.to_string(),
+            },
+            search::Error::YtDlp(e) => e.into(),
+        }
+    }
+}
+
+impl From<library::Error> for ApiError {
+    fn from(value: library::Error) -> Self {
+        let status = match value {
+            library::Error::NotFound(_) => StatusCode::NOT_FOUND,
+            library::Error::OutsideRoot(_) => StatusCode::FORBIDDEN,
+            library::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        ApiError {
+            status,
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<library_index::Error> for ApiError {
+    fn from(value: library_index::Error) -> Self {
+        match value {
+            library_index::Error::Library(e) => e.into(),
+            _ => ApiError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: value.to_string(),
+            },
+        }
+    }
+}
+
+impl From<playlist_file::Error> for ApiError {
+    fn from(value: playlist_file::Error) -> Self {
+        ApiError {
+            status: match value {
+                playlist_file::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            },
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<aria2::Error> for ApiError {
+    fn from(value: aria2::Error) -> Self {
+        ApiError {
+            status: match value {
+                aria2::Error::Rpc(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<announcements::Error> for ApiError {
+    fn from(value: announcements::Error) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<scheduler::Error> for ApiError {
+    fn from(value: scheduler::Error) -> Self {
+        ApiError {
+            status: match value {
+                scheduler::Error::NotFound(_) => StatusCode::NOT_FOUND,
+                scheduler::Error::UnknownMode(_) | scheduler::Error::InvalidInterval => {
+                    StatusCode::BAD_REQUEST
+                }
+                scheduler::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<loudness::Error> for ApiError {
+    fn from(value: loudness::Error) -> Self {
+        match value {
+            loudness::Error::Ipc(e) => e.into(),
+            _ => ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: value.to_string(),
+            },
+        }
+    }
+}
+
+impl From<chat::Error> for ApiError {
+    fn from(value: chat::Error) -> Self {
+        ApiError {
+            status: match value {
+                chat::Error::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::BAD_REQUEST,
+            },
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<saved_queues::Error> for ApiError {
+    fn from(value: saved_queues::Error) -> Self {
+        ApiError {
+            status: match value {
+                saved_queues::Error::InvalidName => StatusCode::BAD_REQUEST,
+                saved_queues::Error::NotFound(_) => StatusCode::NOT_FOUND,
+                saved_queues::Error::Corrupted(..) | saved_queues::Error::Io(_) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            },
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<chunked_upload::Error> for ApiError {
+    fn from(value: chunked_upload::Error) -> Self {
+        let status = match value {
+            chunked_upload::Error::NotFound(_) => StatusCode::NOT_FOUND,
+            chunked_upload::Error::Busy | chunked_upload::Error::OffsetMismatch { .. } => {
+                StatusCode::CONFLICT
+            }
+            chunked_upload::Error::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            chunked_upload::Error::ChecksumMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            chunked_upload::Error::InvalidFilename => StatusCode::BAD_REQUEST,
+            chunked_upload::Error::Upload(e) => return e.into(),
+            chunked_upload::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        ApiError {
+            status,
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<upload::Error> for ApiError {
+    fn from(value: upload::Error) -> Self {
+        let status = match value {
+            upload::Error::TooLarge { .. }
+            | upload::Error::UserQuotaExceeded { .. }
+            | upload::Error::TotalQuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            upload::Error::DiskFull => StatusCode::INSUFFICIENT_STORAGE,
+            upload::Error::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            upload::Error::Interrupted(_) => StatusCode::BAD_REQUEST,
+            upload::Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        ApiError {
+            status,
+            message: value.to_string(),
+        }
+    }
+}
+
 impl warp::reject::Reject for IpcError {}
 
 impl warp::Reply for IpcError {
@@ -72,8 +457,46 @@ impl warp::Reply for IpcError {
     }
 }
 
+/// Turns the errors returned by the endpoints into JSON responses.
+pub async fn handle_rejection(err: warp::Rejection) -> Result<reply::Response, warp::Rejection> {
+    use warp::Reply;
+
+    if let Some(e) = err.find::<ApiError>() {
+        return Ok(e.clone().into_response());
+    }
+
+    if let Some(e) = err.find::<IpcError>() {
+        return Ok(ApiError::from_ipc_error(e).into_response());
+    }
+
+    if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        return Ok(ApiError {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            message: "request body is too large".to_string(),
+        }
+        .into_response());
+    }
+
+    Err(err)
+}
+
+/// Adds a file to the playlist, remembering who queued it for the history.
+async fn enqueue(
+    state: &ServerState,
+    url: &str,
+    options: &LoadFileOptions,
+    queued_by: Option<IpAddr>,
+) -> Result<LoadFile, IpcError> {
+    let loaded = state.ipc.load_file(url, options).await?;
+    state.history.queued(loaded.playlist_entry_id, queued_by);
+    state.autoplay.guest_queued().await;
+
+    Ok(loaded)
+}
+
 pub async fn enqueue_url(
     enqueue_url: EnqueueUrl,
+    remote: Option<SocketAddr>,
     state: ServerState,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     if enqueue_url.url.trim().is_empty() {
@@ -84,13 +507,59 @@ pub async fn enqueue_url(
         .into());
     }
 
+    // Torrents are enqueued by the download manager once their files are ready.
+    if aria2::is_torrent_url(enqueue_url.url.trim()) {
+        let Some(torrents) = &state.torrents else {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: "torrents are disabled".to_string(),
+            }
+            .into());
+        };
+
+        torrents
+            .add(enqueue_url.url.trim(), remote.map(|addr| addr.ip()))
+            .await
+            .map_err(ApiError::from)?;
+
+        return Ok(warp::reply::with_status(
+            warp::reply::with_header(warp::reply(), "Location", "/"),
+            StatusCode::SEE_OTHER,
+        ));
+    }
+
     let options = if enqueue_url.next {
         LoadFileOptions::InsertNextPlay
     } else {
         LoadFileOptions::AppendPlay
     };
 
-    state.ipc.load_file(&enqueue_url.url, &options).await?;
+    if enqueue_url.expand {
+        let playlist = state
+            .ytdlp
+            .flat_playlist(&enqueue_url.url, state.max_playlist_entries)
+            .await
+            .map_err(ApiError::from)?;
+
+        let mut entries = playlist.entries;
+
+        // Each entry inserted next goes before the previous one, so insert them backwards.
+        if enqueue_url.next {
+            entries.reverse();
+        }
+
+        for entry in &entries {
+            enqueue(&state, &entry.url, &options, remote.map(|addr| addr.ip())).await?;
+        }
+    } else {
+        enqueue(
+            &state,
+            &enqueue_url.url,
+            &options,
+            remote.map(|addr| addr.ip()),
+        )
+        .await?;
+    }
 
     Ok(warp::reply::with_status(
         warp::reply::with_header(warp::reply(), "Location", "/"),
@@ -98,72 +567,1282 @@ pub async fn enqueue_url(
     ))
 }
 
-// TODO add error messages
-pub async fn upload_file(
-    mut form: FormData,
+pub async fn preview_url(
+    preview_url: PreviewUrl,
     state: ServerState,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    while let Some(Ok(p)) = form.next().await {
-        if p.name() != "file" {
-            return Err(warp::reject::reject());
+    if preview_url.url.trim().is_empty() {
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "attempted to preview empty url".to_string(),
         }
+        .into());
+    }
 
-        let filename = p.filename().ok_or(warp::reject::reject())?;
+    let playlist = state
+        .ytdlp
+        .flat_playlist(&preview_url.url, state.max_playlist_entries)
+        .await
+        .map_err(ApiError::from)?;
 
-        let filename = &state
-            .upload_dir
-            .join(format!("{}-{}", uuid::Uuid::new_v4(), filename));
+    Ok(warp::reply::json(&playlist))
+}
 
-        {
-            let mut out = tokio::fs::File::create(&filename)
-                .await
-                .map_err(|_| warp::reject::reject())?;
-
-            let mut stream = p.stream();
-            loop {
-                match stream
-                    .try_next()
-                    .await
-                    .map_err(|_| warp::reject::reject())?
-                {
-                    None => break,
-                    Some(chunk) => {
-                        out.write_all(chunk.chunk())
-                            .await
-                            .map_err(|_| warp::reject::reject())?;
-                    }
-                }
-            }
+pub async fn search(
+    query: SearchQuery,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if query.q.trim().is_empty() {
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "attempted to search for an empty query".to_string(),
         }
-
-        state
-            .ipc
-            .load_file(
-                filename.to_str().expect("invalid path"),
-                &LoadFileOptions::AppendPlay,
-            )
-            .await?;
+        .into());
     }
 
-    Ok(warp::reply::with_status(
-        warp::reply::with_header(warp::reply(), "Location", "/"),
-        StatusCode::SEE_OTHER,
-    ))
+    let results = state
+        .search
+        .search(query.q.trim(), query.provider.as_deref())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&results))
 }
 
-pub async fn get_playlist(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
-    let playlist = state.playlist.read().await;
+pub async fn search_providers(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.search.provider_names()))
+}
+
+pub async fn enqueue_search_result(
+    result: EnqueueSearchResult,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let url = state
+        .search
+        .result_url(&result.id)
+        .ok_or_else(|| ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: format!("unknown search result: {}", result.id),
+        })?;
 
-    Ok(warp::reply::json(&*playlist))
+    enqueue_url(
+        EnqueueUrl {
+            url,
+            next: result.next,
+            expand: false,
+        },
+        remote,
+        state,
+    )
+    .await
 }
 
-pub async fn playlist_next(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
-    state.ipc.playlist_next().await?;
+pub async fn list_library(
+    path: LibraryPath,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = state
+        .library
+        .list(&path.path)
+        .await
+        .map_err(ApiError::from)?;
 
-    Ok(warp::reply::with_status(
-        warp::reply::with_header(warp::reply(), "Location", "/"),
-        StatusCode::SEE_OTHER,
-    ))
+    Ok(warp::reply::json(&entries))
+}
+
+pub async fn search_library(
+    query: LibrarySearchQuery,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let index = state.library_index.as_ref().ok_or_else(|| ApiError {
+        status: StatusCode::NOT_FOUND,
+        message: "the library is not indexed".to_string(),
+    })?;
+
+    let results = index
+        .search(&query.q, query.limit.min(500))
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&results))
+}
+
+fn path_to_url(path: &std::path::Path) -> Result<&str, ApiError> {
+    path.to_str().ok_or_else(|| ApiError {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: format!("path is not valid UTF-8: {}", path.to_string_lossy()),
+    })
+}
+
+pub async fn enqueue_library_path(
+    enqueue_path: EnqueueLibraryPath,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = state
+        .library
+        .resolve(&enqueue_path.path)
+        .map_err(ApiError::from)?;
+
+    if !path.is_file() || !library::is_media_file(&path) {
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("not a media file: {}", enqueue_path.path),
+        }
+        .into());
+    }
+
+    let options = if enqueue_path.next {
+        LoadFileOptions::InsertNextPlay
+    } else {
+        LoadFileOptions::AppendPlay
+    };
+
+    enqueue(
+        &state,
+        path_to_url(&path)?,
+        &options,
+        remote.map(|addr| addr.ip()),
+    )
+    .await?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::with_header(warp::reply(), "Location", "/"),
+        StatusCode::SEE_OTHER,
+    ))
+}
+
+pub async fn enqueue_library_folder(
+    enqueue_folder: EnqueueLibraryFolder,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut files = state
+        .library
+        .media_files(&enqueue_folder.path)
+        .await
+        .map_err(ApiError::from)?;
+
+    if enqueue_folder.shuffle {
+        use rand::seq::SliceRandom;
+        files.shuffle(&mut rand::thread_rng());
+    }
+
+    for file in &files {
+        enqueue(
+            &state,
+            path_to_url(file)?,
+            &LoadFileOptions::AppendPlay,
+            remote.map(|addr| addr.ip()),
+        )
+        .await?;
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::with_header(warp::reply(), "Location", "/"),
+        StatusCode::SEE_OTHER,
+    ))
+}
+
+/// Keeps a single copy of files that are uploaded more than once.
+/// Returns the path of the file to enqueue, and whether it's the one that was just uploaded.
+async fn keep_unique_upload(
+    state: &ServerState,
+    path: std::path::PathBuf,
+    sha256: &str,
+) -> (std::path::PathBuf, bool) {
+    match state.upload_hashes.insert(sha256, &path).await {
+        Ok(existing) if existing != path => {
+            log::info!(
+                "{} was already uploaded as {}",
+                path.display(),
+                existing.display()
+            );
+            let _ = tokio::fs::remove_file(&path).await;
+            (existing, false)
+        }
+        Ok(_) => (path, true),
+        Err(e) => {
+            log::warn!("failed to update the upload hash index: {e}");
+            (path, true)
+        }
+    }
+}
+
+pub async fn upload_file(
+    mut form: FormData,
+    remote: Option<SocketAddr>,
+    content_length: Option<u64>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = remote.map(|addr| addr.ip());
+    let limits = &state.upload_limits;
+
+    // The content length of the whole form is an upper bound of the size of the files,
+    // so it's what gets reserved until they're saved.
+    let content_length = content_length.ok_or_else(|| ApiError {
+        status: StatusCode::LENGTH_REQUIRED,
+        message: "missing content length".to_string(),
+    })?;
+    let mut reservation = limits
+        .check(&state.upload_dir, user, content_length)
+        .await
+        .map_err(ApiError::from)?;
+
+    while let Some(part) = form.next().await {
+        let part = part.map_err(|e| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("invalid form data: {e}"),
+        })?;
+
+        if part.name() != "file" {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("unexpected form field: {}", part.name()),
+            }
+            .into());
+        }
+
+        let filename = upload::sanitize_filename(part.filename().ok_or_else(|| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "missing filename".to_string(),
+        })?);
+
+        let path = state
+            .upload_dir
+            .join(format!("{}-{}", uuid::Uuid::new_v4(), filename));
+
+        let saved =
+            match upload::save_stream(part.stream(), &path, &filename, limits.max_size).await {
+                Ok(saved) => saved,
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    return Err(ApiError::from(e).into());
+                }
+            };
+
+        let (path, is_new) = keep_unique_upload(&state, path, &saved.sha256).await;
+        if is_new {
+            reservation.record(saved.size);
+        }
+
+        let loaded = enqueue(
+            &state,
+            path_to_url(&path)?,
+            &LoadFileOptions::AppendPlay,
+            user,
+        )
+        .await?;
+        state.retention.track(loaded.playlist_entry_id, &path);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::with_header(warp::reply(), "Location", "/"),
+        StatusCode::SEE_OTHER,
+    ))
+}
+
+/// Enqueues a file that was already uploaded, so that clients can send the hash
+/// of a file before uploading it and skip the upload entirely.
+pub async fn enqueue_existing_upload(
+    existing: EnqueueExistingUpload,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = state
+        .upload_hashes
+        .find(&existing.sha256)
+        .await
+        .map_err(|e| ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: "no upload with this hash".to_string(),
+        })?;
+
+    let loaded = enqueue(
+        &state,
+        path_to_url(&path)?,
+        &LoadFileOptions::AppendPlay,
+        remote.map(|addr| addr.ip()),
+    )
+    .await?;
+    state.retention.track(loaded.playlist_entry_id, &path);
+
+    Ok(warp::reply::json(&serde_json::json!({
+        "filename": path.file_name().unwrap_or_default().to_string_lossy(),
+    })))
+}
+
+pub async fn create_chunked_upload(
+    mut new_upload: chunked_upload::NewUpload,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = remote.map(|addr| addr.ip());
+
+    let reservation = state
+        .upload_limits
+        .check(&state.upload_dir, user, new_upload.size)
+        .await
+        .map_err(ApiError::from)?;
+
+    new_upload.filename = upload::sanitize_filename(&new_upload.filename);
+
+    let status = state
+        .chunked_uploads
+        .create(new_upload, user, reservation)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&status),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn chunked_upload_status(
+    id: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let status = state
+        .chunked_uploads
+        .status(&id)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&status))
+}
+
+/// Appends a chunk to an upload, and enqueues it once the last chunk has been received and verified.
+pub async fn append_chunked_upload(
+    id: String,
+    offset: u64,
+    chunk: warp::hyper::body::Bytes,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let status = state
+        .chunked_uploads
+        .append(&id, offset, &chunk)
+        .await
+        .map_err(ApiError::from)?;
+
+    if status.complete {
+        let finished = state
+            .chunked_uploads
+            .finish(&id, &state.upload_dir)
+            .await
+            .map_err(ApiError::from)?;
+
+        let (path, is_new) = keep_unique_upload(&state, finished.path, &finished.sha256).await;
+        if let (true, Some(mut reservation)) = (is_new, finished.reservation) {
+            reservation.record(finished.size);
+        }
+
+        let loaded = enqueue(
+            &state,
+            path_to_url(&path)?,
+            &LoadFileOptions::AppendPlay,
+            finished.owner,
+        )
+        .await?;
+        state.retention.track(loaded.playlist_entry_id, &path);
+    }
+
+    Ok(warp::reply::json(&status))
+}
+
+pub async fn cancel_chunked_upload(
+    id: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state
+        .chunked_uploads
+        .cancel(&id)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Uploads a subtitle file for a playlist entry.
+pub async fn upload_subtitles(
+    playlist_entry_id: i64,
+    mut form: FormData,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if state
+        .subtitles
+        .entry_status(playlist_entry_id)
+        .await
+        .is_none()
+    {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: format!("no playlist entry with id {playlist_entry_id}"),
+        }
+        .into());
+    }
+
+    tokio::fs::create_dir_all(state.subtitles.dir())
+        .await
+        .map_err(upload::Error::from)
+        .map_err(ApiError::from)?;
+
+    while let Some(part) = form.next().await {
+        let part = part.map_err(|e| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("invalid form data: {e}"),
+        })?;
+
+        if part.name() != "file" {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("unexpected form field: {}", part.name()),
+            }
+            .into());
+        }
+
+        let filename = upload::sanitize_filename(part.filename().ok_or_else(|| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "missing filename".to_string(),
+        })?);
+
+        let path = state
+            .subtitles
+            .dir()
+            .join(format!("{}-{}", uuid::Uuid::new_v4(), filename));
+
+        let saved = upload::save_stream(
+            part.stream(),
+            &path,
+            &filename,
+            crate::server_hyper::MAX_SUBTITLE_SIZE,
+        )
+        .await
+        .and_then(|saved| match saved.kind {
+            upload::MediaKind::Subtitle => Ok(saved),
+            kind => Err(upload::Error::UnsupportedType(
+                format!("{kind:?}").to_lowercase(),
+            )),
+        });
+
+        if let Err(e) = saved {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(ApiError::from(e).into());
+        }
+
+        state.subtitles.attach(playlist_entry_id, &path).await?;
+    }
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn get_tracks(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let tracks = state.ipc.get_track_list().await?;
+
+    Ok(warp::reply::json(&tracks))
+}
+
+pub async fn select_track(
+    track_type: String,
+    select: SelectTrack,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match track_type.as_str() {
+        "video" => state.ipc.set_track(TrackType::Video, select.id).await?,
+        "audio" => state.ipc.set_track(TrackType::Audio, select.id).await?,
+        "sub" => state.ipc.set_track(TrackType::Sub, select.id).await?,
+        "secondary-sub" => state.ipc.set_secondary_sub_track(select.id).await?,
+        _ => return Err(warp::reject::not_found()),
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Delays {
+    /// Seconds.
+    pub sub_delay: f64,
+
+    /// Seconds.
+    pub audio_delay: f64,
+}
+
+pub async fn get_delays(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&Delays {
+        sub_delay: state.ipc.get_sub_delay().await?,
+        audio_delay: state.ipc.get_audio_delay().await?,
+    }))
+}
+
+pub async fn set_delays(
+    delays: SetDelays,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(delay) = delays.sub_delay {
+        state.ipc.set_sub_delay(delay).await?;
+    }
+
+    if let Some(delay) = delays.audio_delay {
+        state.ipc.set_audio_delay(delay).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Chapters {
+    /// Index of the current chapter, if the file has chapters and one is playing.
+    pub current: Option<i64>,
+    pub chapters: Vec<Chapter>,
+}
+
+pub async fn get_chapters(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let chapters = state.ipc.get_chapter_list().await?;
+
+    // The property is unavailable when the file has no chapters.
+    let current = if chapters.is_empty() {
+        None
+    } else {
+        state
+            .ipc
+            .get_chapter()
+            .await
+            .ok()
+            .filter(|&index| index >= 0)
+    };
+
+    Ok(warp::reply::json(&Chapters { current, chapters }))
+}
+
+pub async fn set_chapter(
+    chapter: SetChapter,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state.ipc.set_chapter(chapter.index).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lets a request through to the admin endpoints if it has the right bearer token,
+/// or if it comes from localhost when no token was configured.
+pub async fn authorize_admin(
+    remote: Option<SocketAddr>,
+    authorization: Option<String>,
+    state: ServerState,
+) -> Result<(), warp::Rejection> {
+    let authorized = match &state.admin_token {
+        Some(token) => authorization
+            .as_deref()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .is_some_and(|given| given == token),
+        None => remote.is_some_and(|addr| addr.ip().is_loopback()),
+    };
+
+    if !authorized {
+        return Err(ApiError {
+            status: StatusCode::FORBIDDEN,
+            message: "admin access required".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn retention_error(e: std::io::Error) -> ApiError {
+    ApiError {
+        status: if e.kind() == std::io::ErrorKind::NotFound {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        },
+        message: e.to_string(),
+    }
+}
+
+pub async fn list_uploads(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let uploads = state.retention.list().await.map_err(retention_error)?;
+
+    Ok(warp::reply::json(&uploads))
+}
+
+pub async fn delete_upload(
+    name: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Path segments aren't decoded by warp, and uploads often have spaces in their names.
+    let name = percent_encoding::percent_decode_str(&name).decode_utf8_lossy();
+
+    state
+        .retention
+        .delete(&name)
+        .await
+        .map_err(retention_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes every upload that isn't playing or queued.
+pub async fn purge_uploads(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let deleted = state.retention.purge().await.map_err(retention_error)?;
+
+    Ok(warp::reply::json(
+        &serde_json::json!({ "deleted": deleted }),
+    ))
+}
+
+pub async fn post_chat(
+    post: ChatPost,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let message = state
+        .chat
+        .post(&post.text, post.reaction, remote.map(|addr| addr.ip()))
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&message))
+}
+
+#[derive(Debug, Serialize)]
+struct Announced {
+    /// Number of announcements waiting to be shown, including this one.
+    queued: usize,
+}
+
+pub async fn announce(
+    announce: Announce,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let style = announce.style.parse().map_err(ApiError::from)?;
+    let announcement =
+        announcements::Announcement::new(&announce.text, style, announce.duration, announce.pause)
+            .map_err(ApiError::from)?;
+
+    let queued = state.announcements.announce(announcement);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Announced { queued }),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn pending_announcements(
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.announcements.pending()))
+}
+
+pub async fn get_sleep_timer(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.sleep_timer.status()))
+}
+
+fn parse_clock_time(time: &str) -> Option<(u32, u32)> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours = hours.parse().ok().filter(|hours| *hours < 24)?;
+    let minutes = minutes.parse().ok().filter(|minutes| *minutes < 60)?;
+    Some((hours, minutes))
+}
+
+pub async fn set_sleep_timer(
+    request: SetSleepTimer,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let bad_request = |message: &str| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: message.to_string(),
+    };
+
+    let minutes = || {
+        request
+            .minutes
+            .filter(|minutes| *minutes > 0.0 && *minutes <= 24.0 * 60.0)
+            .map(|minutes| std::time::Duration::from_secs_f64(minutes * 60.0))
+            .ok_or_else(|| bad_request("minutes must be between 0 and 1440"))
+    };
+
+    let mode = match request.mode.as_str() {
+        "after-current" => sleep_timer::Mode::AfterCurrent,
+        "minutes" => sleep_timer::Mode::After(minutes()?),
+        "fade" => sleep_timer::Mode::Fade(minutes()?),
+        "at" => {
+            let (hours, minutes) = request
+                .at
+                .as_deref()
+                .and_then(parse_clock_time)
+                .ok_or_else(|| bad_request("the time must be given as HH:MM"))?;
+
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let utc_offset = request
+                .utc_offset
+                .unwrap_or_else(|| sleep_timer::local_utc_offset(now));
+            let seconds = sleep_timer::seconds_until(hours, minutes, utc_offset, now);
+
+            sleep_timer::Mode::After(std::time::Duration::from_secs(seconds))
+        }
+        mode => return Err(bad_request(&format!("unknown sleep timer mode: {mode}")).into()),
+    };
+
+    Ok(warp::reply::json(&state.sleep_timer.set(mode).await))
+}
+
+pub async fn cancel_sleep_timer(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    if !state.sleep_timer.cancel().await {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: "no sleep timer is running".to_string(),
+        }
+        .into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_schedule(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.scheduler.schedule().await))
+}
+
+pub async fn add_scheduled_entry(
+    request: ScheduleEntry,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mode: scheduler::Mode = request.mode.parse().map_err(ApiError::from)?;
+    let (hours, minutes) = parse_clock_time(&request.at).ok_or_else(|| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: "the time must be given as HH:MM".to_string(),
+    })?;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let utc_offset = request
+        .utc_offset
+        .unwrap_or_else(|| sleep_timer::local_utc_offset(now));
+    let fires_at = now + sleep_timer::seconds_until(hours, minutes, utc_offset, now);
+
+    let item = state
+        .scheduler
+        .add(&request.url, mode, fires_at)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&item),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_scheduled_entry(
+    id: u64,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state.scheduler.remove(id).await.map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_jingle(
+    request: SetJingle,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state
+        .scheduler
+        .set_jingle(Some(scheduler::Jingle {
+            url: request.url,
+            every: request.every,
+        }))
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_jingle(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    state
+        .scheduler
+        .set_jingle(None)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_loudness(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.loudness.settings()))
+}
+
+pub async fn set_loudness(
+    request: SetLoudness,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let settings = loudness::Settings {
+        mode: request.mode.parse().map_err(ApiError::from)?,
+        measure: request.measure,
+        target: request
+            .target
+            .unwrap_or_else(|| state.loudness.settings().target),
+    };
+
+    state.loudness.set(settings).await.map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&settings))
+}
+
+/// Live stream of chat messages and other events as server-sent events.
+pub async fn events(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let stream = futures::stream::unfold(state.events.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let sse = warp::sse::Event::default()
+                        .event(event.name())
+                        .json_data(&event);
+                    return Some((sse, events));
+                }
+                // Clients that fall behind just miss some events.
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+/// Recent mpv log messages, optionally only the ones at a level or more severe.
+pub async fn get_logs(
+    query: LogsQuery,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let level = query
+        .level
+        .parse::<log::Level>()
+        .ok()
+        // Also accept mpv's own level names.
+        .or_else(|| {
+            ["fatal", "status", "v"]
+                .contains(&query.level.as_str())
+                .then(|| crate::mpv_log::severity(&query.level))
+        })
+        .ok_or_else(|| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("unknown log level: {}", query.level),
+        })?;
+
+    Ok(warp::reply::json(&state.mpv_log.lines(level, query.limit)))
+}
+
+/// Entries that failed to play in the last few minutes, and why.
+pub async fn list_entry_errors(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&state.entry_errors.list()))
+}
+
+/// Torrents that are still downloading or finished recently.
+pub async fn list_torrents(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let downloads = state
+        .torrents
+        .as_ref()
+        .map(|torrents| torrents.list())
+        .unwrap_or_default();
+
+    Ok(warp::reply::json(&downloads))
+}
+
+fn history_error(e: std::io::Error) -> ApiError {
+    ApiError {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        message: format!("failed to read the history: {e}"),
+    }
+}
+
+/// The history without the addresses of who queued each entry, which only admins can see.
+async fn public_history(state: &ServerState) -> Result<Vec<history::HistoryEntry>, ApiError> {
+    let mut entries = state.history.entries().await.map_err(history_error)?;
+    for entry in &mut entries {
+        entry.queued_by = None;
+    }
+
+    Ok(entries)
+}
+
+pub async fn get_history(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = public_history(&state).await?;
+
+    Ok(warp::reply::json(&entries))
+}
+
+/// Sends the whole history as a file to download, either in CSV or JSON.
+pub async fn export_history(
+    format: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = public_history(&state).await?;
+
+    let (body, content_type) = match format.as_str() {
+        "csv" => (history::to_csv(&entries), "text/csv; charset=utf-8"),
+        "json" => (
+            serde_json::to_string_pretty(&entries).map_err(|e| ApiError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message: e.to_string(),
+            })?,
+            "application/json",
+        ),
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(body, "Content-Type", content_type),
+        "Content-Disposition",
+        format!("attachment; filename=\"kameloso-history.{format}\""),
+    ))
+}
+
+pub async fn history_stats(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = state.history.entries().await.map_err(history_error)?;
+
+    Ok(warp::reply::json(&history::stats(&entries, 10)))
+}
+
+pub async fn export_playlist(
+    format: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let format: playlist_file::Format = format.parse().map_err(ApiError::from)?;
+
+    let items = playlist_file::from_playlist(
+        &state.playlist.read().await,
+        &*state.metadata.read().await,
+        &state.history.original_urls(),
+    );
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(
+            playlist_file::export(format, &items),
+            "Content-Type",
+            format.content_type(),
+        ),
+        "Content-Disposition",
+        format!("attachment; filename=\"kameloso.{}\"", format.extension()),
+    ))
+}
+
+/// Whether an entry of an uploaded or saved playlist can be enqueued.
+/// Guests can only queue URLs, uploads and files from the library, not any file on the computer.
+fn is_importable(state: &ServerState, url: &str) -> bool {
+    if crate::metadata::is_url(url) {
+        return true;
+    }
+
+    let Ok(path) = std::fs::canonicalize(url) else {
+        return false;
+    };
+
+    state.library.library_path(&path).is_some()
+        || path.parent().is_some_and(|parent| {
+            std::fs::canonicalize(&state.upload_dir).is_ok_and(|upload_dir| parent == upload_dir)
+        })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportResult {
+    pub enqueued: usize,
+
+    /// Entries that weren't URLs, uploads or files from the library.
+    pub skipped: usize,
+}
+
+pub async fn import_playlist(
+    mut form: FormData,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut result = ImportResult {
+        enqueued: 0,
+        skipped: 0,
+    };
+
+    while let Some(part) = form.next().await {
+        let part = part.map_err(|e| ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("invalid form data: {e}"),
+        })?;
+
+        if part.name() != "file" {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("unexpected form field: {}", part.name()),
+            }
+            .into());
+        }
+
+        let format = playlist_file::Format::from_path(std::path::Path::new(
+            part.filename().unwrap_or_default(),
+        ))
+        .map_err(ApiError::from)?;
+
+        let mut content = vec![];
+        let mut stream = part.stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| ApiError {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("invalid form data: {e}"),
+            })?;
+            content.extend_from_slice(warp::Buf::chunk(&chunk));
+        }
+
+        let items = playlist_file::import(format, &String::from_utf8_lossy(&content), None)
+            .map_err(ApiError::from)?;
+
+        for item in items {
+            if !is_importable(&state, &item.url) {
+                result.skipped += 1;
+                continue;
+            }
+
+            enqueue(
+                &state,
+                &item.url,
+                &LoadFileOptions::AppendPlay,
+                remote.map(|addr| addr.ip()),
+            )
+            .await?;
+            result.enqueued += 1;
+        }
+    }
+
+    Ok(warp::reply::json(&result))
+}
+
+fn decode_path_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+pub async fn list_saved_queues(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let queues = state.saved_queues.list().await.map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&queues))
+}
+
+pub async fn get_saved_queue(
+    name: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let items = state
+        .saved_queues
+        .get(&decode_path_segment(&name))
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&items))
+}
+
+pub async fn put_saved_queue(
+    name: String,
+    items: Vec<playlist_file::Item>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state
+        .saved_queues
+        .put(&decode_path_segment(&name), &items)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_saved_queue(
+    name: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    state
+        .saved_queues
+        .delete(&decode_path_segment(&name))
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Adds the entry that's playing right now to the end of a saved queue.
+pub async fn append_current_to_saved_queue(
+    name: String,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let item = {
+        let playlist = state.playlist.read().await;
+        let playing = playlist
+            .iter()
+            .find(|entry| entry.playing)
+            .ok_or_else(|| ApiError {
+                status: StatusCode::CONFLICT,
+                message: "nothing is playing".to_string(),
+            })?;
+
+        playlist_file::from_playlist(
+            std::slice::from_ref(playing),
+            &*state.metadata.read().await,
+            &state.history.original_urls(),
+        )
+        .remove(0)
+    };
+
+    state
+        .saved_queues
+        .append(&decode_path_segment(&name), item)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the whole playlist with a saved queue.
+pub async fn play_saved_queue(
+    name: String,
+    remote: Option<SocketAddr>,
+    state: ServerState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let items = state
+        .saved_queues
+        .get(&decode_path_segment(&name))
+        .await
+        .map_err(ApiError::from)?;
+
+    // Saved queues can be written by anyone, so they're checked like uploaded playlists.
+    let (items, skipped): (Vec<_>, Vec<_>) = items
+        .into_iter()
+        .partition(|item| is_importable(&state, &item.url));
+
+    if items.is_empty() {
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "nothing in the saved queue can be played".to_string(),
+        }
+        .into());
+    }
+
+    for (i, item) in items.iter().enumerate() {
+        let options = if i == 0 {
+            LoadFileOptions::Replace
+        } else {
+            LoadFileOptions::Append
+        };
+
+        enqueue(&state, &item.url, &options, remote.map(|addr| addr.ip())).await?;
+    }
+
+    Ok(warp::reply::json(&ImportResult {
+        enqueued: items.len(),
+        skipped: skipped.len(),
+    }))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub filename: String,
+    pub title: Option<String>,
+    pub current: bool,
+    pub playing: bool,
+    pub uploader: Option<String>,
+    pub duration: Option<f64>,
+    pub thumbnail: Option<String>,
+
+    /// Queued by autoplay, and removed as soon as someone queues something.
+    pub filler: bool,
+
+    /// Seconds until this entry starts playing.
+    /// Only known for upcoming entries when the duration of everything before them is known.
+    pub eta: Option<f64>,
+}
+
+/// Computes the ETA of each entry from the duration of the entries before it
+/// and the position in the one currently playing.
+fn queue_etas(
+    durations: &[Option<f64>],
+    playing: Option<usize>,
+    position: Option<f64>,
+) -> Vec<Option<f64>> {
+    let mut etas = vec![None; durations.len()];
+
+    let Some(playing) = playing else {
+        return etas;
+    };
+
+    let mut eta = match (durations[playing], position) {
+        (Some(duration), Some(position)) => Some((duration - position).max(0.0)),
+        _ => None,
+    };
+
+    for i in playing + 1..durations.len() {
+        etas[i] = eta;
+        eta = eta.zip(durations[i]).map(|(eta, duration)| eta + duration);
+    }
+
+    etas
+}
+
+pub async fn get_playlist(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    let (duration, position) =
+        futures::join!(state.ipc.get_duration_ms(), state.ipc.get_time_pos_ms());
+
+    let playlist = state.playlist.read().await;
+    let metadata = state.metadata.read().await;
+
+    let mut entries: Vec<QueueEntry> = playlist
+        .iter()
+        .map(|entry| {
+            let metadata = metadata.get(&entry.id).cloned().unwrap_or_default();
+
+            QueueEntry {
+                id: entry.id,
+                filename: entry.filename.clone(),
+                title: entry.title.clone().or(metadata.title),
+                current: entry.current,
+                playing: entry.playing,
+                uploader: metadata.uploader,
+                duration: metadata.duration,
+                thumbnail: metadata.thumbnail,
+                filler: state.autoplay.is_filler(entry.id),
+                eta: None,
+            }
+        })
+        .collect();
+
+    let playing = entries.iter().position(|entry| entry.playing);
+
+    // mpv knows better once the file is loaded
+    if let (Some(i), Ok(duration)) = (playing, duration) {
+        entries[i].duration = Some(duration);
+    }
+
+    let durations: Vec<_> = entries.iter().map(|entry| entry.duration).collect();
+
+    for (entry, eta) in entries
+        .iter_mut()
+        .zip(queue_etas(&durations, playing, position.ok()))
+    {
+        entry.eta = eta;
+    }
+
+    Ok(warp::reply::json(&entries))
+}
+
+pub async fn playlist_next(state: ServerState) -> Result<impl warp::Reply, warp::Rejection> {
+    state.ipc.playlist_next().await?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::with_header(warp::reply(), "Location", "/"),
+        StatusCode::SEE_OTHER,
+    ))
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -208,3 +1887,976 @@ pub async fn toggle_qr_code(state: ServerState) -> Result<impl warp::Reply, warp
         StatusCode::SEE_OTHER,
     ))
 }
+
+#[test]
+fn test_queue_etas() {
+    assert_eq!(
+        queue_etas(&[Some(100.0), Some(60.0), Some(30.0)], None, None),
+        [None, None, None]
+    );
+
+    assert_eq!(
+        queue_etas(
+            &[Some(100.0), Some(60.0), Some(30.0), Some(10.0)],
+            Some(1),
+            Some(15.0)
+        ),
+        [None, None, Some(45.0), Some(75.0)]
+    );
+
+    assert_eq!(
+        queue_etas(&[Some(60.0), None, Some(30.0)], Some(0), Some(20.0)),
+        [None, Some(40.0), None]
+    );
+}
+
+#[cfg(test)]
+fn multipart_upload(filename: &str, data: &[u8]) -> warp::test::RequestBuilder {
+    let mut body = format!(
+        "--BOUNDARY\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+        Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(b"\r\n--BOUNDARY--\r\n");
+
+    warp::test::request()
+        .method("POST")
+        .path("/api/upload")
+        .remote_addr("192.168.1.10:50000".parse().unwrap())
+        .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+        .header("content-length", body.len())
+        .body(body)
+}
+
+#[cfg(test)]
+fn fake_mp4(size: usize) -> Vec<u8> {
+    let mut data = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00mp42isom".to_vec();
+    data.resize(size, 0);
+    data
+}
+
+/// Number of uploaded files, not counting the hash index.
+#[cfg(test)]
+fn count_uploads(upload_dir: &std::path::Path) -> usize {
+    std::fs::read_dir(upload_dir)
+        .unwrap()
+        .filter(|entry| {
+            !entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')
+        })
+        .count()
+}
+
+/// Routes over a fresh server state in `dir`, and the commands they send to mpv.
+#[cfg(test)]
+fn test_routes(
+    dir: &std::path::Path,
+) -> (
+    impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone,
+    tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+) {
+    let (state, commands) = ServerState::for_tests(dir);
+    (crate::server_hyper::routes(state), commands)
+}
+
+/// A request from another device on the LAN.
+#[cfg(test)]
+fn guest_request(method: &str, path: &str) -> warp::test::RequestBuilder {
+    warp::test::request()
+        .method(method)
+        .path(path)
+        .remote_addr("192.168.1.20:50000".parse().unwrap())
+}
+
+/// A request from the machine kameloso runs on, which is an admin when there is no admin token.
+#[cfg(test)]
+fn admin_request(method: &str, path: &str) -> warp::test::RequestBuilder {
+    warp::test::request()
+        .method(method)
+        .path(path)
+        .remote_addr("127.0.0.1:50000".parse().unwrap())
+}
+
+#[tokio::test]
+async fn test_upload_malicious_filenames() {
+    let dir = crate::test_dir::TestDir::new();
+    let (routes, mut commands) = test_routes(&dir);
+    let upload_dir = dir.join("uploads");
+
+    for (i, filename) in ["../../evil.mp4", "..\\\\..\\\\evil.mp4", "/tmp/evil.mp4"]
+        .into_iter()
+        .enumerate()
+    {
+        let res = multipart_upload(filename, &fake_mp4(1000 + i))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER, "{filename}");
+
+        let command = commands.recv().await.unwrap();
+        let path = std::path::PathBuf::from(command[1].as_str().unwrap());
+        assert_eq!(path.parent().unwrap(), upload_dir);
+        assert!(path.to_string_lossy().ends_with("-evil.mp4"));
+    }
+
+    assert!(!dir.join("evil.mp4").exists());
+    assert_eq!(count_uploads(&upload_dir), 3);
+
+    let res = multipart_upload("evil.mp4", b"#!/bin/sh\nrm -rf ~\n")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("unsupported file type"));
+    assert_eq!(count_uploads(&upload_dir), 3);
+}
+
+#[tokio::test]
+async fn test_upload_oversized_bodies() {
+    let dir = crate::test_dir::TestDir::new();
+    let (mut state, _commands) = ServerState::for_tests(&dir);
+    state.upload_limits = upload::UploadLimits::new(100 * 1024, Some(150 * 1024), None, 0);
+    let upload_dir = state.upload_dir.clone();
+    let routes = crate::server_hyper::routes(state);
+
+    let res = multipart_upload("big.mp4", &fake_mp4(200 * 1024))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // The file is too large but the form is still small enough for warp to accept it.
+    let res = multipart_upload("big.mp4", &fake_mp4(100 * 1024 + 10))
+        .header("content-length", "1000")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(std::fs::read_dir(&upload_dir).unwrap().count(), 0);
+
+    let res = multipart_upload("ok.mp4", &fake_mp4(90 * 1024))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+    let res = multipart_upload("over-quota.mp4", &fake_mp4(90 * 1024))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("upload quota exceeded"));
+
+    let res = guest_request("POST", "/api/uploads")
+        .json(&serde_json::json!({ "filename": "big.mp4", "size": 1024 * 1024 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Chunks can't go past the announced size.
+    let res = guest_request("POST", "/api/uploads")
+        .json(&serde_json::json!({ "filename": "small.mp4", "size": 10 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    let res = guest_request(
+        "PATCH",
+        &format!("/api/uploads/{}", status["id"].as_str().unwrap()),
+    )
+    .header("upload-offset", "0")
+    .body(fake_mp4(20))
+    .reply(&routes)
+    .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_admin_uploads() {
+    let dir = crate::test_dir::TestDir::new();
+    let (mut state, _commands) = ServerState::for_tests(&dir);
+    let upload_dir = state.upload_dir.clone();
+    std::fs::write(upload_dir.join("a first.mp4"), b"first").unwrap();
+    std::fs::write(upload_dir.join("b-second.mp4"), b"second").unwrap();
+
+    // Without a token only localhost is allowed in.
+    let routes = crate::server_hyper::routes(state.clone());
+    for (method, path) in [
+        ("GET", "/api/admin/uploads"),
+        ("DELETE", "/api/admin/uploads/a%20first.mp4"),
+        ("POST", "/api/admin/uploads/purge"),
+    ] {
+        let res = guest_request(method, path).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method} {path}");
+    }
+    let res = admin_request("GET", "/api/admin/uploads")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // With a token, localhost needs it too.
+    state.admin_token = Some("hunter2".to_string());
+    let routes = crate::server_hyper::routes(state);
+    let res = admin_request("GET", "/api/admin/uploads")
+        .header("authorization", "Bearer hunter3")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = guest_request("GET", "/api/admin/uploads")
+        .header("authorization", "Bearer hunter2")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let uploads: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(uploads.as_array().unwrap().len(), 2);
+
+    for path in [
+        "/api/admin/uploads/..%2Fsecret",
+        "/api/admin/uploads/missing.mp4",
+    ] {
+        let res = guest_request("DELETE", path)
+            .header("authorization", "Bearer hunter2")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+
+    let res = guest_request("DELETE", "/api/admin/uploads/a%20first.mp4")
+        .header("authorization", "Bearer hunter2")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert!(!upload_dir.join("a first.mp4").exists());
+
+    let res = guest_request("POST", "/api/admin/uploads/purge")
+        .header("authorization", "Bearer hunter2")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["deleted"], 1);
+    assert_eq!(std::fs::read_dir(&upload_dir).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_duplicate_uploads() {
+    let dir = crate::test_dir::TestDir::new();
+    let (routes, mut commands) = test_routes(&dir);
+    let upload_dir = dir.join("uploads");
+    let data = fake_mp4(1000);
+
+    let res = multipart_upload("meme.mp4", &data).reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    let first = commands.recv().await.unwrap();
+
+    let res = multipart_upload("meme (1).mp4", &data).reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(commands.recv().await.unwrap()[1], first[1]);
+    assert_eq!(count_uploads(&upload_dir), 1);
+
+    let sha256 = format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(&data));
+    let res = guest_request("POST", "/api/uploads/existing")
+        .json(&serde_json::json!({ "sha256": sha256 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(commands.recv().await.unwrap()[1], first[1]);
+
+    let res = guest_request("POST", "/api/uploads/existing")
+        .json(&serde_json::json!({ "sha256": "0".repeat(64) }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_upload_subtitles() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, mut commands) = ServerState::for_tests(&dir);
+    *state.playlist.write().await = vec![crate::mpv::response::PlaylistEntry {
+        id: 1,
+        filename: "video.mkv".to_string(),
+        title: None,
+        current: true,
+        playing: true,
+    }];
+    let routes = crate::server_hyper::routes(state);
+
+    let srt = b"1\n00:00:01,000 --> 00:00:02,000\nhello\n";
+
+    let res = multipart_upload("video.srt", srt)
+        .path("/api/playlist/2/subtitles")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = multipart_upload("video.srt", &fake_mp4(1000))
+        .path("/api/playlist/1/subtitles")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let res = multipart_upload("video.srt", srt)
+        .path("/api/playlist/1/subtitles")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // The entry is playing, so the subtitles are added right away.
+    let command = commands.recv().await.unwrap();
+    assert_eq!(command[0], "sub-add");
+    assert!(command[1].as_str().unwrap().ends_with("-video.srt"));
+    assert_eq!(command[2], "select");
+}
+
+#[tokio::test]
+async fn test_track_controls() {
+    let dir = crate::test_dir::TestDir::new();
+    let (routes, mut commands) = test_routes(&dir);
+
+    let post = |path: &str, body: &'static str| {
+        guest_request("POST", path)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+    };
+
+    for (path, body, expected) in [
+        (
+            "/api/tracks/audio",
+            "id=2",
+            serde_json::json!(["set_property", "aid", "2"]),
+        ),
+        (
+            "/api/tracks/secondary-sub",
+            "",
+            serde_json::json!(["set_property", "secondary-sid", "no"]),
+        ),
+        (
+            "/api/delays",
+            "sub_delay=-0.5",
+            serde_json::json!(["set_property", "sub-delay", "-0.5"]),
+        ),
+        (
+            "/api/chapters",
+            "index=3",
+            serde_json::json!(["set_property", "chapter", "3"]),
+        ),
+    ] {
+        let res = post(path, body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT, "{path}");
+        assert_eq!(commands.recv().await.unwrap(), expected);
+    }
+
+    let res = post("/api/tracks/karaoke", "id=1").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    for (path, body) in [("/api/tracks/audio", "id=first"), ("/api/chapters", "")] {
+        let res = post(path, body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{path} {body}");
+    }
+    assert!(commands.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_history_export() {
+    let dir = crate::test_dir::TestDir::new();
+    let (routes, _commands) = test_routes(&dir);
+    std::fs::write(
+        dir.join("history.jsonl"),
+        "{\"timestamp\":1700000000,\"title\":\"song\",\"url\":\"https://example.com/song\",\
+        \"queued_by\":\"10.0.0.2\",\"watched\":180.0,\"end_reason\":\"eof\"}\n\
+        not json\n",
+    )
+    .unwrap();
+
+    let res = guest_request("GET", "/api/history/export/csv")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "text/csv; charset=utf-8");
+    assert_eq!(
+        std::str::from_utf8(res.body()).unwrap(),
+        "timestamp,title,url,watched,end_reason\n\
+        1700000000,song,https://example.com/song,180.0,eof\n"
+    );
+
+    for path in ["/api/history", "/api/history/export/json"] {
+        let res = guest_request("GET", path).reply(&routes).await;
+        let entries: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(entries[0]["title"], "song", "{path}");
+        assert!(entries[0].get("queued_by").is_none(), "{path}");
+    }
+
+    // The stats rank users by address, so they're only for admins.
+    let res = guest_request("GET", "/api/history/stats")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = admin_request("GET", "/api/history/stats")
+        .reply(&routes)
+        .await;
+    let stats: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(stats["plays"], 1);
+    assert_eq!(stats["top_queuers"][0]["name"], "10.0.0.2");
+
+    let res = guest_request("GET", "/api/history/export/xml")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_import_export_playlist() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, mut commands) = ServerState::for_tests(&dir);
+    *state.playlist.write().await = vec![crate::mpv::response::PlaylistEntry {
+        id: 1,
+        filename: "https://example.com/a.mp4".to_string(),
+        title: Some("A".to_string()),
+        current: true,
+        playing: true,
+    }];
+    let routes = crate::server_hyper::routes(state);
+
+    let res = guest_request("GET", "/api/playlist/export/json")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let items: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        items,
+        serde_json::json!([{ "url": "https://example.com/a.mp4", "title": "A", "duration": null }])
+    );
+
+    let res = multipart_upload(
+        "party.m3u8",
+        b"#EXTM3U\n#EXTINF:10,B\nhttps://example.com/b.mp4\n/etc/passwd\n../secret.mp4\n",
+    )
+    .path("/api/playlist/import")
+    .reply(&routes)
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let result: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(result["enqueued"], 1);
+    assert_eq!(result["skipped"], 2);
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "https://example.com/b.mp4", "append-play"])
+    );
+
+    let res = multipart_upload("party.pls", b"[playlist]\n")
+        .path("/api/playlist/import")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = guest_request("GET", "/api/playlist/export/pls")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_saved_queues() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, mut commands) = ServerState::for_tests(&dir);
+    let playlist = state.playlist.clone();
+    let routes = crate::server_hyper::routes(state);
+
+    let res = admin_request("POST", "/api/queues/late%20night/append-current")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    *playlist.write().await = vec![crate::mpv::response::PlaylistEntry {
+        id: 1,
+        filename: "https://example.com/a.mp4".to_string(),
+        title: Some("A".to_string()),
+        current: true,
+        playing: true,
+    }];
+
+    let items = serde_json::json!([
+        { "url": "https://example.com/b.mp4" },
+        { "url": "/etc/passwd" }
+    ]);
+    let res = admin_request("PUT", "/api/queues/late%20night")
+        .json(&items)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    // Guests can look at the saved queues, but not change or play them.
+    let res = guest_request("GET", "/api/queues/late%20night")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    for (method, path) in [
+        ("PUT", "/api/queues/late%20night"),
+        ("DELETE", "/api/queues/late%20night"),
+        ("POST", "/api/queues/late%20night/append-current"),
+        ("POST", "/api/queues/late%20night/play"),
+    ] {
+        let res = guest_request(method, path)
+            .json(&serde_json::json!([]))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method} {path}");
+    }
+    assert!(commands.try_recv().is_err());
+
+    let res = admin_request("POST", "/api/queues/late%20night/append-current")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = admin_request("POST", "/api/queues/late%20night/play")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let result: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(result, serde_json::json!({ "enqueued": 2, "skipped": 1 }));
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "https://example.com/b.mp4", "replace"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["loadfile", "https://example.com/a.mp4", "append"])
+    );
+
+    let res = admin_request("DELETE", "/api/queues/late%20night")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    for (method, path) in [
+        ("GET", "/api/queues/late%20night"),
+        ("DELETE", "/api/queues/late%20night"),
+        ("POST", "/api/queues/late%20night/play"),
+    ] {
+        let res = admin_request(method, path).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{method} {path}");
+    }
+
+    let res = admin_request("PUT", "/api/queues/..%2F..%2Fetc")
+        .json(&serde_json::json!([]))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_logs() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, _commands) = ServerState::for_tests(&dir);
+
+    let (log_tx, log_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(state.mpv_log.clone().run(log_rx));
+    for (level, text) in [
+        ("v", "opening file"),
+        ("error", "Failed to recognize file format."),
+    ] {
+        log_tx
+            .send(crate::mpv::event::LogMessage {
+                prefix: "demux".to_string(),
+                level: level.to_string(),
+                text: format!("{text}\n"),
+            })
+            .unwrap();
+    }
+    tokio::task::yield_now().await;
+
+    let routes = crate::server_hyper::routes(state);
+
+    // The logs can show the paths and URLs of everything that was queued.
+    let res = guest_request("GET", "/api/logs").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = admin_request("GET", "/api/logs?level=warn")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let lines: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        lines,
+        serde_json::json!([{
+            "timestamp": lines[0]["timestamp"],
+            "level": "error",
+            "prefix": "demux",
+            "text": "Failed to recognize file format.",
+        }])
+    );
+
+    let res = admin_request("GET", "/api/logs").reply(&routes).await;
+    let lines: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(lines[0]["level"], "v");
+    assert_eq!(lines[1]["level"], "error");
+    assert_eq!(lines.as_array().unwrap().len(), 2);
+
+    let res = admin_request("GET", "/api/logs?level=loud")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_chat() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, _commands) = ServerState::for_tests(&dir);
+    let mut events = state.events.subscribe();
+    let routes = crate::server_hyper::routes(state);
+
+    let post_chat = |body: &str| {
+        guest_request("POST", "/api/chat")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+    };
+
+    // Rejected messages don't count towards the rate limit.
+    for body in [
+        "text=+%0A",
+        &format!("text={}", "a".repeat(51)),
+        "reaction=true",
+    ] {
+        let res = post_chat(body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    let res = post_chat("text=what+the+heck").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let message: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(message["text"], "what the ****");
+
+    match events.recv().await.unwrap() {
+        crate::events::Event::Chat(message) => {
+            assert_eq!(message.text, "what the ****");
+            assert!(!message.reaction);
+        }
+        event => panic!("unexpected event {event:?}"),
+    }
+
+    let res = post_chat("text=too+long+to+be+a+reaction&reaction=true")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    for _ in 0..2 {
+        let res = post_chat("text=%F0%9F%94%A5&reaction=true")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let res = post_chat("text=one+more").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Huge messages are turned away before the body is read.
+    let res = post_chat(&format!("text={}", "a".repeat(1024 * 1024)))
+        .remote_addr("192.168.1.21:50000".parse().unwrap())
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Only the reactions went through after the first message.
+    for _ in 0..2 {
+        match events.recv().await.unwrap() {
+            crate::events::Event::Chat(message) => assert!(message.reaction),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_announce() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, _commands) = ServerState::for_tests(&dir);
+    let routes = crate::server_hyper::routes(state);
+
+    let announce = |body: &str| {
+        admin_request("POST", "/api/announce")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+    };
+
+    // Only admins can take over the screen, or see what's about to.
+    for method in ["POST", "GET"] {
+        let res = guest_request(method, "/api/announce")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("text=pizza+is+here")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method}");
+    }
+
+    for body in [
+        "text=pizza+is+here&style=marquee",
+        "text=+%0A",
+        "text=pizza+is+here&duration=0",
+        "style=banner",
+    ] {
+        let res = announce(body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    // Huge announcements are turned away before the body is read.
+    let res = announce(&format!("text={}", "a".repeat(1024 * 1024)))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    for queued in 1..=2 {
+        let res = announce("text=pizza+is+here&style=fullscreen&duration=30&pause=true")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["queued"], queued);
+    }
+
+    let res = admin_request("GET", "/api/announce").reply(&routes).await;
+    let pending: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(pending.as_array().unwrap().len(), 2);
+    assert_eq!(pending[1]["text"], "pizza is here");
+    assert_eq!(pending[1]["style"], "fullscreen");
+    assert_eq!(pending[1]["pause"], true);
+}
+
+#[tokio::test]
+async fn test_sleep_timer() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, _commands) = ServerState::for_tests(&dir);
+    let routes = crate::server_hyper::routes(state);
+
+    let request = |method: &str| {
+        admin_request(method, "/api/sleep")
+            .header("content-type", "application/x-www-form-urlencoded")
+    };
+
+    for method in ["GET", "POST", "DELETE"] {
+        let res = guest_request(method, "/api/sleep")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("mode=after-current")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method}");
+    }
+
+    for body in [
+        "mode=at&at=25:00",
+        "mode=at",
+        "mode=fade",
+        "mode=minutes&minutes=0",
+        "mode=nap&minutes=5",
+    ] {
+        let res = request("POST").body(body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    let res = request("POST")
+        .body(format!("mode=after-current&at={}", "0".repeat(1024 * 1024)))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let res = request("GET").reply(&routes).await;
+    assert_eq!(res.body(), "null");
+
+    // Without an offset the time is read in the timezone kameloso runs in.
+    for body in ["mode=at&at=23:30&utc_offset=60", "mode=at&at=23:30"] {
+        let res = request("POST").body(body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK, "{body}");
+        let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(status["mode"], "timer");
+        let in_a_day = sleep_timer::unix_now() + 24 * 60 * 60;
+        assert!(status["stops_at"].as_u64().unwrap() <= in_a_day, "{body}");
+    }
+
+    let res = request("GET").reply(&routes).await;
+    let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(status["mode"], "timer");
+
+    let res = request("DELETE").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = request("DELETE").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = request("GET").reply(&routes).await;
+    assert_eq!(res.body(), "null");
+}
+
+#[tokio::test]
+async fn test_schedule() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, _commands) = ServerState::for_tests(&dir);
+    let routes = crate::server_hyper::routes(state);
+
+    let request = |method: &str, path: &str| {
+        admin_request(method, path).header("content-type", "application/x-www-form-urlencoded")
+    };
+
+    for (method, path) in [
+        ("GET", "/api/schedule"),
+        ("POST", "/api/schedule"),
+        ("POST", "/api/schedule/jingle"),
+        ("DELETE", "/api/schedule/jingle"),
+        ("DELETE", "/api/schedule/1"),
+    ] {
+        let res = guest_request(method, path)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("url=https://example.com/countdown&at=23:59&every=5")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method} {path}");
+    }
+
+    for body in [
+        "url=https://example.com/countdown&at=24:00",
+        "url=https://example.com/countdown&at=noon",
+        "url=https://example.com/countdown&at=23:59&mode=later",
+        "url=https://example.com/countdown",
+    ] {
+        let res = request("POST", "/api/schedule")
+            .body(body)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    let res = request("POST", "/api/schedule")
+        .body(format!("at=23:59&url={}", "a".repeat(1024 * 1024)))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // The minute of the day an item fires at, in the timezone `utc_offset` minutes east of UTC.
+    let local_minute = |item: &serde_json::Value, utc_offset: i64| {
+        (item["fires_at"].as_u64().unwrap() as i64 / 60 + utc_offset).rem_euclid(24 * 60)
+    };
+
+    let res = request("POST", "/api/schedule")
+        .body("url=https://example.com/countdown&at=23:59&utc_offset=60&mode=interrupt")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let item: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(item["mode"], "interrupt");
+    assert_eq!(local_minute(&item, 60), 23 * 60 + 59);
+
+    // Without an offset the time is read in the timezone kameloso runs in.
+    let res = request("POST", "/api/schedule")
+        .body("url=https://example.com/midnight&at=00:00")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let midnight: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(midnight["mode"], "insert-next");
+    let utc_offset = sleep_timer::local_utc_offset(midnight["fires_at"].as_u64().unwrap());
+    assert_eq!(local_minute(&midnight, utc_offset), 0);
+
+    let res = request("POST", "/api/schedule/jingle")
+        .body("url=/jingles/station-id.mp3&every=0")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = request("POST", "/api/schedule/jingle")
+        .body("url=/jingles/station-id.mp3&every=5")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = request("GET", "/api/schedule").reply(&routes).await;
+    let schedule: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(schedule["items"].as_array().unwrap().len(), 2);
+    assert_eq!(schedule["jingle"]["every"], 5);
+
+    for item in [&item, &midnight] {
+        let path = format!("/api/schedule/{}", item["id"]);
+        let res = request("DELETE", &path).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = request("DELETE", &path).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    let res = request("DELETE", "/api/schedule/jingle")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = request("GET", "/api/schedule").reply(&routes).await;
+    assert_eq!(
+        res.body(),
+        &serde_json::to_vec(&serde_json::json!({ "items": [], "jingle": null })).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_loudness() {
+    let dir = crate::test_dir::TestDir::new();
+    let (state, mut commands) = ServerState::for_tests(&dir);
+    let routes = crate::server_hyper::routes(state);
+
+    let request = |method: &str| {
+        admin_request(method, "/api/loudness")
+            .header("content-type", "application/x-www-form-urlencoded")
+    };
+
+    for method in ["GET", "POST"] {
+        let res = guest_request(method, "/api/loudness")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("mode=off")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{method}");
+    }
+
+    for body in [
+        "mode=louder",
+        "mode=loudnorm&target=3",
+        "mode=loudnorm&target=loud",
+        "measure=true",
+    ] {
+        let res = request("POST").body(body).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    let res = request("POST")
+        .body(format!("mode=off&target={}", "0".repeat(1024 * 1024)))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(commands.try_recv().is_err());
+
+    let res = request("POST")
+        .body("mode=loudnorm&measure=true&target=-14")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["af", "remove", "@kameloso-normalize"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!([
+            "af",
+            "add",
+            "@kameloso-normalize:lavfi=[loudnorm=I=-14:TP=-1.5:LRA=11]"
+        ])
+    );
+
+    let res = request("GET").reply(&routes).await;
+    let settings: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        settings,
+        serde_json::json!({ "mode": "loudnorm", "measure": true, "target": -14.0 })
+    );
+}
@@ -9,24 +9,232 @@ fn with_arg<T: std::marker::Send + std::clone::Clone>(
     warp::any().map(move || t.clone())
 }
 
-const MAX_UPLOAD_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
 
-pub async fn start(addr: SocketAddr, state: ServerState) {
+pub const MAX_SUBTITLE_SIZE: u64 = 16 * 1024 * 1024;
+
+const MAX_PLAYLIST_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Room for the multipart headers on top of the size of the uploaded file.
+const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+
+/// Maximum size of the small forms sent to the admin endpoints.
+const MAX_FORM_SIZE: u64 = 16 * 1024;
+
+/// Room for the other fields of the chat form on top of the message.
+const CHAT_FORM_OVERHEAD: u64 = 1024;
+
+pub fn routes(
+    state: ServerState,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let enqueue = warp::path("enqueue")
         .and(warp::path::end())
         .and(warp::post())
         .and(warp::body::form())
+        .and(warp::addr::remote())
         .and(with_arg(state.clone()))
         .and_then(crate::server_endpoints::enqueue_url);
 
-    // TODO make upload size configurable
+    let preview = warp::path!("enqueue" / "preview")
+        .and(warp::get())
+        .and(warp::query())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::preview_url);
+
+    let search = warp::path("search").and(
+        warp::path::end()
+            .and(warp::get())
+            .and(warp::query())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::search)
+            .or(warp::path!("providers")
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::search_providers))
+            .or(warp::path!("enqueue")
+                .and(warp::post())
+                .and(warp::body::form())
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::enqueue_search_result)),
+    );
+
+    let library = warp::path("library").and(
+        warp::path::end()
+            .and(warp::get())
+            .and(warp::query())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::list_library)
+            .or(warp::path!("search")
+                .and(warp::get())
+                .and(warp::query())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::search_library))
+            .or(warp::path!("enqueue")
+                .and(warp::post())
+                .and(warp::body::form())
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::enqueue_library_path))
+            .or(warp::path!("enqueue-folder")
+                .and(warp::post())
+                .and(warp::body::form())
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::enqueue_library_folder)),
+    );
+
     let upload_file = warp::path("upload")
         .and(warp::path::end())
         .and(warp::post())
-        .and(warp::multipart::form().max_length(MAX_UPLOAD_SIZE))
+        .and(
+            warp::multipart::form().max_length(
+                state
+                    .upload_limits
+                    .max_size
+                    .saturating_add(MULTIPART_OVERHEAD),
+            ),
+        )
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<u64>("content-length"))
         .and(with_arg(state.clone()))
         .and_then(crate::server_endpoints::upload_file);
 
+    let chunked_upload = warp::path("uploads").and(
+        warp::path::end()
+            .and(warp::post())
+            .and(warp::body::content_length_limit(16 * 1024))
+            .and(warp::body::json())
+            .and(warp::addr::remote())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::create_chunked_upload)
+            .or(warp::path!("existing")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(16 * 1024))
+                .and(warp::body::json())
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::enqueue_existing_upload))
+            .or(warp::path!(String)
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::chunked_upload_status))
+            .or(warp::path!(String)
+                .and(warp::patch())
+                .and(warp::header::<u64>("upload-offset"))
+                .and(warp::body::content_length_limit(MAX_CHUNK_SIZE))
+                .and(warp::body::bytes())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::append_chunked_upload))
+            .or(warp::path!(String)
+                .and(warp::delete())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::cancel_chunked_upload)),
+    );
+
+    let admin = warp::addr::remote()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::authorize_admin)
+        .untuple_one();
+
+    let admin_uploads = warp::path!("admin" / "uploads" / ..)
+        .and(admin.clone())
+        .and(
+            warp::path::end()
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::list_uploads)
+                .or(warp::path!("purge")
+                    .and(warp::post())
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::purge_uploads))
+                .or(warp::path!(String)
+                    .and(warp::delete())
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::delete_upload)),
+        );
+
+    let logs = warp::path!("logs")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(warp::query())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::get_logs);
+
+    let sleep = warp::path("sleep")
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(
+            warp::get()
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::get_sleep_timer)
+                .or(warp::post()
+                    .and(warp::body::content_length_limit(MAX_FORM_SIZE))
+                    .and(warp::body::form())
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::set_sleep_timer))
+                .or(warp::delete()
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::cancel_sleep_timer)),
+        );
+
+    let schedule = warp::path("schedule").and(admin.clone()).and(
+        warp::path::end()
+            .and(
+                warp::get()
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::get_schedule)
+                    .or(warp::post()
+                        .and(warp::body::content_length_limit(MAX_FORM_SIZE))
+                        .and(warp::body::form())
+                        .and(with_arg(state.clone()))
+                        .and_then(crate::server_endpoints::add_scheduled_entry)),
+            )
+            .or(warp::path!("jingle").and(
+                warp::post()
+                    .and(warp::body::content_length_limit(MAX_FORM_SIZE))
+                    .and(warp::body::form())
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::set_jingle)
+                    .or(warp::delete()
+                        .and(with_arg(state.clone()))
+                        .and_then(crate::server_endpoints::remove_jingle)),
+            ))
+            .or(warp::path!(u64)
+                .and(warp::delete())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::remove_scheduled_entry)),
+    );
+
+    let loudness = warp::path("loudness")
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(
+            warp::get()
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::get_loudness)
+                .or(warp::post()
+                    .and(warp::body::content_length_limit(MAX_FORM_SIZE))
+                    .and(warp::body::form())
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::set_loudness)),
+        );
+
+    let announce = warp::path("announce")
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(
+            warp::post()
+                .and(warp::body::content_length_limit(MAX_FORM_SIZE))
+                .and(warp::body::form())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::announce)
+                .or(warp::get()
+                    .and(with_arg(state.clone()))
+                    .and_then(crate::server_endpoints::pending_announcements)),
+        );
+
     let get_playlist = warp::get()
         .and(with_arg(state.clone()))
         .and_then(crate::server_endpoints::get_playlist);
@@ -38,9 +246,142 @@ pub async fn start(addr: SocketAddr, state: ServerState) {
     let playlist = warp::path("playlist").and(
         warp::path::end()
             .and(get_playlist)
-            .or(warp::path("next").and(warp::path::end()).and(playlist_next)),
+            .or(warp::path("next").and(warp::path::end()).and(playlist_next))
+            .or(warp::path!("export" / String)
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::export_playlist))
+            .or(warp::path!("import")
+                .and(warp::post())
+                .and(
+                    warp::multipart::form()
+                        .max_length(MAX_PLAYLIST_FILE_SIZE.saturating_add(MULTIPART_OVERHEAD)),
+                )
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::import_playlist))
+            .or(warp::path!(i64 / "subtitles")
+                .and(warp::post())
+                .and(
+                    warp::multipart::form()
+                        .max_length(MAX_SUBTITLE_SIZE.saturating_add(MULTIPART_OVERHEAD)),
+                )
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::upload_subtitles)),
+    );
+
+    let tracks = warp::path("tracks").and(
+        warp::path::end()
+            .and(warp::get())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::get_tracks)
+            .or(warp::path!(String)
+                .and(warp::post())
+                .and(warp::body::form())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::select_track)),
     );
 
+    let delays = warp::path("delays").and(warp::path::end()).and(
+        warp::get()
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::get_delays)
+            .or(warp::post()
+                .and(warp::body::form())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::set_delays)),
+    );
+
+    let chapters = warp::path("chapters").and(warp::path::end()).and(
+        warp::get()
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::get_chapters)
+            .or(warp::post()
+                .and(warp::body::form())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::set_chapter)),
+    );
+
+    let history = warp::path("history").and(
+        warp::path::end()
+            .and(warp::get())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::get_history)
+            .or(warp::path!("export" / String)
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::export_history))
+            .or(warp::path!("stats")
+                .and(warp::get())
+                .and(admin.clone())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::history_stats)),
+    );
+
+    let saved_queues = warp::path("queues").and(
+        warp::path::end()
+            .and(warp::get())
+            .and(with_arg(state.clone()))
+            .and_then(crate::server_endpoints::list_saved_queues)
+            .or(warp::path!(String)
+                .and(warp::get())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::get_saved_queue))
+            .or(warp::path!(String)
+                .and(warp::put())
+                .and(admin.clone())
+                .and(warp::body::content_length_limit(MAX_PLAYLIST_FILE_SIZE))
+                .and(warp::body::json())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::put_saved_queue))
+            .or(warp::path!(String)
+                .and(warp::delete())
+                .and(admin.clone())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::delete_saved_queue))
+            .or(warp::path!(String / "append-current")
+                .and(warp::post())
+                .and(admin.clone())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::append_current_to_saved_queue))
+            .or(warp::path!(String / "play")
+                .and(warp::post())
+                .and(admin)
+                .and(warp::addr::remote())
+                .and(with_arg(state.clone()))
+                .and_then(crate::server_endpoints::play_saved_queue)),
+    );
+
+    // A character can take up to 12 bytes once it's UTF-8 and percent-encoded.
+    let chat = warp::path("chat")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            state.chat.max_length() as u64 * 12 + CHAT_FORM_OVERHEAD,
+        ))
+        .and(warp::body::form())
+        .and(warp::addr::remote())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::post_chat);
+
+    let events = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::events);
+
+    let errors = warp::path("errors")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::list_entry_errors);
+
+    let torrents = warp::path("torrents")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_arg(state.clone()))
+        .and_then(crate::server_endpoints::list_torrents);
+
     let current = warp::path("current")
         .and(warp::path::end())
         .and(warp::get())
@@ -53,18 +394,71 @@ pub async fn start(addr: SocketAddr, state: ServerState) {
         .and(with_arg(state.clone()))
         .and_then(crate::server_endpoints::toggle_qr_code);
 
+    // The routes are boxed in groups, otherwise the combined filter and its futures
+    // get large enough to overflow the stack in debug builds.
+    let queue_routes = enqueue
+        .or(preview)
+        .or(search)
+        .or(library)
+        .or(playlist)
+        .or(saved_queues)
+        .or(torrents)
+        .boxed();
+
+    let upload_routes = upload_file.or(chunked_upload).boxed();
+
+    let admin_routes = admin_uploads
+        .or(logs)
+        .or(announce)
+        .or(sleep)
+        .or(schedule)
+        .or(loudness)
+        .boxed();
+
+    let playback_routes = tracks
+        .or(delays)
+        .or(chapters)
+        .or(history)
+        .or(errors)
+        .or(chat)
+        .or(events)
+        .or(current)
+        .or(toggle_qr)
+        .boxed();
+
     let api_routes = warp::path("api").and(
-        enqueue
-            .or(upload_file)
-            .or(playlist)
-            .or(current)
-            .or(toggle_qr),
+        queue_routes
+            .or(upload_routes)
+            .or(admin_routes)
+            .or(playback_routes),
     );
 
     let static_files = warp::path("static").and(warp::fs::dir(state.serve_dir.join("static")));
     let index_html = warp::path::end().and(warp::fs::file(state.serve_dir.join("index.html")));
 
-    let routes = api_routes.or(index_html).or(static_files);
+    api_routes
+        .or(index_html)
+        .or(static_files)
+        .recover(crate::server_endpoints::handle_rejection)
+}
+
+pub async fn start(addr: SocketAddr, state: ServerState, mdns_hostname: Option<String>) {
+    let routes = routes(state);
+
+    let (addr, server) = warp::serve(routes).bind_ephemeral(addr);
+
+    // Dropping the advertisement unregisters the service, so keep it around
+    // until the server shuts down.
+    let _advertisement =
+        mdns_hostname.and_then(
+            |hostname| match crate::mdns::advertise(&hostname, addr.port()) {
+                Ok(advertisement) => Some(advertisement),
+                Err(e) => {
+                    log::warn!("failed to advertise the server via mDNS: {e}");
+                    None
+                }
+            },
+        );
 
-    warp::serve(routes).run(addr).await
+    server.await
 }
@@ -3,8 +3,30 @@ use std::{path::PathBuf, sync::Arc};
 use tokio::sync::{Mutex, RwLock};
 
 use crate::{
+    announcements::Announcements,
+    aria2::Torrents,
+    autoplay::Autoplay,
+    chat::Chat,
+    chunked_upload::ChunkedUploads,
+    entry_errors::EntryErrors,
+    events::Events,
+    history::History,
+    library::Library,
+    library_index::LibraryIndex,
+    loudness::Loudness,
+    metadata::Resolver,
     mpv::{response::PlaylistEntry, Client},
+    mpv_log::MpvLog,
     qr::QrCodeParams,
+    retention::Retention,
+    saved_queues::SavedQueues,
+    scheduler::Scheduler,
+    search::Search,
+    sleep_timer::SleepTimer,
+    subtitles::Subtitles,
+    upload::UploadLimits,
+    upload_hashes::UploadHashes,
+    ytdlp::YtDlp,
 };
 
 #[derive(Debug, Clone)]
@@ -12,6 +34,140 @@ pub struct ServerState {
     pub ipc: Client,
     pub serve_dir: PathBuf,
     pub upload_dir: PathBuf,
+    pub chunked_uploads: ChunkedUploads,
+    pub upload_limits: UploadLimits,
+    pub upload_hashes: UploadHashes,
     pub qr_code_params: Arc<Mutex<QrCodeParams>>,
     pub playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+    pub ytdlp: YtDlp,
+    pub metadata: Resolver,
+    pub search: Search,
+    pub library: Library,
+    pub library_index: Option<LibraryIndex>,
+    pub retention: Retention,
+    pub subtitles: Subtitles,
+    pub history: History,
+    pub autoplay: Autoplay,
+    pub entry_errors: EntryErrors,
+    pub mpv_log: MpvLog,
+    pub events: Events,
+    pub chat: Chat,
+    pub announcements: Announcements,
+    pub sleep_timer: SleepTimer,
+    pub scheduler: Scheduler,
+    pub loudness: Loudness,
+    pub saved_queues: SavedQueues,
+
+    /// Downloads of magnet links and .torrent files. Unset when aria2c isn't available.
+    pub torrents: Option<Torrents>,
+
+    /// Token required by the admin endpoints. When unset they're only reachable from localhost.
+    pub admin_token: Option<String>,
+    pub max_playlist_entries: usize,
+}
+
+#[cfg(test)]
+impl ServerState {
+    /// State for testing the endpoints, with a fake mpv and everything stored in `dir`.
+    pub fn for_tests(
+        dir: &std::path::Path,
+    ) -> (
+        Self,
+        tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        let (ipc, commands) = Client::fake();
+        let upload_dir = dir.join("uploads");
+        std::fs::create_dir_all(&upload_dir).unwrap();
+
+        let ytdlp = YtDlp::new("yt-dlp".to_string());
+
+        let playlist = Arc::new(RwLock::new(vec![]));
+        let retention = Retention::new(
+            crate::retention::RetentionPolicy::Forever,
+            upload_dir.clone(),
+            playlist.clone(),
+        );
+
+        let subtitles = Subtitles::new(&upload_dir, ipc.clone(), playlist.clone());
+        let entry_errors = EntryErrors::new(ipc.clone(), playlist.clone(), false);
+        let events = Events::new();
+        let announcements = Announcements::new(ipc.clone(), events.clone());
+        let sleep_timer = SleepTimer::new(ipc.clone());
+        let loudness = Loudness::new(
+            ipc.clone(),
+            crate::ffmpeg::FfMpeg::new("ffmpeg".to_string()),
+            playlist.clone(),
+            crate::loudness::Settings {
+                mode: crate::loudness::Mode::Off,
+                measure: false,
+                target: -16.0,
+            },
+        );
+        let scheduler =
+            Scheduler::load(&dir.join("schedule.json"), ipc.clone(), playlist.clone()).unwrap();
+        let chat = Chat::new(
+            ipc.clone(),
+            events.clone(),
+            crate::chat::ChatFilter::new(50, vec!["heck".to_string()]),
+            3,
+        );
+
+        let metadata = Resolver::start(
+            ytdlp.clone(),
+            crate::ffprobe::FfProbe::new("ffprobe".to_string()),
+            1,
+        );
+        let history = History::new(
+            &dir.join("history.jsonl"),
+            playlist.clone(),
+            metadata.clone(),
+        );
+
+        let autoplay = Autoplay::new(
+            None,
+            ipc.clone(),
+            history.clone(),
+            Library::default(),
+            ytdlp.clone(),
+        );
+
+        let state = ServerState {
+            ipc,
+            serve_dir: dir.join("public"),
+            chunked_uploads: ChunkedUploads::new(&upload_dir),
+            upload_limits: UploadLimits::new(1024 * 1024, None, None, 0),
+            upload_hashes: UploadHashes::load(&upload_dir).unwrap(),
+            upload_dir,
+            qr_code_params: Arc::new(Mutex::new(QrCodeParams {
+                path: String::new(),
+                width: 0,
+                magnification: 1,
+                active: false,
+            })),
+            playlist,
+            metadata,
+            ytdlp,
+            search: Search::new(vec![]),
+            library: Library::default(),
+            library_index: None,
+            retention,
+            subtitles,
+            entry_errors,
+            mpv_log: MpvLog::new(),
+            events,
+            chat,
+            announcements,
+            sleep_timer,
+            scheduler,
+            loudness,
+            history,
+            autoplay,
+            saved_queues: SavedQueues::new(&dir.join("queues")),
+            torrents: None,
+            admin_token: None,
+            max_playlist_entries: 100,
+        };
+
+        (state, commands)
+    }
 }
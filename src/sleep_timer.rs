@@ -0,0 +1,326 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::mpv::{event::EndFile, Client};
+
+/// How long the notes about the sleep timer stay on screen.
+const OSD_DURATION_MS: u64 = 4000;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Pause when the file that's playing now ends.
+    AfterCurrent,
+
+    /// Pause after some time.
+    After(Duration),
+
+    /// Lower the volume to nothing over some time, then pause.
+    Fade(Duration),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "mode")]
+pub enum Status {
+    AfterCurrent,
+    Timer {
+        /// Seconds since the Unix epoch.
+        stops_at: u64,
+    },
+    Fade {
+        /// Seconds since the Unix epoch.
+        stops_at: u64,
+    },
+}
+
+/// Seconds until the next time the clock reads `hours:minutes` in the timezone `utc_offset`
+/// minutes east of UTC, counting from `now` seconds since the Unix epoch.
+pub fn seconds_until(hours: u32, minutes: u32, utc_offset: i64, now: u64) -> u64 {
+    let now_local = (now as i64 + utc_offset * 60).rem_euclid(SECONDS_PER_DAY as i64) as u64;
+    let target = (hours as u64 * 60 + minutes as u64) * 60;
+
+    match (target + SECONDS_PER_DAY - now_local) % SECONDS_PER_DAY {
+        // It's exactly that time, so it means tomorrow.
+        0 => SECONDS_PER_DAY,
+        seconds => seconds,
+    }
+}
+
+/// Minutes east of UTC of the timezone kameloso runs in at `now` seconds since the Unix epoch,
+/// for clock times sent without their own offset.
+#[cfg(unix)]
+// tm_gmtoff is an i64 on most platforms, but not all of them.
+#[allow(clippy::useless_conversion)]
+pub fn local_utc_offset(now: u64) -> i64 {
+    let time = now as libc::time_t;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+
+    // SAFETY: localtime_r only writes to `tm`, which is only read if it succeeded.
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        log::warn!("failed to get the local timezone, using UTC");
+        return 0;
+    }
+
+    i64::from(unsafe { tm.assume_init() }.tm_gmtoff) / 60
+}
+
+#[cfg(not(unix))]
+pub fn local_utc_offset(_now: u64) -> i64 {
+    log::warn!("the local timezone isn't known on this platform, using UTC");
+    0
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn format_minutes(duration: Duration) -> String {
+    match (duration.as_secs() + 30) / 60 {
+        1 => "1 minute".to_string(),
+        minutes => format!("{minutes} minutes"),
+    }
+}
+
+#[derive(Debug)]
+struct Active {
+    id: u64,
+    status: Status,
+    cancel: Arc<Notify>,
+}
+
+/// Stops playback at the end of the night, politely.
+#[derive(Debug, Clone)]
+pub struct SleepTimer {
+    ipc: Client,
+    active: Arc<Mutex<Option<Active>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SleepTimer {
+    pub fn new(ipc: Client) -> Self {
+        SleepTimer {
+            ipc,
+            active: Arc::new(Mutex::new(None)),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn status(&self) -> Option<Status> {
+        self.active
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|active| active.status.clone())
+    }
+
+    async fn show_text(&self, text: &str) {
+        if let Err(e) = self.ipc.show_text(text, OSD_DURATION_MS).await {
+            log::warn!("failed to show the sleep timer on screen: {e}");
+        }
+    }
+
+    async fn stop(&self) {
+        if let Err(e) = self.ipc.set_paused(true).await {
+            log::warn!("failed to pause for the sleep timer: {e}");
+        }
+        self.show_text("Good night!").await;
+    }
+
+    /// Lowers the volume bit by bit, and puts it back if the fade is cancelled or done.
+    async fn fade(&self, duration: Duration, cancel: &Notify) {
+        let volume = self.ipc.get_volume().await.unwrap_or(100.0);
+        let step = (duration / 50).min(Duration::from_secs(1));
+        let started = Instant::now();
+
+        let cancelled = loop {
+            let progress = started.elapsed().as_secs_f64() / duration.as_secs_f64();
+            if progress >= 1.0 {
+                break false;
+            }
+
+            if let Err(e) = self.ipc.set_volume(volume * (1.0 - progress)).await {
+                log::warn!("failed to lower the volume: {e}");
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(step) => {}
+                _ = cancel.notified() => break true,
+            }
+        };
+
+        if !cancelled {
+            self.stop().await;
+        }
+
+        if let Err(e) = self.ipc.set_volume(volume).await {
+            log::warn!("failed to restore the volume: {e}");
+        }
+    }
+
+    async fn run(&self, mode: Mode, cancel: &Notify) {
+        match mode {
+            Mode::AfterCurrent => {
+                // Pause is kept across files, so pausing as one ends means the next one starts paused.
+                let Ok(mut end_file_rx) = self.ipc.observe_event::<EndFile>("end-file") else {
+                    return;
+                };
+
+                tokio::select! {
+                    Some(_) = end_file_rx.recv() => self.stop().await,
+                    _ = cancel.notified() => {}
+                }
+            }
+            Mode::After(duration) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(duration) => self.stop().await,
+                    _ = cancel.notified() => {}
+                }
+            }
+            Mode::Fade(duration) => self.fade(duration, cancel).await,
+        }
+    }
+
+    /// Starts the sleep timer, replacing the one that was running.
+    pub async fn set(&self, mode: Mode) -> Status {
+        let (status, text) = match mode {
+            Mode::AfterCurrent => (
+                Status::AfterCurrent,
+                "Playback will stop after this file".to_string(),
+            ),
+            Mode::After(duration) => (
+                Status::Timer {
+                    stops_at: unix_now() + duration.as_secs(),
+                },
+                format!("Playback will stop in {}", format_minutes(duration)),
+            ),
+            Mode::Fade(duration) => (
+                Status::Fade {
+                    stops_at: unix_now() + duration.as_secs(),
+                },
+                format!("Fading out over {}", format_minutes(duration)),
+            ),
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(Notify::new());
+
+        let previous = self.active.lock().unwrap().replace(Active {
+            id,
+            status: status.clone(),
+            cancel: cancel.clone(),
+        });
+        if let Some(previous) = previous {
+            previous.cancel.notify_one();
+        }
+
+        self.show_text(&text).await;
+
+        tokio::spawn({
+            let timer = self.clone();
+
+            async move {
+                timer.run(mode, &cancel).await;
+
+                let mut active = timer.active.lock().unwrap();
+                if active.as_ref().is_some_and(|active| active.id == id) {
+                    *active = None;
+                }
+            }
+        });
+
+        status
+    }
+
+    /// Returns whether there was a sleep timer running.
+    pub async fn cancel(&self) -> bool {
+        let Some(active) = self.active.lock().unwrap().take() else {
+            return false;
+        };
+
+        active.cancel.notify_one();
+        self.show_text("Sleep timer cancelled").await;
+
+        true
+    }
+}
+
+#[test]
+fn test_seconds_until() {
+    // 2023-11-14 22:13:20 UTC.
+    let now = 1700000000;
+
+    assert_eq!(seconds_until(23, 0, 0, now), 46 * 60 + 40);
+    // 23:13:20 in UTC+1, so it's tomorrow.
+    assert_eq!(
+        seconds_until(23, 0, 60, now),
+        SECONDS_PER_DAY - 13 * 60 - 20
+    );
+    // 17:13:20 in UTC-5.
+    assert_eq!(seconds_until(18, 0, -300, now), 46 * 60 + 40);
+    assert_eq!(seconds_until(22, 13, 0, now - 20), SECONDS_PER_DAY);
+
+    assert!(local_utc_offset(now).abs() <= 14 * 60);
+}
+
+#[tokio::test]
+async fn test_sleep_timer() {
+    let (ipc, mut commands) = Client::fake();
+    let timer = SleepTimer::new(ipc);
+
+    let status = timer.set(Mode::After(Duration::from_secs(600))).await;
+    assert!(matches!(status, Status::Timer { .. }));
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["show-text", "Playback will stop in 10 minutes", "4000"])
+    );
+
+    // Replacing it cancels the first one.
+    timer.set(Mode::Fade(Duration::from_millis(50))).await;
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["show-text", "Fading out over 0 minutes", "4000"])
+    );
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["get_property", "volume"])
+    );
+
+    let mut sent = vec![];
+    loop {
+        let command = commands.recv().await.unwrap();
+        if command[0] == "show-text" {
+            assert_eq!(command[1], "Good night!");
+            break;
+        }
+        sent.push(command);
+    }
+
+    assert!(sent.len() > 1);
+    assert!(sent[..sent.len() - 1]
+        .iter()
+        .all(|command| command[0] == "set_property" && command[1] == "volume"));
+    assert_eq!(
+        sent.last().unwrap(),
+        &serde_json::json!(["set_property", "pause", "yes"])
+    );
+    // The volume is back to where it was for whenever playback resumes.
+    assert_eq!(
+        commands.recv().await.unwrap(),
+        serde_json::json!(["set_property", "volume", "100"])
+    );
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(timer.status().is_none());
+    assert!(!timer.cancel().await);
+}
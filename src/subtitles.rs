@@ -0,0 +1,203 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{mpsc::UnboundedReceiver, RwLock};
+
+use crate::mpv::{
+    event::{FileLoaded, StartFile},
+    response::PlaylistEntry,
+    Client, Error as IpcError,
+};
+
+/// Name of the directory inside of the upload dir where subtitles are kept.
+const SUBTITLES_DIR: &str = ".subtitles";
+
+/// External subtitle files uploaded for specific playlist entries.
+///
+/// Subtitles can only be added to the file that's currently loaded, so they're added
+/// again every time their entry is loaded, and right away if it's already playing.
+#[derive(Debug, Clone)]
+pub struct Subtitles {
+    dir: PathBuf,
+    ipc: Client,
+    playlist: Arc<RwLock<Vec<PlaylistEntry>>>,
+
+    /// Playlist entry ID to its subtitle files, in the order they were uploaded.
+    attached: Arc<Mutex<HashMap<i64, Vec<PathBuf>>>>,
+}
+
+impl Subtitles {
+    pub fn new(upload_dir: &Path, ipc: Client, playlist: Arc<RwLock<Vec<PlaylistEntry>>>) -> Self {
+        Subtitles {
+            dir: upload_dir.join(SUBTITLES_DIR),
+            ipc,
+            playlist,
+            attached: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether this entry is still in the playlist, and if it's the one playing.
+    pub async fn entry_status(&self, playlist_entry_id: i64) -> Option<bool> {
+        self.playlist
+            .read()
+            .await
+            .iter()
+            .find(|entry| entry.id as i64 == playlist_entry_id)
+            .map(|entry| entry.playing)
+    }
+
+    /// Attaches a subtitle file to a playlist entry, and shows it right away if it's playing.
+    pub async fn attach(&self, playlist_entry_id: i64, path: &Path) -> Result<(), IpcError> {
+        self.attached
+            .lock()
+            .unwrap()
+            .entry(playlist_entry_id)
+            .or_default()
+            .push(path.to_path_buf());
+
+        match self.entry_status(playlist_entry_id).await {
+            Some(true) => self.ipc.sub_add(&path.to_string_lossy(), true).await?,
+            Some(false) => {}
+            // The entry was removed while the subtitles were being uploaded.
+            None => {
+                self.attached.lock().unwrap().remove(&playlist_entry_id);
+                if let Err(e) = tokio::fs::remove_file(path).await {
+                    log::warn!("failed to remove subtitles {}: {e}", path.display());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the subtitles of an entry to the one that replaced it.
+    pub fn replaced(&self, old_id: i64, new_id: i64) {
+        let mut attached = self.attached.lock().unwrap();
+        if let Some(paths) = attached.remove(&old_id) {
+            attached.insert(new_id, paths);
+        }
+    }
+
+    /// Forgets the subtitles of the entries that aren't in the playlist anymore and deletes them.
+    pub async fn update(&self, playlist: &[PlaylistEntry]) {
+        let ids: HashSet<i64> = playlist.iter().map(|entry| entry.id as i64).collect();
+
+        let mut removed = vec![];
+        self.attached.lock().unwrap().retain(|id, paths| {
+            if ids.contains(id) {
+                return true;
+            }
+            removed.append(paths);
+            false
+        });
+
+        for path in removed {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::warn!("failed to remove subtitles {}: {e}", path.display());
+            }
+        }
+    }
+
+    /// Removes the subtitles left over from a previous run, since the entries they were attached to
+    /// don't survive a restart. This must be done before any new subtitles can be uploaded.
+    pub async fn remove_old(&self) {
+        if let Err(e) = tokio::fs::remove_dir_all(&self.dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("failed to remove old subtitles: {e}");
+            }
+        }
+    }
+
+    async fn add_to_current_file(&self, playlist_entry_id: i64) {
+        let paths = self
+            .attached
+            .lock()
+            .unwrap()
+            .get(&playlist_entry_id)
+            .cloned()
+            .unwrap_or_default();
+
+        for path in paths {
+            if let Err(e) = self.ipc.sub_add(&path.to_string_lossy(), true).await {
+                log::warn!("failed to add subtitles {}: {e}", path.display());
+            }
+        }
+    }
+
+    pub async fn run(
+        self,
+        mut start_file_rx: UnboundedReceiver<StartFile>,
+        mut file_loaded_rx: UnboundedReceiver<FileLoaded>,
+    ) {
+        // file-loaded doesn't say which entry was loaded, so remember the last one that started.
+        let mut current = None;
+
+        loop {
+            tokio::select! {
+                start_file = start_file_rx.recv() => match start_file {
+                    Some(start_file) => current = Some(start_file.playlist_entry_id),
+                    None => break,
+                },
+
+                file_loaded = file_loaded_rx.recv() => match file_loaded {
+                    Some(FileLoaded {}) => {
+                        if let Some(current) = current {
+                            self.add_to_current_file(current).await;
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_subtitles_of_removed_entries() {
+    let dir = crate::test_dir::TestDir::new();
+    let entry = |id| PlaylistEntry {
+        id,
+        filename: format!("video{id}.mkv"),
+        title: None,
+        current: false,
+        playing: false,
+    };
+    let playlist = Arc::new(RwLock::new(vec![entry(1), entry(2)]));
+    let (ipc, _commands) = Client::fake();
+    let subtitles = Subtitles::new(&dir, ipc, playlist.clone());
+    std::fs::create_dir_all(subtitles.dir()).unwrap();
+
+    let first = subtitles.dir().join("first.srt");
+    let second = subtitles.dir().join("second.srt");
+    for (id, path) in [(1, &first), (2, &second)] {
+        std::fs::write(path, b"").unwrap();
+        subtitles.attach(id, path).await.unwrap();
+    }
+
+    *playlist.write().await = vec![entry(2)];
+    subtitles.update(&playlist.read().await).await;
+    assert!(!first.exists());
+    assert!(second.exists());
+    assert_eq!(
+        subtitles
+            .attached
+            .lock()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>(),
+        [&2]
+    );
+
+    // Subtitles for an entry that's already gone are removed right away.
+    let late = subtitles.dir().join("late.srt");
+    std::fs::write(&late, b"").unwrap();
+    subtitles.attach(1, &late).await.unwrap();
+    assert!(!late.exists());
+}
@@ -0,0 +1,36 @@
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A unique path in the system's temporary directory for a test to work in,
+/// deleted along with everything inside when dropped, even if the test fails.
+/// The directory itself isn't created.
+#[derive(Debug)]
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        TestDir(std::env::temp_dir().join(format!("kameloso-test-{}", uuid::Uuid::new_v4())))
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use futures::{Stream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use warp::Buf;
+
+/// How many bytes are needed to tell what kind of file is being uploaded.
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+const SUBTITLE_EXTENSIONS: &[&str] = &["srt", "ass", "ssa", "vtt", "sub", "lrc"];
+
+const MAX_FILENAME_LEN: usize = 200;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("file is too large, the limit is {max} bytes")]
+    TooLarge { max: u64 },
+
+    #[error("upload quota exceeded, {remaining} bytes left")]
+    UserQuotaExceeded { remaining: u64 },
+
+    #[error("the uploads directory is full")]
+    TotalQuotaExceeded,
+
+    #[error("not enough free disk space")]
+    DiskFull,
+
+    #[error("unsupported file type: {0}")]
+    UnsupportedType(String),
+
+    #[error("upload interrupted: {0}")]
+    Interrupted(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Audio,
+    Video,
+    Image,
+    Subtitle,
+}
+
+/// Turns a client-provided filename into something that is safe to put in the upload directory,
+/// dropping any directories and characters that aren't allowed on some platforms.
+pub fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        .collect();
+
+    let mut name = name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    if name.len() > MAX_FILENAME_LEN {
+        // Try to keep the extension, which mpv may need to figure out what the file is.
+        let extension = Path::new(&name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .filter(|ext| ext.len() < 16)
+            .unwrap_or_default();
+
+        let mut end = MAX_FILENAME_LEN - extension.len() - 1;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        name.truncate(end);
+        if !extension.is_empty() {
+            name = format!("{name}.{extension}");
+        }
+    }
+
+    if name.is_empty() {
+        "upload".to_string()
+    } else {
+        name
+    }
+}
+
+fn is_mpeg_ts(head: &[u8]) -> bool {
+    const PACKET_LEN: usize = 188;
+    head.len() > PACKET_LEN * 2 && (0..3).all(|i| head[i * PACKET_LEN] == 0x47)
+}
+
+fn is_text(head: &[u8]) -> bool {
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+
+    match std::str::from_utf8(head) {
+        Ok(text) => !text.contains('\0'),
+        // The head may end in the middle of a character.
+        Err(e) => e.error_len().is_none() && !head[..e.valid_up_to()].contains(&0),
+    }
+}
+
+/// Figures out what kind of media a file is from its first few bytes,
+/// and rejects anything that isn't audio, video, an image or subtitles.
+pub fn sniff(head: &[u8], filename: &str) -> Result<MediaKind, Error> {
+    if let Some(kind) = infer::get(head) {
+        return match kind.matcher_type() {
+            infer::MatcherType::Audio => Ok(MediaKind::Audio),
+            infer::MatcherType::Video => Ok(MediaKind::Video),
+            infer::MatcherType::Image => Ok(MediaKind::Image),
+            _ => Err(Error::UnsupportedType(kind.mime_type().to_string())),
+        };
+    }
+
+    if is_mpeg_ts(head) {
+        return Ok(MediaKind::Video);
+    }
+
+    let extension = Path::new(filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if SUBTITLE_EXTENSIONS.contains(&extension.as_str()) && is_text(head) {
+        return Ok(MediaKind::Subtitle);
+    }
+
+    Err(Error::UnsupportedType(if extension.is_empty() {
+        "unknown".to_string()
+    } else {
+        extension
+    }))
+}
+
+pub async fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut size = 0;
+
+    while let Some(dir) = dirs.pop() {
+        let mut read_dir = fs::read_dir(&dir).await?;
+
+        while let Some(entry) = read_dir.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += meta.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// Bytes uploaded or reserved by each user.
+    by_user: HashMap<IpAddr, u64>,
+
+    /// Bytes reserved by uploads that aren't in the uploads directory yet.
+    pending: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    /// Maximum size of a single file.
+    pub max_size: u64,
+
+    /// Maximum number of bytes a single user can upload while kameloso is running.
+    pub user_quota: Option<u64>,
+
+    /// Maximum size of the uploads directory.
+    pub total_quota: Option<u64>,
+
+    /// Free space that must be left on the disk after an upload.
+    pub min_free_space: u64,
+
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl UploadLimits {
+    pub fn new(
+        max_size: u64,
+        user_quota: Option<u64>,
+        total_quota: Option<u64>,
+        min_free_space: u64,
+    ) -> Self {
+        UploadLimits {
+            max_size,
+            user_quota,
+            total_quota,
+            min_free_space,
+            usage: Arc::new(Mutex::new(Usage::default())),
+        }
+    }
+
+    /// Checks that a file of `size` bytes can be uploaded by `user` right now,
+    /// and reserves the space so that concurrent uploads can't go over the limits together.
+    pub async fn check(
+        &self,
+        upload_dir: &Path,
+        user: Option<IpAddr>,
+        size: u64,
+    ) -> Result<Reservation, Error> {
+        if size > self.max_size {
+            return Err(Error::TooLarge { max: self.max_size });
+        }
+
+        let dir_size = match self.total_quota {
+            Some(_) => dir_size(upload_dir).await?,
+            None => 0,
+        };
+        let available = fs4::available_space(upload_dir)?;
+
+        let mut usage = self.usage.lock().unwrap();
+
+        if let (Some(quota), Some(user)) = (self.user_quota, user) {
+            let used = usage.by_user.get(&user).copied().unwrap_or(0);
+            let remaining = quota.saturating_sub(used);
+            if size > remaining {
+                return Err(Error::UserQuotaExceeded { remaining });
+            }
+        }
+
+        if let Some(quota) = self.total_quota {
+            if dir_size + usage.pending + size > quota {
+                return Err(Error::TotalQuotaExceeded);
+            }
+        }
+
+        if available.saturating_sub(usage.pending + size) < self.min_free_space {
+            return Err(Error::DiskFull);
+        }
+
+        if let Some(user) = user {
+            *usage.by_user.entry(user).or_default() += size;
+        }
+        usage.pending += size;
+
+        Ok(Reservation {
+            limits: self.clone(),
+            user,
+            remaining: size,
+        })
+    }
+}
+
+/// Space reserved for an upload by [`UploadLimits::check`].
+/// Whatever wasn't recorded as uploaded is given back when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    limits: UploadLimits,
+    user: Option<IpAddr>,
+    remaining: u64,
+}
+
+impl Reservation {
+    /// Counts a finished upload of `size` bytes towards the user's quota.
+    pub fn record(&mut self, size: u64) {
+        let reserved = size.min(self.remaining);
+        self.remaining -= reserved;
+
+        let mut usage = self.limits.usage.lock().unwrap();
+        usage.pending -= reserved;
+        if let Some(user) = self.user {
+            *usage.by_user.entry(user).or_default() += size - reserved;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut usage = self.limits.usage.lock().unwrap();
+        usage.pending -= self.remaining;
+        if let Some(used) = self.user.and_then(|user| usage.by_user.get_mut(&user)) {
+            *used -= self.remaining;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SavedUpload {
+    pub kind: MediaKind,
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 of the file.
+    pub sha256: String,
+}
+
+/// Writes an upload to `path`, checking what kind of file it is before writing anything
+/// and stopping if it goes over `max_size` bytes.
+/// On failure the partially written file is left for the caller to clean up.
+pub async fn save_stream<S, B, E>(
+    mut stream: S,
+    path: &Path,
+    filename: &str,
+    max_size: u64,
+) -> Result<SavedUpload, Error>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: Display,
+{
+    let mut out = fs::File::create(path).await?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut kind = None;
+    let mut written = 0u64;
+    let mut hasher = Sha256::new();
+
+    loop {
+        let chunk = stream
+            .try_next()
+            .await
+            .map_err(|e| Error::Interrupted(e.to_string()))?;
+
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        let chunk = chunk.chunk();
+
+        written += chunk.len() as u64;
+        if written > max_size {
+            return Err(Error::TooLarge { max: max_size });
+        }
+
+        hasher.update(chunk);
+
+        if kind.is_some() {
+            out.write_all(chunk).await?;
+            continue;
+        }
+
+        head.extend_from_slice(chunk);
+        if head.len() >= SNIFF_LEN {
+            kind = Some(sniff(&head, filename)?);
+            out.write_all(&head).await?;
+        }
+    }
+
+    let kind = match kind {
+        Some(kind) => kind,
+        None => {
+            let kind = sniff(&head, filename)?;
+            out.write_all(&head).await?;
+            kind
+        }
+    };
+
+    out.flush().await?;
+
+    Ok(SavedUpload {
+        kind,
+        size: written,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+#[test]
+fn test_sanitize_filename() {
+    for (name, expected) in [
+        ("video.mp4", "video.mp4"),
+        ("../../../etc/passwd", "passwd"),
+        ("..\\..\\Windows\\System32\\evil.dll", "evil.dll"),
+        ("/absolute/path.mkv", "path.mkv"),
+        ("..", "upload"),
+        ("", "upload"),
+        ("...hidden", "hidden"),
+        ("trailing dots. . .", "trailing dots"),
+        ("what?<is>:this|*\".mp3", "whatisthis.mp3"),
+        ("new\nline\0null.webm", "newlinenull.webm"),
+        ("C:\\Users\\me\\video.mp4", "video.mp4"),
+    ] {
+        assert_eq!(sanitize_filename(name), expected, "{name:?}");
+    }
+
+    let long = format!("{}.mp4", "あ".repeat(100));
+    let sanitized = sanitize_filename(&long);
+    assert!(sanitized.len() <= MAX_FILENAME_LEN);
+    assert!(sanitized.ends_with("あ.mp4"));
+}
+
+#[test]
+fn test_sniff() {
+    let mp4 = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00mp42isom";
+    assert_eq!(sniff(mp4, "video.exe").unwrap(), MediaKind::Video);
+    assert_eq!(
+        sniff(b"fLaC\x00\x00\x00\x22", "x").unwrap(),
+        MediaKind::Audio
+    );
+    assert_eq!(
+        sniff(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR", "x").unwrap(),
+        MediaKind::Image
+    );
+    assert_eq!(
+        sniff(b"1\n00:00:01,000 --> 00:00:02,000\nhi\n", "subs.srt").unwrap(),
+        MediaKind::Subtitle
+    );
+
+    assert!(matches!(
+        sniff(b"#!/bin/sh\nrm -rf /\n", "video.mp4"),
+        Err(Error::UnsupportedType(_))
+    ));
+    assert!(matches!(
+        sniff(b"MZ\x90\x00\x03\x00\x00\x00", "subs.srt"),
+        Err(Error::UnsupportedType(_))
+    ));
+    assert!(matches!(
+        sniff(b"1\n00:00:01,000\0\0\0\0", "subs.srt"),
+        Err(Error::UnsupportedType(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_upload_reservations() {
+    let dir = crate::test_dir::TestDir::new();
+    fs::create_dir_all(&dir).await.unwrap();
+
+    let limits = UploadLimits::new(100, Some(150), None, 0);
+    let user = Some("10.0.0.1".parse().unwrap());
+
+    // Uploads in flight count towards the quota until they fail or finish.
+    let mut first = limits.check(&dir, user, 100).await.unwrap();
+    assert!(matches!(
+        limits.check(&dir, user, 100).await,
+        Err(Error::UserQuotaExceeded { remaining: 50 })
+    ));
+
+    // Only the bytes that were actually uploaded are kept.
+    first.record(30);
+    drop(first);
+    let second = limits.check(&dir, user, 100).await.unwrap();
+    assert!(matches!(
+        limits.check(&dir, user, 100).await,
+        Err(Error::UserQuotaExceeded { remaining: 20 })
+    ));
+
+    drop(second);
+    assert!(limits.check(&dir, user, 100).await.is_ok());
+}
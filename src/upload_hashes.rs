@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{fs, sync::Mutex};
+
+/// Name of the index inside of the upload dir.
+const INDEX_FILE: &str = ".hashes.json";
+
+/// Index of the SHA-256 of every upload, so that a file that was already uploaded
+/// can be enqueued again instead of being stored twice.
+///
+/// The index maps hex-encoded hashes to the names of the files in the upload dir
+/// and is rewritten whenever it changes. Files deleted by the retention policy
+/// or by hand are dropped from the index the next time they're looked up.
+#[derive(Debug, Clone)]
+pub struct UploadHashes {
+    upload_dir: PathBuf,
+    index: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl UploadHashes {
+    pub fn load(upload_dir: &Path) -> io::Result<Self> {
+        let index = match std::fs::read(upload_dir.join(INDEX_FILE)) {
+            Ok(index) => serde_json::from_slice(&index).unwrap_or_else(|e| {
+                log::warn!("upload hash index is corrupted, starting over: {e}");
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(UploadHashes {
+            upload_dir: upload_dir.to_path_buf(),
+            index: Arc::new(Mutex::new(index)),
+        })
+    }
+
+    async fn save(&self, index: &HashMap<String, String>) -> io::Result<()> {
+        let path = self.upload_dir.join(INDEX_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec(index)?).await?;
+        fs::rename(&tmp_path, &path).await
+    }
+
+    /// Returns the path of the upload with this hash, if it's still around.
+    pub async fn find(&self, sha256: &str) -> io::Result<Option<PathBuf>> {
+        let mut index = self.index.lock().await;
+        self.lookup(&mut index, &sha256.to_ascii_lowercase()).await
+    }
+
+    async fn lookup(
+        &self,
+        index: &mut HashMap<String, String>,
+        sha256: &str,
+    ) -> io::Result<Option<PathBuf>> {
+        let Some(name) = index.get(sha256) else {
+            return Ok(None);
+        };
+
+        let path = self.upload_dir.join(name);
+        if fs::try_exists(&path).await? {
+            return Ok(Some(path));
+        }
+
+        index.remove(sha256);
+        self.save(index).await?;
+
+        Ok(None)
+    }
+
+    /// Adds a freshly uploaded file to the index, unless a file with the same hash
+    /// is already there. Returns the path of the file that should be kept.
+    pub async fn insert(&self, sha256: &str, path: &Path) -> io::Result<PathBuf> {
+        let sha256 = sha256.to_ascii_lowercase();
+
+        // The lock is held from the lookup to the insert, otherwise two uploads
+        // of the same file could both miss the lookup and both be kept.
+        let mut index = self.index.lock().await;
+
+        if let Some(existing) = self.lookup(&mut index, &sha256).await? {
+            if existing != path {
+                return Ok(existing);
+            }
+        }
+
+        let Some(name) = path.file_name() else {
+            return Ok(path.to_path_buf());
+        };
+
+        index.insert(sha256, name.to_string_lossy().to_string());
+        self.save(&index).await?;
+
+        Ok(path.to_path_buf())
+    }
+}
+
+#[tokio::test]
+async fn test_upload_hashes() {
+    let dir = crate::test_dir::TestDir::new();
+    fs::create_dir_all(&dir).await.unwrap();
+
+    let first = dir.join("first.mp4");
+    let second = dir.join("second.mp4");
+    fs::write(&first, b"same").await.unwrap();
+    fs::write(&second, b"same").await.unwrap();
+
+    let hashes = UploadHashes::load(&dir).unwrap();
+    assert_eq!(hashes.insert("AB12", &first).await.unwrap(), first);
+    assert_eq!(hashes.insert("ab12", &second).await.unwrap(), first);
+
+    // The index survives a restart.
+    let hashes = UploadHashes::load(&dir).unwrap();
+    assert_eq!(hashes.find("ab12").await.unwrap(), Some(first.clone()));
+
+    fs::remove_file(&first).await.unwrap();
+    assert_eq!(hashes.find("ab12").await.unwrap(), None);
+    assert_eq!(hashes.insert("ab12", &second).await.unwrap(), second);
+    // Only one of two concurrent uploads of the same file is kept, even while
+    // the index still points at a file that was deleted.
+    let gone = dir.join("gone.mp4");
+    let third = dir.join("third.mp4");
+    let fourth = dir.join("fourth.mp4");
+    for path in [&gone, &third, &fourth] {
+        fs::write(path, b"other").await.unwrap();
+    }
+    hashes.insert("cd34", &gone).await.unwrap();
+    fs::remove_file(&gone).await.unwrap();
+    let (a, b) = tokio::join!(
+        hashes.insert("cd34", &third),
+        hashes.insert("cd34", &fourth)
+    );
+    assert_eq!(a.unwrap(), b.unwrap());
+}
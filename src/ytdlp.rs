@@ -0,0 +1,251 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to run yt-dlp: {0}")]
+    Spawn(#[from] io::Error),
+
+    #[error("yt-dlp failed: {0}")]
+    Failed(String),
+
+    #[error("failed to decode yt-dlp output: {0}")]
+    InvalidOutput(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub url: String,
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Playlist {
+    pub title: Option<String>,
+
+    /// Number of entries in the whole playlist, if yt-dlp knows it.
+    /// This may be larger than `entries.len()` if the playlist was capped.
+    pub total: Option<usize>,
+
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoInfo {
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub duration: Option<f64>,
+    pub thumbnail: Option<String>,
+}
+
+// The fields we care about from yt-dlp's -J output.
+#[derive(Debug, Deserialize)]
+struct RawInfo {
+    title: Option<String>,
+    url: Option<String>,
+    uploader: Option<String>,
+    duration: Option<f64>,
+    thumbnail: Option<String>,
+    playlist_count: Option<usize>,
+    entries: Option<Vec<RawInfo>>,
+}
+
+impl RawInfo {
+    fn into_entry(self) -> Option<Entry> {
+        Some(Entry {
+            url: self.url?,
+            title: self.title,
+            duration: self.duration,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct YtDlp {
+    path: String,
+}
+
+impl YtDlp {
+    pub fn new(path: String) -> Self {
+        YtDlp { path }
+    }
+
+    async fn run(&self, args: &[&str], url: &str) -> Result<Vec<u8>, Error> {
+        let output = Command::new(&self.path)
+            .args(args)
+            .arg("--")
+            .arg(url)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or("no error message")
+                .to_string();
+            return Err(Error::Failed(message));
+        }
+
+        Ok(output.stdout)
+    }
+
+    async fn dump_json(&self, args: &[&str], url: &str) -> Result<RawInfo, Error> {
+        let args = [args, &["-J"]].concat();
+        let stdout = self.run(&args, url).await?;
+
+        Ok(serde_json::from_slice(&stdout)?)
+    }
+
+    /// Downloads a single video to `dir` as `<name>.<ext>` and returns the path of the file.
+    /// Videos larger than `max_size` bytes are not downloaded.
+    pub async fn download(
+        &self,
+        url: &str,
+        dir: &Path,
+        name: &str,
+        max_size: u64,
+    ) -> Result<PathBuf, Error> {
+        let template = dir.join(format!("{name}.%(ext)s"));
+        let max_size = max_size.to_string();
+
+        let stdout = self
+            .run(
+                &[
+                    "--no-playlist",
+                    "--no-progress",
+                    "--max-filesize",
+                    &max_size,
+                    "-o",
+                    &template.to_string_lossy(),
+                    "--print",
+                    "after_move:filepath",
+                ],
+                url,
+            )
+            .await?;
+
+        // Nothing is printed when the download is skipped, like when it's too large.
+        String::from_utf8_lossy(&stdout)
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| PathBuf::from(line.trim()))
+            .ok_or_else(|| Error::Failed("nothing was downloaded".to_string()))
+    }
+
+    /// Resolves the metadata of a single video without downloading it.
+    pub async fn video_info(&self, url: &str) -> Result<VideoInfo, Error> {
+        let info = self.dump_json(&["--no-playlist"], url).await?;
+
+        Ok(VideoInfo {
+            title: info.title,
+            uploader: info.uploader,
+            duration: info.duration,
+            thumbnail: info.thumbnail,
+        })
+    }
+
+    /// Lists the entries of a playlist or channel without resolving each of them.
+    /// A URL pointing to a single video results in a playlist with one entry.
+    pub async fn flat_playlist(&self, url: &str, max_entries: usize) -> Result<Playlist, Error> {
+        let playlist_end = max_entries.to_string();
+        let info = self
+            .dump_json(&["--flat-playlist", "--playlist-end", &playlist_end], url)
+            .await?;
+
+        let Some(raw_entries) = info.entries else {
+            return Ok(Playlist {
+                title: info.title.clone(),
+                total: Some(1),
+                entries: vec![Entry {
+                    url: url.to_string(),
+                    title: info.title,
+                    duration: info.duration,
+                }],
+            });
+        };
+
+        let entries = raw_entries
+            .into_iter()
+            .filter_map(RawInfo::into_entry)
+            .take(max_entries)
+            .collect();
+
+        Ok(Playlist {
+            title: info.title,
+            total: info.playlist_count,
+            entries,
+        })
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_flat_playlist_stub() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = crate::test_dir::TestDir::new();
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("yt-dlp");
+
+    std::fs::write(
+        &script,
+        r#"#!/bin/sh
+case "$*" in
+    "--flat-playlist --playlist-end 2 -J -- https://example.com/playlist") ;;
+    *) echo "ERROR: unexpected arguments: $*" >&2; exit 1 ;;
+esac
+cat <<'JSON'
+{
+    "_type": "playlist",
+    "title": "party mix",
+    "playlist_count": 3,
+    "entries": [
+        {"_type": "url", "url": "https://example.com/1", "title": "one", "duration": 61.0},
+        {"_type": "url", "url": "https://example.com/2", "title": "two", "duration": null},
+        {"_type": "url", "url": "https://example.com/3", "title": "three", "duration": 3.5}
+    ]
+}
+JSON
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let ytdlp = YtDlp::new(script.to_string_lossy().to_string());
+
+    let playlist = ytdlp
+        .flat_playlist("https://example.com/playlist", 2)
+        .await
+        .unwrap();
+
+    assert_eq!(playlist.title.as_deref(), Some("party mix"));
+    assert_eq!(playlist.total, Some(3));
+    assert_eq!(
+        playlist
+            .entries
+            .iter()
+            .map(|e| e.url.as_str())
+            .collect::<Vec<_>>(),
+        ["https://example.com/1", "https://example.com/2"]
+    );
+    assert_eq!(playlist.entries[0].duration, Some(61.0));
+
+    let err = ytdlp
+        .flat_playlist("https://example.com/other", 2)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Failed(msg) if msg.contains("unexpected arguments")));
+}